@@ -1341,6 +1341,9 @@ trait Backend<'a> {
                 arg_layouts,
                 ret_layout,
             ),
+            LowLevel::Prefetch => {
+                // Only a hint, so it's fine to skip it
+            }
             LowLevel::NumToStr => {
                 let arg_layout = arg_layouts[0];
                 let intrinsic = match self.interner().get(arg_layout) {
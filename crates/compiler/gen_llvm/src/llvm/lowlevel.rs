@@ -1250,7 +1250,7 @@ pub(crate) fn run_low_level<'a, 'ctx>(
         }
 
         PtrCast | PtrWrite | RefCountIncRcPtr | RefCountDecRcPtr | RefCountIncDataPtr
-        | RefCountDecDataPtr => {
+        | RefCountDecDataPtr | Prefetch => {
             unreachable!("Not used in LLVM backend: {:?}", op);
         }
 
@@ -26,7 +26,7 @@ use roc_wasm_module::{
     round_up_to_alignment, Align, ExportType, LocalId, Signature, SymInfo, ValueType, WasmModule,
 };
 
-use crate::code_builder::CodeBuilder;
+use crate::code_builder::{CodeBuilder, WasmFeatures};
 use crate::layout::{CallConv, ReturnMethod, WasmLayout};
 use crate::low_level::{call_higher_order_lowlevel, LowLevelCall};
 use crate::storage::{AddressValue, Storage, StoredValue, StoredVarKind};
@@ -136,7 +136,7 @@ impl<'a, 'r> WasmBackend<'a, 'r> {
             // Function-level data
             block_depth: 0,
             joinpoint_label_map: MutMap::default(),
-            code_builder: CodeBuilder::new(env.arena),
+            code_builder: CodeBuilder::new(env.arena, WasmFeatures::ROC_TARGETS),
             storage: Storage::new(env.arena),
         }
     }
@@ -336,9 +336,9 @@ impl<'a, 'r> WasmBackend<'a, 'r> {
 
         self.code_builder.i32_const(0); // argc=0
         self.code_builder.i32_const(0); // argv=NULL
-        self.code_builder.call(main_fn_index, 2, true);
-        self.code_builder.drop_();
-        self.code_builder.build_fn_header_and_footer(&[], 0, None);
+        self.code_builder.call_drop(main_fn_index, 2, true);
+        self.code_builder
+            .build_fn_header_and_footer(0, &[], 0, None, &[]);
         self.reset();
 
         self.called_fns.set(main_fn_index as usize, true);
@@ -449,15 +449,23 @@ impl<'a, 'r> WasmBackend<'a, 'r> {
         // end the block from start_proc, to ensure all paths pop stack memory (if any)
         self.end_block();
 
-        if let Some(ret_var) = self.storage.return_var {
+        let ret_type = self.storage.return_var.map(|ret_var| {
             self.code_builder.get_local(ret_var);
-        }
+            let local_index = ret_var.0 as usize - self.storage.arg_types.len();
+            self.storage.local_types[local_index]
+        });
+        let ret_types: &[ValueType] = match &ret_type {
+            Some(ty) => std::slice::from_ref(ty),
+            None => &[],
+        };
 
         // Write local declarations and stack frame push/pop code
         self.code_builder.build_fn_header_and_footer(
+            self.storage.arg_types.len(),
             &self.storage.local_types,
             self.storage.stack_frame_size,
             self.storage.stack_frame_pointer,
+            ret_types,
         );
 
         if DEBUG_SETTINGS.storage_map {
@@ -568,6 +576,7 @@ impl<'a, 'r> WasmBackend<'a, 'r> {
                     other
                 ),
             };
+            n_inner_wasm_args += 1;
             self.code_builder.get_local(LocalId(0));
             // Since the closure data is wrapped in a one-element struct, we've been passed in the
             // pointer to that struct in the stack memory. To get the closure data we just need to
@@ -597,7 +606,8 @@ impl<'a, 'r> WasmBackend<'a, 'r> {
         }
 
         // Write empty function header (local variables array with zero length)
-        self.code_builder.build_fn_header_and_footer(&[], 0, None);
+        self.code_builder
+            .build_fn_header_and_footer(wrapper_arg_layouts.len(), &[], 0, None, &[]);
 
         self.module.add_function_signature(Signature {
             param_types: bumpalo::vec![in self.env.arena; I32; wrapper_arg_layouts.len()],
@@ -650,7 +660,8 @@ impl<'a, 'r> WasmBackend<'a, 'r> {
             .call(inner_wasm_fn_index, n_inner_args, true);
 
         // Write empty function header (local variables array with zero length)
-        self.code_builder.build_fn_header_and_footer(&[], 0, None);
+        self.code_builder
+            .build_fn_header_and_footer(3, &[], 0, None, &[I32]);
 
         self.module.add_function_signature(Signature {
             param_types: bumpalo::vec![in self.env.arena; I32; 3],
@@ -888,6 +899,9 @@ impl<'a, 'r> WasmBackend<'a, 'r> {
                         self.code_builder.f64_const(f64::from_bits(*value));
                         self.code_builder.f64_eq();
                     }
+                    ValueType::FuncRef | ValueType::ExternRef => {
+                        internal_error!("Roc values are never Wasm reference types")
+                    }
                 }
             }
 
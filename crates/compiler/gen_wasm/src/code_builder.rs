@@ -1,3 +1,4 @@
+use bitflags::bitflags;
 use bumpalo::collections::vec::Vec;
 use bumpalo::Bump;
 use core::panic;
@@ -5,7 +6,8 @@ use roc_wasm_module::linking::IndexRelocType;
 
 use roc_error_macros::internal_error;
 use roc_module::symbol::Symbol;
-use roc_wasm_module::opcodes::{OpCode, OpCode::*};
+use roc_target::PtrWidth;
+use roc_wasm_module::opcodes::{MiscOp, OpCode, OpCode::*, SimdOp};
 use roc_wasm_module::serialize::SerialBuffer;
 use roc_wasm_module::{
     round_up_to_alignment, Align, LocalId, RelocationEntry, ValueType, WasmModule,
@@ -20,13 +22,83 @@ macro_rules! log_instruction {
     };
 }
 
+/// Record an instruction in the call trace, so tests can check the sequence of operations
+macro_rules! trace_instruction {
+    ($self: expr, $($x: expr),+) => {
+        #[cfg(test)]
+        $self.call_trace.push(format!($($x,)*));
+    };
+}
+
+/// The type of the value an instruction pushes, if it's fixed by the opcode alone.
+/// Values from locals, globals, calls and `select` depend on context, so they are unknown.
+fn opcode_result_type(opcode: OpCode) -> Option<ValueType> {
+    match opcode as u8 {
+        // i32.load ... f64.load
+        0x28 => Some(ValueType::I32),
+        0x29 => Some(ValueType::I64),
+        0x2a => Some(ValueType::F32),
+        0x2b => Some(ValueType::F64),
+        // i32.load8_s ... i32.load16_u
+        0x2c..=0x2f => Some(ValueType::I32),
+        // i64.load8_s ... i64.load32_u
+        0x30..=0x35 => Some(ValueType::I64),
+        // memory.size, memory.grow
+        0x3f..=0x40 => Some(ValueType::I32),
+        // constants
+        0x41 => Some(ValueType::I32),
+        0x42 => Some(ValueType::I64),
+        0x43 => Some(ValueType::F32),
+        0x44 => Some(ValueType::F64),
+        // comparisons of every type, then i32 arithmetic
+        0x45..=0x78 => Some(ValueType::I32),
+        0x79..=0x8a => Some(ValueType::I64),
+        0x8b..=0x98 => Some(ValueType::F32),
+        0x99..=0xa6 => Some(ValueType::F64),
+        // conversions, grouped by result type
+        0xa7..=0xab => Some(ValueType::I32),
+        0xac..=0xb1 => Some(ValueType::I64),
+        0xb2..=0xb6 => Some(ValueType::F32),
+        0xb7..=0xbb => Some(ValueType::F64),
+        0xbc => Some(ValueType::I32),
+        0xbd => Some(ValueType::I64),
+        0xbe => Some(ValueType::F32),
+        0xbf => Some(ValueType::F64),
+        // ref.is_null, ref.func
+        0xd1 => Some(ValueType::I32),
+        0xd2 => Some(ValueType::FuncRef),
+        _ => None,
+    }
+}
+
+/// A value in our model of the VM stack
+/// The type is only known for values pushed by instructions that declare it
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct VmValue {
+    symbol: Symbol,
+    ty: Option<ValueType>,
+}
+
+impl std::fmt::Debug for VmValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.ty {
+            Some(ty) => f.write_fmt(format_args!("{:?}:{:?}", self.symbol, ty)),
+            None => f.write_fmt(format_args!("{:?}", self.symbol)),
+        }
+    }
+}
+
 /// A control block in our model of the VM
 /// Child blocks cannot "see" values from their parent block
+#[derive(Clone)]
 struct VmBlock<'a> {
     /// opcode indicating what kind of block this is
     opcode: OpCode,
     /// the stack of values for this block
-    value_stack: Vec<'a, Symbol>,
+    value_stack: Vec<'a, VmValue>,
+    /// true after `unreachable`. The validator then treats the stack as polymorphic,
+    /// so pops always succeed and the block's results don't need to be on the stack.
+    polymorphic: bool,
 }
 
 impl std::fmt::Debug for VmBlock<'_> {
@@ -58,6 +130,27 @@ struct Insertion {
     end: usize,
 }
 
+// The most recent integer constant, in case the next instruction makes it redundant
+#[derive(Debug, Clone, Copy)]
+struct LastConst {
+    opcode: OpCode,
+    value: i64,
+    start: usize,
+    end: usize,
+}
+
+/// The state of a CodeBuilder at some point, so that speculative code can be rolled back
+pub struct StackSnapshot<'a> {
+    vm_block_stack: Vec<'a, VmBlock<'a>>,
+    code_len: usize,
+    insertions_len: usize,
+    insert_bytes_len: usize,
+    import_relocations_len: usize,
+    last_const: Option<LastConst>,
+    #[cfg(test)]
+    call_trace_len: usize,
+}
+
 macro_rules! instruction_no_args {
     ($method_name: ident, $opcode: expr, $pops: expr, $push: expr) => {
         pub fn $method_name(&mut self) {
@@ -66,6 +159,17 @@ macro_rules! instruction_no_args {
     };
 }
 
+/// A binary operator that leaves its left operand unchanged when the right one is `$identity`
+macro_rules! instruction_binop_identity {
+    ($method_name: ident, $opcode: expr, $const_opcode: expr, $identity: expr) => {
+        pub fn $method_name(&mut self) {
+            if !self.remove_identity_const($const_opcode, $identity) {
+                self.inst($opcode, 2, true);
+            }
+        }
+    };
+}
+
 macro_rules! instruction_memargs {
     ($method_name: ident, $opcode: expr, $pops: expr, $push: expr) => {
         pub fn $method_name(&mut self, align: Align, offset: u32) {
@@ -74,6 +178,28 @@ macro_rules! instruction_memargs {
     };
 }
 
+bitflags! {
+    /// Optional Wasm features, beyond the MVP, that the target runtime supports.
+    /// Instructions from a feature that is not enabled are rejected in debug builds.
+    pub struct WasmFeatures: u32 {
+        /// `memory.init`, `data.drop`, `memory.fill`
+        const BULK_MEMORY = 1 << 0;
+        /// 128-bit SIMD instructions
+        const SIMD = 1 << 1;
+        /// `return_call`, `return_call_indirect`
+        const TAIL_CALL = 1 << 2;
+        /// `funcref` and `externref` values, and the `ref.*` instructions
+        const REFERENCE_TYPES = 1 << 3;
+        /// Memory instructions with a nonzero memory index
+        const MULTI_MEMORY = 1 << 4;
+    }
+}
+
+impl WasmFeatures {
+    /// Features supported by every runtime that Roc apps and tests run on
+    pub const ROC_TARGETS: Self = Self::BULK_MEMORY;
+}
+
 #[derive(Debug)]
 pub struct CodeBuilder<'a> {
     pub arena: &'a Bump,
@@ -106,15 +232,32 @@ pub struct CodeBuilder<'a> {
     /// Relocations for calls to JS imports
     /// When we remove unused imports, the live ones are re-indexed
     import_relocations: Vec<'a, (usize, u32)>,
+
+    /// One more than the highest local index accessed so far, to check against the declared locals
+    num_locals_used: u32,
+
+    /// Position of the last integer constant, for removing arithmetic identities like `x + 0`
+    last_const: Option<LastConst>,
+
+    /// Optional Wasm features that we're allowed to emit instructions for
+    features: WasmFeatures,
+
+    /// Global holding the lowest address the stack may grow down to, if we should check for overflow
+    stack_limit_global: Option<u32>,
+
+    /// Every instruction emitted so far, with its immediates
+    #[cfg(test)]
+    call_trace: std::vec::Vec<String>,
 }
 
 #[allow(clippy::new_without_default)]
 impl<'a> CodeBuilder<'a> {
-    pub fn new(arena: &'a Bump) -> Self {
+    pub fn new(arena: &'a Bump, features: WasmFeatures) -> Self {
         let mut vm_block_stack = Vec::with_capacity_in(8, arena);
         let function_block = VmBlock {
             opcode: BLOCK,
             value_stack: Vec::with_capacity_in(8, arena),
+            polymorphic: false,
         };
         vm_block_stack.push(function_block);
 
@@ -127,9 +270,21 @@ impl<'a> CodeBuilder<'a> {
             inner_length: Vec::with_capacity_in(5, arena),
             vm_block_stack,
             import_relocations: Vec::with_capacity_in(0, arena),
+            num_locals_used: 0,
+            last_const: None,
+            features,
+            stack_limit_global: None,
+            #[cfg(test)]
+            call_trace: std::vec::Vec::new(),
         }
     }
 
+    /// Make functions with a stack frame trap if it would grow the stack past the address
+    /// in `__stack_limit`, like a stack probe on native targets. `None` disables the check.
+    pub fn set_stack_check(&mut self, stack_limit_global: Option<u32>) {
+        self.stack_limit_global = stack_limit_global;
+    }
+
     pub fn clear(&mut self) {
         self.code.clear();
         self.insertions.clear();
@@ -137,9 +292,20 @@ impl<'a> CodeBuilder<'a> {
         self.preamble.clear();
         self.inner_length.clear();
         self.import_relocations.clear();
+        self.num_locals_used = 0;
+        self.last_const = None;
 
         self.vm_block_stack.truncate(1);
         self.vm_block_stack[0].value_stack.clear();
+        self.vm_block_stack[0].polymorphic = false;
+
+        #[cfg(test)]
+        self.call_trace.clear();
+    }
+
+    #[cfg(test)]
+    fn call_trace(&self) -> &[String] {
+        &self.call_trace
     }
 
     /**********************************************************
@@ -152,12 +318,12 @@ impl<'a> CodeBuilder<'a> {
 
     ***********************************************************/
 
-    fn current_stack(&self) -> &Vec<'a, Symbol> {
+    fn current_stack(&self) -> &Vec<'a, VmValue> {
         let block = self.vm_block_stack.last().unwrap();
         &block.value_stack
     }
 
-    fn current_stack_mut(&mut self) -> &mut Vec<'a, Symbol> {
+    fn current_stack_mut(&mut self) -> &mut Vec<'a, VmValue> {
         let block = self.vm_block_stack.last_mut().unwrap();
         &mut block.value_stack
     }
@@ -167,10 +333,10 @@ impl<'a> CodeBuilder<'a> {
     pub fn set_top_symbol(&mut self, sym: Symbol) -> VmSymbolState {
         let current_stack = &mut self.vm_block_stack.last_mut().unwrap().value_stack;
         let pushed_at = self.code.len();
-        let top_symbol: &mut Symbol = current_stack
+        let top_value: &mut VmValue = current_stack
             .last_mut()
             .unwrap_or_else(|| internal_error!("Empty stack when trying to set Symbol {:?}", sym));
-        *top_symbol = sym;
+        top_value.symbol = sym;
 
         VmSymbolState::Pushed { pushed_at }
     }
@@ -186,14 +352,56 @@ impl<'a> CodeBuilder<'a> {
         let offset = stack_depth - n_symbols;
 
         for (i, sym) in symbols.iter().enumerate() {
-            if current_stack[offset + i] != *sym {
+            if current_stack[offset + i].symbol != *sym {
                 return false;
             }
         }
         true
     }
 
+    /// The type of the value at the top of the VM stack, if it's known
+    pub fn top_type(&self) -> Option<ValueType> {
+        self.current_stack().last().and_then(|value| value.ty)
+    }
+
+    /// Save the VM stack model and code position, before emitting code we might want to discard
+    pub fn snapshot_stack(&self) -> StackSnapshot<'a> {
+        StackSnapshot {
+            vm_block_stack: self.vm_block_stack.clone(),
+            code_len: self.code.len(),
+            insertions_len: self.insertions.len(),
+            insert_bytes_len: self.insert_bytes.len(),
+            import_relocations_len: self.import_relocations.len(),
+            last_const: self.last_const,
+            #[cfg(test)]
+            call_trace_len: self.call_trace.len(),
+        }
+    }
+
+    /// Discard everything emitted since the snapshot was taken
+    pub fn restore_stack(&mut self, snapshot: StackSnapshot<'a>) {
+        debug_assert!(
+            self.code.len() >= snapshot.code_len,
+            "Cannot restore a snapshot at byte {} when the code is only {} bytes long",
+            snapshot.code_len,
+            self.code.len()
+        );
+        self.vm_block_stack = snapshot.vm_block_stack;
+        self.code.truncate(snapshot.code_len);
+        self.insertions.truncate(snapshot.insertions_len);
+        self.insert_bytes.truncate(snapshot.insert_bytes_len);
+        self.import_relocations
+            .truncate(snapshot.import_relocations_len);
+        self.last_const = snapshot.last_const;
+
+        #[cfg(test)]
+        self.call_trace.truncate(snapshot.call_trace_len);
+    }
+
     fn add_insertion(&mut self, insert_at: usize, opcode: OpCode, immediate: u32) {
+        // Insertions are always local.set or local.tee
+        self.note_local(LocalId(immediate));
+
         let start = self.insert_bytes.len();
 
         self.insert_bytes.push(opcode as u8);
@@ -235,7 +443,7 @@ impl<'a> CodeBuilder<'a> {
 
             Pushed { pushed_at } => {
                 match self.current_stack().last() {
-                    Some(top_symbol) if *top_symbol == symbol => {
+                    Some(top_value) if top_value.symbol == symbol => {
                         // We're lucky, the symbol is already on top of the current block's stack.
                         // No code to generate! (This reduces code size by up to 25% in tests.)
                         // Just let the caller know what happened
@@ -311,7 +519,7 @@ impl<'a> CodeBuilder<'a> {
         // Update our stack model at the position where we're going to set the SETLOCAL
         let mut found = false;
         for block in self.vm_block_stack.iter_mut() {
-            if let Some(found_index) = block.value_stack.iter().position(|&s| s == symbol) {
+            if let Some(found_index) = block.value_stack.iter().position(|v| v.symbol == symbol) {
                 block.value_stack.remove(found_index);
                 found = true;
             }
@@ -346,7 +554,8 @@ impl<'a> CodeBuilder<'a> {
             return;
         }
 
-        // Write declarations in batches of the same ValueType
+        // Write declarations in batches of the same ValueType.
+        // Only adjacent locals are batched, so every local keeps the index the caller gave it.
         let mut num_batches: u32 = 0;
         let mut batch_type = local_types[0];
         let mut batch_size = 0;
@@ -391,6 +600,19 @@ impl<'a> CodeBuilder<'a> {
         self.preamble.encode_u32(frame_pointer.0);
         self.preamble.push(SETGLOBAL as u8);
         self.preamble.encode_u32(STACK_POINTER_GLOBAL_ID);
+
+        // Trap on overflow. The stack grows down, so the new frame must not start below the limit.
+        if let Some(limit_global) = self.stack_limit_global {
+            self.preamble.push(GETLOCAL as u8);
+            self.preamble.encode_u32(frame_pointer.0);
+            self.preamble.push(GETGLOBAL as u8);
+            self.preamble.encode_u32(limit_global);
+            self.preamble.push(I32LTU as u8);
+            self.preamble.push(IF as u8);
+            self.preamble.push(ValueType::VOID);
+            self.preamble.push(UNREACHABLE as u8);
+            self.preamble.push(END as u8);
+        }
     }
 
     /// Generate instruction bytes to release a frame of stack memory on leaving the function
@@ -401,14 +623,55 @@ impl<'a> CodeBuilder<'a> {
         self.set_global(STACK_POINTER_GLOBAL_ID);
     }
 
+    /// Check that the VM stack holds exactly the function's declared results.
+    /// Types are only compared where the stack model knows them.
+    fn verify_results(&self, expected_results: &[ValueType]) {
+        let block = self.vm_block_stack.last().unwrap();
+        let stack = &block.value_stack;
+
+        // After `unreachable`, any missing results are taken from the polymorphic stack
+        let n_missing = expected_results.len().saturating_sub(stack.len());
+        let len_ok = if block.polymorphic {
+            stack.len() <= expected_results.len()
+        } else {
+            stack.len() == expected_results.len()
+        };
+
+        debug_assert!(
+            len_ok
+                && stack
+                    .iter()
+                    .zip(&expected_results[n_missing..])
+                    .all(|(value, ty)| value.ty.is_none() || value.ty == Some(*ty)),
+            "Function should return {:?} but the VM stack is {:?}",
+            expected_results,
+            stack
+        );
+    }
+
+    /// Check that every local we accessed is either a param or a declared local
+    fn verify_locals(&self, num_params: usize, local_types: &[ValueType]) {
+        let num_declared = num_params + local_types.len();
+        debug_assert!(
+            self.num_locals_used as usize <= num_declared,
+            "Local {} is out of range. The function only declares {} params and {} locals",
+            self.num_locals_used - 1,
+            num_params,
+            local_types.len()
+        );
+    }
+
     /// Build the function header: local declarations, stack frame push/pop code, and function length
     /// After this, all bytes have been generated (but not yet serialized) and we know the final size.
     pub fn build_fn_header_and_footer(
         &mut self,
+        num_params: usize,
         local_types: &[ValueType],
         frame_size: i32,
         frame_pointer: Option<LocalId>,
+        expected_results: &[ValueType],
     ) {
+        self.verify_results(expected_results);
         self.build_local_declarations(local_types);
 
         if frame_size != 0 {
@@ -419,6 +682,8 @@ impl<'a> CodeBuilder<'a> {
             }
         }
 
+        self.verify_locals(num_params, local_types);
+
         self.code.push(END as u8);
 
         let inner_len = self.preamble.len() + self.code.len() + self.insert_bytes.len();
@@ -438,6 +703,16 @@ impl<'a> CodeBuilder<'a> {
         self.inner_length.len() + self.preamble.len() + self.code.len() + self.insert_bytes.len()
     }
 
+    /// Number of bytes of instructions emitted so far, not counting the function header
+    pub fn code_len(&self) -> usize {
+        self.code.len() + self.insert_bytes.len()
+    }
+
+    /// True if no instructions have been emitted yet
+    pub fn is_empty(&self) -> bool {
+        self.code_len() == 0
+    }
+
     /// Serialize all byte vectors in the right order
     /// Insert relocations for imported functions
     pub fn insert_into_module(&self, module: &mut WasmModule<'a>) {
@@ -493,23 +768,35 @@ impl<'a> CodeBuilder<'a> {
 
     ***********************************************************/
 
+    fn require_feature(&self, feature: WasmFeatures) {
+        debug_assert!(
+            self.features.contains(feature),
+            "Wasm feature {:?} is not enabled for this module",
+            feature
+        );
+    }
+
     /// Base method for generating instructions
     /// Emits the opcode and simulates VM stack push/pop
     fn inst_base(&mut self, opcode: OpCode, pops: usize, push: bool) {
-        let current_stack = self.current_stack_mut();
+        let block = self.vm_block_stack.last_mut().unwrap();
+        let current_stack = &mut block.value_stack;
         let stack_size = current_stack.len();
 
         debug_assert!(
-            stack_size >= pops,
+            stack_size >= pops || block.polymorphic,
             "Wasm value stack underflow. Tried to pop {} but only {} available",
             pops,
             stack_size
         );
 
-        let new_len = stack_size - pops;
+        let new_len = stack_size.saturating_sub(pops);
         current_stack.truncate(new_len);
         if push {
-            current_stack.push(Symbol::WASM_TMP);
+            current_stack.push(VmValue {
+                symbol: Symbol::WASM_TMP,
+                ty: opcode_result_type(opcode),
+            });
         }
         self.code.push(opcode as u8);
     }
@@ -517,6 +804,7 @@ impl<'a> CodeBuilder<'a> {
     /// Plain instruction without any immediates
     fn inst(&mut self, opcode: OpCode, pops: usize, push: bool) {
         self.inst_base(opcode, pops, push);
+        trace_instruction!(self, "{:?}", opcode);
         log_instruction!(
             "{:10}\t\t{:?}",
             format!("{:?}", opcode),
@@ -536,14 +824,17 @@ impl<'a> CodeBuilder<'a> {
         self.vm_block_stack.push(VmBlock {
             opcode,
             value_stack: Vec::with_capacity_in(8, self.arena),
+            polymorphic: false,
         });
 
+        trace_instruction!(self, "{:?}", opcode);
         log_instruction!("{:10}\t{:?}", format!("{:?}", opcode), &self.vm_block_stack);
     }
 
     fn inst_imm32(&mut self, opcode: OpCode, pops: usize, push: bool, immediate: u32) {
         self.inst_base(opcode, pops, push);
         self.code.encode_u32(immediate);
+        trace_instruction!(self, "{:?} {}", opcode, immediate);
         log_instruction!(
             "{:10}\t{}\t{:?}",
             format!("{:?}", opcode),
@@ -556,6 +847,7 @@ impl<'a> CodeBuilder<'a> {
         self.inst_base(opcode, pops, push);
         self.code.push(align as u8);
         self.code.encode_u32(offset);
+        trace_instruction!(self, "{:?} {:?} {}", opcode, align, offset);
         log_instruction!(
             "{:10} {:?} {}\t{:?}",
             format!("{:?}", opcode),
@@ -565,6 +857,49 @@ impl<'a> CodeBuilder<'a> {
         );
     }
 
+    /// Instruction with the `MISC` prefix, and one immediate
+    fn inst_misc(&mut self, misc_op: MiscOp, pops: usize, push: bool, immediate: u32) {
+        self.require_feature(WasmFeatures::BULK_MEMORY);
+        self.inst_base(MISC, pops, push);
+        self.code.encode_u32(misc_op as u32);
+        self.code.encode_u32(immediate);
+        trace_instruction!(self, "{:?} {}", misc_op, immediate);
+        log_instruction!(
+            "{:10}\t{}\t{:?}",
+            format!("{:?}", misc_op),
+            immediate,
+            self.vm_block_stack
+        );
+    }
+
+    /// Instruction with the `SIMD` prefix and no immediates
+    fn inst_simd(&mut self, simd_op: SimdOp, pops: usize, push: bool) {
+        self.require_feature(WasmFeatures::SIMD);
+        self.inst_base(SIMD, pops, push);
+        self.code.encode_u32(simd_op as u32);
+        trace_instruction!(self, "{:?}", simd_op);
+        log_instruction!(
+            "{:10}\t\t{:?}",
+            format!("{:?}", simd_op),
+            self.vm_block_stack
+        );
+    }
+
+    /// Instruction with the `SIMD` prefix and a lane index immediate
+    fn inst_simd_lane(&mut self, simd_op: SimdOp, pops: usize, push: bool, lane: u8) {
+        self.require_feature(WasmFeatures::SIMD);
+        self.inst_base(SIMD, pops, push);
+        self.code.encode_u32(simd_op as u32);
+        self.code.push(lane);
+        trace_instruction!(self, "{:?} {}", simd_op, lane);
+        log_instruction!(
+            "{:10}\t{}\t{:?}",
+            format!("{:?}", simd_op),
+            lane,
+            self.vm_block_stack
+        );
+    }
+
     /**********************************************************
 
         INSTRUCTION METHODS
@@ -575,9 +910,35 @@ impl<'a> CodeBuilder<'a> {
 
     ***********************************************************/
 
-    instruction_no_args!(unreachable_, UNREACHABLE, 0, false);
+    pub fn unreachable_(&mut self) {
+        self.inst(UNREACHABLE, 0, false);
+
+        // Nothing after this can run, so the rest of the block type-checks against any stack
+        self.vm_block_stack.last_mut().unwrap().polymorphic = true;
+    }
     instruction_no_args!(nop, NOP, 0, false);
 
+    /// Emit `nop` bytes until the code length is a multiple of `align`.
+    /// This is for tools that expect function bodies at aligned offsets. It's about layout, not performance!
+    /// Our model of the VM stack is unaffected.
+    pub fn pad_to_alignment(&mut self, align: usize) {
+        debug_assert!(align > 0, "Cannot pad Wasm code to an alignment of 0");
+        while self.code.len() % align != 0 {
+            self.code.push(NOP as u8);
+        }
+    }
+
+    /// Reserve 5 bytes of code for a LEB128-encoded u32 that will be written later by `patch_leb`.
+    /// Returns the offset of the slot in the code. Our model of the VM stack is unaffected.
+    pub fn emit_leb_placeholder(&mut self) -> usize {
+        self.code.reserve_padded_u32()
+    }
+
+    /// Write `value` into a slot from `emit_leb_placeholder`, padded to fill all 5 bytes
+    pub fn patch_leb(&mut self, at: usize, value: u32) {
+        self.code.overwrite_padded_u32(at, value);
+    }
+
     pub fn block(&mut self) {
         self.inst_block(BLOCK, 0);
     }
@@ -587,9 +948,45 @@ impl<'a> CodeBuilder<'a> {
     pub fn if_(&mut self) {
         self.inst_block(IF, 1);
     }
+    /// Emit a `block`, with the instructions from `f` inside it, then its `end`.
+    /// Our blocks have no result type, so `f` must leave the value stack as it found it,
+    /// and must close any blocks it opens.
+    pub fn scoped_block<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        self.block();
+        let depth = self.vm_block_stack.len();
+        let result = f(self);
+        debug_assert_eq!(
+            self.vm_block_stack.len(),
+            depth,
+            "Unbalanced Wasm blocks inside scoped_block"
+        );
+        let block = self.vm_block_stack.last().unwrap();
+        debug_assert!(
+            block.polymorphic || block.value_stack.is_empty(),
+            "Wasm scoped_block must leave the value stack empty, but it has {} values",
+            block.value_stack.len()
+        );
+        self.end();
+        result
+    }
+    /// Start an `if` block that runs only when the pointer in `ptr_local` is non-null.
+    /// Wasm treats any non-zero i32 as true, so the pointer itself is the condition.
+    /// Close it with `else_` and/or `end` as usual.
+    pub fn if_nonnull(&mut self, ptr_local: LocalId) {
+        self.get_local(ptr_local);
+        self.if_();
+    }
+    /// Trap if the i32 condition on top of the stack is non-zero. Consumes the condition.
+    pub fn trap_if(&mut self) {
+        self.if_();
+        self.unreachable_();
+        self.end();
+    }
     pub fn else_(&mut self) {
         // Reuse the 'then' block but clear its value stack
-        self.current_stack_mut().clear();
+        let block = self.vm_block_stack.last_mut().unwrap();
+        block.value_stack.clear();
+        block.polymorphic = false;
         self.inst(ELSE, 0, false);
     }
 
@@ -609,6 +1006,7 @@ impl<'a> CodeBuilder<'a> {
         self.inst_base(END, 0, false);
         self.vm_block_stack.pop();
 
+        trace_instruction!(self, "{:?}", END);
         log_instruction!("END       \t\t{:?}", &self.vm_block_stack);
     }
     pub fn br(&mut self, levels: u32) {
@@ -620,9 +1018,50 @@ impl<'a> CodeBuilder<'a> {
         // where the branch was not taken. So we only pop 1 value, the condition.
         self.inst_imm32(BRIF, 1, false, levels);
     }
-    #[allow(dead_code)]
-    fn br_table() {
-        todo!("br instruction");
+    /// Branch to one of the `targets` using the i32 index on top of the stack,
+    /// or to `default` if the index is out of range.
+    pub fn br_table(&mut self, targets: &[u32], default: u32) {
+        let open_blocks = self.vm_block_stack.len();
+        for (i, target) in targets.iter().chain(std::iter::once(&default)).enumerate() {
+            debug_assert!(
+                (*target as usize) < open_blocks,
+                "br_table target {} at index {} is out of range, with only {} blocks open",
+                target,
+                i,
+                open_blocks
+            );
+        }
+
+        self.inst_base(BRTABLE, 1, false);
+        self.code.encode_u32(targets.len() as u32);
+        for target in targets {
+            self.code.encode_u32(*target);
+        }
+        self.code.encode_u32(default);
+        trace_instruction!(self, "{:?} {:?} {}", BRTABLE, targets, default);
+        log_instruction!(
+            "{:10}\t{:?} {}\t{:?}",
+            format!("{:?}", BRTABLE),
+            targets,
+            default,
+            self.vm_block_stack
+        );
+    }
+
+    /// Jump to one of `branch_count` dense cases, selected by the i32 in `selector`.
+    /// Out-of-range values go to the last case, like the default branch of `Stmt::Switch`.
+    ///
+    /// Opens `branch_count` nested blocks and emits a `br_table` in the innermost one.
+    /// Case `i` is the code following the `i`th call to `end`. The caller is responsible
+    /// for wrapping the whole thing in an outer block that the cases can `br` out of.
+    pub fn switch_on_i32(&mut self, selector: LocalId, branch_count: u32) {
+        debug_assert!(branch_count > 0, "switch_on_i32 needs at least one branch");
+        for _ in 0..branch_count {
+            self.block();
+        }
+        self.get_local(selector);
+        let targets = Vec::from_iter_in(0..branch_count - 1, self.arena);
+        self.br_table(&targets, branch_count - 1);
     }
 
     instruction_no_args!(return_, RETURN, 0, false);
@@ -631,6 +1070,86 @@ impl<'a> CodeBuilder<'a> {
         self.call_impl(function_index, n_args, has_return_val, false)
     }
 
+    /// Copy a whole passive data segment to the address in `dest_local`, then drop the segment.
+    /// After this, the segment can't be used again, so it's only for data that is loaded once.
+    pub fn init_from_data_segment(&mut self, segment: u32, dest_local: LocalId, byte_len: u32) {
+        self.get_local(dest_local);
+        self.i32_const(0);
+        self.i32_const(byte_len as i32);
+        self.memory_init(segment);
+        self.data_drop(segment);
+    }
+
+    /// Fill `byte_len` bytes at the address in `dest_local` with zeros.
+    /// Without bulk memory, this is a loop of 8-byte stores that counts down in `tmp_local`.
+    pub fn memset_zero(&mut self, dest_local: LocalId, byte_len: u32, tmp_local: LocalId) {
+        if byte_len == 0 {
+            return;
+        }
+
+        if self.features.contains(WasmFeatures::BULK_MEMORY) {
+            self.get_local(dest_local);
+            self.i32_const(0);
+            self.i32_const(byte_len as i32);
+            self.memory_fill();
+            return;
+        }
+
+        let loop_bytes = byte_len & !7;
+        if loop_bytes > 0 {
+            self.i32_const(loop_bytes as i32);
+            self.set_local(tmp_local);
+            self.loop_();
+            {
+                self.get_local(tmp_local);
+                self.i32_const(8);
+                self.i32_sub();
+                self.tee_local(tmp_local);
+                self.get_local(dest_local);
+                self.i32_add();
+                self.i64_const(0);
+                self.i64_store(Align::Bytes1, 0);
+
+                self.get_local(tmp_local);
+                self.br_if(0);
+            }
+            self.end();
+        }
+
+        let mut i = loop_bytes;
+        if byte_len - i >= 4 {
+            self.get_local(dest_local);
+            self.i32_const(0);
+            self.i32_store(Align::Bytes1, i);
+            i += 4;
+        }
+        while byte_len - i > 0 {
+            self.get_local(dest_local);
+            self.i32_const(0);
+            self.i32_store8(Align::Bytes1, i);
+            i += 1;
+        }
+    }
+
+    /// Call a function that returns a value, and label the result with `result_sym`
+    pub fn call_with_result(
+        &mut self,
+        function_index: u32,
+        pops: usize,
+        result_sym: Symbol,
+    ) -> VmSymbolState {
+        self.call(function_index, pops, true);
+        self.set_top_symbol(result_sym)
+    }
+
+    /// Call a function whose return value (if any) is not needed
+    pub fn call_drop(&mut self, function_index: u32, n_args: usize, has_return_val: bool) {
+        self.call(function_index, n_args, has_return_val);
+        if has_return_val {
+            self.drop_();
+        }
+    }
+
     pub fn call_import(&mut self, function_index: u32, n_args: usize, has_return_val: bool) {
         self.call_impl(function_index, n_args, has_return_val, true)
     }
@@ -652,6 +1171,7 @@ impl<'a> CodeBuilder<'a> {
 
         self.code.encode_padded_u32(function_index);
 
+        trace_instruction!(self, "{:?} {}", CALL, function_index);
         log_instruction!(
             "{:10}\t{}\t{:?}",
             format!("{:?}", CALL),
@@ -660,6 +1180,64 @@ impl<'a> CodeBuilder<'a> {
         );
     }
 
+    /// Tail call: call a function with `pops` args and return its result from this function.
+    /// Needs the Wasm tail call proposal, which is not yet supported by every runtime.
+    pub fn return_call(&mut self, function_index: u32, pops: usize) {
+        self.return_call_impl(function_index, pops, false)
+    }
+
+    /// Tail call to an imported function
+    pub fn return_call_import(&mut self, function_index: u32, pops: usize) {
+        self.return_call_impl(function_index, pops, true)
+    }
+
+    #[inline(always)]
+    fn return_call_impl(&mut self, function_index: u32, pops: usize, is_import: bool) {
+        self.require_feature(WasmFeatures::TAIL_CALL);
+        self.inst_base(RETURNCALL, pops, false);
+
+        if is_import {
+            self.import_relocations
+                .push((self.code.len(), function_index));
+        }
+
+        self.code.encode_padded_u32(function_index);
+        self.vm_block_stack.last_mut().unwrap().polymorphic = true;
+
+        trace_instruction!(self, "{:?} {}", RETURNCALL, function_index);
+        log_instruction!(
+            "{:10}\t{}\t{:?}",
+            format!("{:?}", RETURNCALL),
+            function_index,
+            self.vm_block_stack
+        );
+    }
+
+    /// Tail call through a function table. `pops` includes the table element index on top of the args.
+    /// Needs the Wasm tail call proposal, which is not yet supported by every runtime.
+    pub fn return_call_indirect(&mut self, type_index: u32, table_index: u32, pops: usize) {
+        self.require_feature(WasmFeatures::TAIL_CALL);
+        self.inst_base(RETURNCALLINDIRECT, pops, false);
+        self.code.encode_u32(type_index);
+        self.code.encode_u32(table_index);
+        self.vm_block_stack.last_mut().unwrap().polymorphic = true;
+
+        trace_instruction!(
+            self,
+            "{:?} {} {}",
+            RETURNCALLINDIRECT,
+            type_index,
+            table_index
+        );
+        log_instruction!(
+            "{:10}\t{} {}\t{:?}",
+            format!("{:?}", RETURNCALLINDIRECT),
+            type_index,
+            table_index,
+            self.vm_block_stack
+        );
+    }
+
     #[allow(dead_code)]
     fn call_indirect() {
         unimplemented!(
@@ -670,13 +1248,20 @@ impl<'a> CodeBuilder<'a> {
     instruction_no_args!(drop_, DROP, 1, false);
     instruction_no_args!(select, SELECT, 3, true);
 
+    fn note_local(&mut self, id: LocalId) {
+        self.num_locals_used = self.num_locals_used.max(id.0 + 1);
+    }
+
     pub fn get_local(&mut self, id: LocalId) {
+        self.note_local(id);
         self.inst_imm32(GETLOCAL, 0, true, id.0);
     }
     pub fn set_local(&mut self, id: LocalId) {
+        self.note_local(id);
         self.inst_imm32(SETLOCAL, 1, false, id.0);
     }
     pub fn tee_local(&mut self, id: LocalId) {
+        self.note_local(id);
         self.inst_imm32(TEELOCAL, 0, false, id.0);
     }
     pub fn get_global(&mut self, id: u32) {
@@ -686,6 +1271,39 @@ impl<'a> CodeBuilder<'a> {
         self.inst_imm32(SETGLOBAL, 1, false, id);
     }
 
+    /// Like `get_global`, but also records the global's type in our model of the VM stack
+    pub fn get_global_typed(&mut self, id: u32, ty: ValueType) {
+        self.get_global(id);
+        self.current_stack_mut().last_mut().unwrap().ty = Some(ty);
+    }
+
+    /// Like `drop_`, but returns the type of the dropped value.
+    /// Panics if the stack is empty or the type of the top value is not known.
+    pub fn drop_typed(&mut self) -> ValueType {
+        let top = self.current_stack().last().copied();
+        assert!(
+            top.is_some(),
+            "Tried to drop from an empty Wasm value stack"
+        );
+        let ty = top.and_then(|value| value.ty);
+        assert!(ty.is_some(), "Tried to drop a Wasm value of unknown type");
+        self.drop_();
+        ty.unwrap()
+    }
+
+    /// Like `set_global`, but checks the popped value against the global's type, where known
+    pub fn set_global_typed(&mut self, id: u32, ty: ValueType) {
+        let top_type = self.current_stack().last().and_then(|value| value.ty);
+        debug_assert!(
+            top_type.is_none() || top_type == Some(ty),
+            "Wasm type mismatch setting global {}. Expected {:?} but found {:?}",
+            id,
+            ty,
+            top_type
+        );
+        self.set_global(id);
+    }
+
     instruction_memargs!(i32_load, I32LOAD, 1, true);
     instruction_memargs!(i64_load, I64LOAD, 1, true);
     instruction_memargs!(f32_load, F32LOAD, 1, true);
@@ -711,18 +1329,65 @@ impl<'a> CodeBuilder<'a> {
     instruction_memargs!(i64_store32, I64STORE32, 2, false);
 
     pub fn memory_size(&mut self) {
-        self.inst(CURRENTMEMORY, 0, true);
-        self.code.push(0);
+        self.memory_size_indexed(0);
     }
     pub fn memory_grow(&mut self) {
-        self.inst(GROWMEMORY, 1, true);
+        self.memory_grow_indexed(0);
+    }
+    pub fn memory_size_indexed(&mut self, mem: u32) {
+        if mem != 0 {
+            self.require_feature(WasmFeatures::MULTI_MEMORY);
+        }
+        self.inst_imm32(CURRENTMEMORY, 0, true, mem);
+    }
+    pub fn memory_grow_indexed(&mut self, mem: u32) {
+        if mem != 0 {
+            self.require_feature(WasmFeatures::MULTI_MEMORY);
+        }
+        self.inst_imm32(GROWMEMORY, 1, true, mem);
+    }
+
+    /// Push a null reference. `heap_type` is the encoding of `funcref` or `externref`.
+    pub fn ref_null(&mut self, heap_type: ValueType) {
+        self.require_feature(WasmFeatures::REFERENCE_TYPES);
+        debug_assert!(matches!(
+            heap_type,
+            ValueType::FuncRef | ValueType::ExternRef
+        ));
+        self.inst_base(REFNULL, 0, true);
+        self.code.push(heap_type as u8);
+        trace_instruction!(self, "{:?} {:?}", REFNULL, heap_type);
+        log_instruction!(
+            "{:10}\t{:?}\t{:?}",
+            format!("{:?}", REFNULL),
+            heap_type,
+            self.vm_block_stack
+        );
+    }
+    pub fn ref_is_null(&mut self) {
+        self.require_feature(WasmFeatures::REFERENCE_TYPES);
+        self.inst(REFISNULL, 1, true);
+    }
+    pub fn ref_func(&mut self, function_index: u32) {
+        self.require_feature(WasmFeatures::REFERENCE_TYPES);
+        self.inst_imm32(REFFUNC, 0, true, function_index);
+    }
+    pub fn memory_init(&mut self, segment: u32) {
+        self.inst_misc(MiscOp::MEMORYINIT, 3, false, segment);
         self.code.push(0);
     }
+    pub fn data_drop(&mut self, segment: u32) {
+        self.inst_misc(MiscOp::DATADROP, 0, false, segment);
+    }
+    pub fn memory_fill(&mut self) {
+        self.inst_misc(MiscOp::MEMORYFILL, 3, false, 0);
+    }
 
-    fn log_const<T>(&self, opcode: OpCode, x: T)
+    fn log_const<T>(&mut self, opcode: OpCode, x: T)
     where
         T: std::fmt::Debug + std::fmt::Display,
     {
+        trace_instruction!(self, "{:?} {}", opcode, x);
         log_instruction!(
             "{:10}\t{}\t{:?}",
             format!("{:?}", opcode),
@@ -731,14 +1396,81 @@ impl<'a> CodeBuilder<'a> {
         );
     }
     pub fn i32_const(&mut self, x: i32) {
+        let start = self.code.len();
         self.inst_base(I32CONST, 0, true);
         self.code.encode_i32(x);
         self.log_const(I32CONST, x);
+        self.set_last_const(I32CONST, x as i64, start);
     }
     pub fn i64_const(&mut self, x: i64) {
+        let start = self.code.len();
         self.inst_base(I64CONST, 0, true);
         self.code.encode_i64(x);
         self.log_const(I64CONST, x);
+        self.set_last_const(I64CONST, x, start);
+    }
+    fn set_last_const(&mut self, opcode: OpCode, value: i64, start: usize) {
+        self.last_const = Some(LastConst {
+            opcode,
+            value,
+            start,
+            end: self.code.len(),
+        });
+    }
+
+    /// If the last instruction was an anonymous constant equal to `identity`, remove it.
+    /// The left operand is left on the stack as the result, and the operator is not emitted.
+    fn remove_identity_const(&mut self, const_opcode: OpCode, identity: i64) -> bool {
+        let last = match self.last_const {
+            Some(last) if last.opcode == const_opcode && last.value == identity => last,
+            _ => return false,
+        };
+
+        // Nothing else can have been emitted since the constant
+        if last.end != self.code.len() {
+            return false;
+        }
+
+        // The constant must not have been given a Symbol, which could be loaded again later
+        let stack = self.current_stack();
+        let n = stack.len();
+        if n < 2 || stack[n - 1].symbol != Symbol::WASM_TMP {
+            return false;
+        }
+
+        // Don't remove code that an insertion needs to go after
+        if self.insertions.iter().any(|ins| ins.at > last.start) {
+            return false;
+        }
+
+        self.code.truncate(last.start);
+        self.last_const = None;
+
+        // The left operand becomes the result
+        let stack = self.current_stack_mut();
+        stack.pop();
+        stack.last_mut().unwrap().symbol = Symbol::WASM_TMP;
+
+        #[cfg(test)]
+        self.call_trace.pop();
+        log_instruction!(
+            "**removed {:?} {} identity**\t{:?}",
+            const_opcode,
+            identity,
+            self.vm_block_stack
+        );
+
+        true
+    }
+    /// Push a constant of the target's pointer-sized integer type
+    pub fn isize_const(&mut self, x: i64, ptr_width: PtrWidth) {
+        match ptr_width {
+            PtrWidth::Bytes4 => {
+                debug_assert!(i32::try_from(x).is_ok(), "{} doesn't fit in an i32", x);
+                self.i32_const(x as i32)
+            }
+            PtrWidth::Bytes8 => self.i64_const(x),
+        }
     }
     pub fn f32_const(&mut self, x: f32) {
         self.inst_base(F32CONST, 0, true);
@@ -750,6 +1482,19 @@ impl<'a> CodeBuilder<'a> {
         self.code.encode_f64(x);
         self.log_const(F64CONST, x);
     }
+    /// Push a constant of any type, from its bit pattern.
+    /// Integers use the low bits, floats are reinterpreted with `from_bits`.
+    pub fn const_from_bits(&mut self, ty: ValueType, bits: u64) {
+        match ty {
+            ValueType::I32 => self.i32_const(bits as u32 as i32),
+            ValueType::I64 => self.i64_const(bits as i64),
+            ValueType::F32 => self.f32_const(f32::from_bits(bits as u32)),
+            ValueType::F64 => self.f64_const(f64::from_bits(bits)),
+            ValueType::FuncRef | ValueType::ExternRef => {
+                internal_error!("There are no constants of reference type {:?}", ty)
+            }
+        }
+    }
 
     // TODO: Consider creating unified methods for numerical ops like 'eq' and 'add',
     // passing the ValueType as an argument. Could simplify lowlevel code gen.
@@ -790,14 +1535,14 @@ impl<'a> CodeBuilder<'a> {
     instruction_no_args!(i32_clz, I32CLZ, 1, true);
     instruction_no_args!(i32_ctz, I32CTZ, 1, true);
     instruction_no_args!(i32_popcnt, I32POPCNT, 1, true);
-    instruction_no_args!(i32_add, I32ADD, 2, true);
-    instruction_no_args!(i32_sub, I32SUB, 2, true);
-    instruction_no_args!(i32_mul, I32MUL, 2, true);
+    instruction_binop_identity!(i32_add, I32ADD, I32CONST, 0);
+    instruction_binop_identity!(i32_sub, I32SUB, I32CONST, 0);
+    instruction_binop_identity!(i32_mul, I32MUL, I32CONST, 1);
     instruction_no_args!(i32_div_s, I32DIVS, 2, true);
     instruction_no_args!(i32_div_u, I32DIVU, 2, true);
     instruction_no_args!(i32_rem_s, I32REMS, 2, true);
     instruction_no_args!(i32_rem_u, I32REMU, 2, true);
-    instruction_no_args!(i32_and, I32AND, 2, true);
+    instruction_binop_identity!(i32_and, I32AND, I32CONST, -1);
     instruction_no_args!(i32_or, I32OR, 2, true);
     instruction_no_args!(i32_xor, I32XOR, 2, true);
     instruction_no_args!(i32_shl, I32SHL, 2, true);
@@ -808,14 +1553,14 @@ impl<'a> CodeBuilder<'a> {
     instruction_no_args!(i64_clz, I64CLZ, 1, true);
     instruction_no_args!(i64_ctz, I64CTZ, 1, true);
     instruction_no_args!(i64_popcnt, I64POPCNT, 1, true);
-    instruction_no_args!(i64_add, I64ADD, 2, true);
-    instruction_no_args!(i64_sub, I64SUB, 2, true);
-    instruction_no_args!(i64_mul, I64MUL, 2, true);
+    instruction_binop_identity!(i64_add, I64ADD, I64CONST, 0);
+    instruction_binop_identity!(i64_sub, I64SUB, I64CONST, 0);
+    instruction_binop_identity!(i64_mul, I64MUL, I64CONST, 1);
     instruction_no_args!(i64_div_s, I64DIVS, 2, true);
     instruction_no_args!(i64_div_u, I64DIVU, 2, true);
     instruction_no_args!(i64_rem_s, I64REMS, 2, true);
     instruction_no_args!(i64_rem_u, I64REMU, 2, true);
-    instruction_no_args!(i64_and, I64AND, 2, true);
+    instruction_binop_identity!(i64_and, I64AND, I64CONST, -1);
     instruction_no_args!(i64_or, I64OR, 2, true);
     instruction_no_args!(i64_xor, I64XOR, 2, true);
     instruction_no_args!(i64_shl, I64SHL, 2, true);
@@ -876,4 +1621,967 @@ impl<'a> CodeBuilder<'a> {
     instruction_no_args!(i64_reinterpret_f64, I64REINTERPRETF64, 1, true);
     instruction_no_args!(f32_reinterpret_i32, F32REINTERPRETI32, 1, true);
     instruction_no_args!(f64_reinterpret_i64, F64REINTERPRETI64, 1, true);
+
+    pub fn i32x4_splat(&mut self) {
+        self.inst_simd(SimdOp::I32X4SPLAT, 1, true);
+    }
+    pub fn i32x4_extract_lane(&mut self, lane: u8) {
+        debug_assert!(lane < 4, "i32x4 lane index {} out of range", lane);
+        self.inst_simd_lane(SimdOp::I32X4EXTRACTLANE, 1, true, lane);
+    }
+    pub fn i32x4_replace_lane(&mut self, lane: u8) {
+        debug_assert!(lane < 4, "i32x4 lane index {} out of range", lane);
+        self.inst_simd_lane(SimdOp::I32X4REPLACELANE, 2, true, lane);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_global_typed_i64() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        code_builder.get_global_typed(1, ValueType::I64);
+        assert_eq!(code_builder.top_type(), Some(ValueType::I64));
+        assert_eq!(code_builder.current_stack().len(), 1);
+
+        code_builder.set_global_typed(1, ValueType::I64);
+        assert_eq!(code_builder.top_type(), None);
+        assert!(code_builder.current_stack().is_empty());
+
+        assert_eq!(
+            &code_builder.code,
+            &[GETGLOBAL as u8, 1, SETGLOBAL as u8, 1]
+        );
+    }
+
+    #[test]
+    fn test_drop_typed() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        code_builder.i32_const(1);
+        code_builder.get_global_typed(0, ValueType::I64);
+        assert_eq!(code_builder.drop_typed(), ValueType::I64);
+        assert_eq!(code_builder.current_stack().len(), 1);
+
+        assert_eq!(code_builder.drop_typed(), ValueType::I32);
+        assert!(code_builder.current_stack().is_empty());
+        assert_eq!(
+            code_builder.call_trace(),
+            ["I32CONST 1", "GETGLOBAL 0", "DROP", "DROP"]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Tried to drop from an empty Wasm value stack")]
+    fn test_drop_typed_empty_stack() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+        code_builder.drop_typed();
+    }
+
+    #[test]
+    #[should_panic(expected = "Tried to drop a Wasm value of unknown type")]
+    fn test_drop_typed_unknown_type() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+        code_builder.get_local(LocalId(0));
+        code_builder.drop_typed();
+    }
+
+    #[test]
+    fn test_instruction_result_types() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        code_builder.i64_const(1);
+        assert_eq!(code_builder.top_type(), Some(ValueType::I64));
+        code_builder.i64_const(2);
+        code_builder.i64_add();
+        assert_eq!(code_builder.top_type(), Some(ValueType::I64));
+        code_builder.i64_const(3);
+        code_builder.i64_eq();
+        assert_eq!(code_builder.top_type(), Some(ValueType::I32));
+        code_builder.f64_convert_u_i32();
+        assert_eq!(code_builder.top_type(), Some(ValueType::F64));
+        code_builder.drop_();
+
+        code_builder.i32_const(0);
+        code_builder.f32_load(Align::Bytes4, 0);
+        assert_eq!(code_builder.top_type(), Some(ValueType::F32));
+        code_builder.drop_();
+
+        // Locals are not typed in our model of the stack
+        code_builder.get_local(LocalId(0));
+        assert_eq!(code_builder.top_type(), None);
+    }
+
+    #[test]
+    fn test_code_len() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+        assert!(code_builder.is_empty());
+        assert_eq!(code_builder.code_len(), 0);
+
+        code_builder.i32_const(1);
+        code_builder.drop_();
+        assert!(!code_builder.is_empty());
+        assert_eq!(code_builder.code_len(), 3);
+
+        // The function header is not part of the code
+        code_builder.build_fn_header_and_footer(0, &[], 0, None, &[]);
+        assert_eq!(code_builder.code_len(), 4);
+        assert!(code_builder.size() > code_builder.code_len());
+
+        code_builder.clear();
+        assert!(code_builder.is_empty());
+    }
+
+    #[test]
+    fn test_patch_leb() {
+        use roc_wasm_module::parse::Parse;
+
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        code_builder.nop();
+        let at = code_builder.emit_leb_placeholder();
+        code_builder.nop();
+        assert_eq!(at, 1);
+        assert_eq!(code_builder.code_len(), 7);
+        assert!(code_builder.code[at..at + 5].iter().all(|b| b & 0x80 != 0));
+
+        code_builder.patch_leb(at, 300);
+        assert_eq!(code_builder.code_len(), 7);
+        assert_eq!(
+            code_builder.code[at..at + 5],
+            [0xac, 0x82, 0x80, 0x80, 0x00]
+        );
+
+        let mut cursor = at;
+        let decoded = u32::parse((), &code_builder.code, &mut cursor).unwrap();
+        assert_eq!(decoded, 300);
+        assert_eq!(cursor, at + 5);
+        assert_eq!(code_builder.code[cursor], NOP as u8);
+    }
+
+    #[test]
+    fn test_pad_to_alignment() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        code_builder.i32_const(1);
+        code_builder.pad_to_alignment(4);
+        assert_eq!(
+            &code_builder.code,
+            &[I32CONST as u8, 1, NOP as u8, NOP as u8]
+        );
+        assert_eq!(code_builder.current_stack().len(), 1);
+
+        // Already aligned, so nothing to do
+        code_builder.pad_to_alignment(4);
+        assert_eq!(code_builder.code.len(), 4);
+    }
+
+    #[test]
+    fn test_isize_const() {
+        let arena = &Bump::new();
+
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+        code_builder.isize_const(0, PtrWidth::Bytes4);
+        assert_eq!(&code_builder.code, &[I32CONST as u8, 0]);
+
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+        code_builder.isize_const(0, PtrWidth::Bytes8);
+        assert_eq!(&code_builder.code, &[I64CONST as u8, 0]);
+        assert_eq!(code_builder.current_stack().len(), 1);
+    }
+
+    #[test]
+    fn test_float_consts_are_little_endian() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        // Pi, where every byte is different
+        code_builder.f32_const(f32::from_bits(0x4049_0fdb));
+        code_builder.f64_const(f64::from_bits(0x4009_21fb_5444_2d18));
+
+        #[rustfmt::skip]
+        let expected = [
+            F32CONST as u8, 0xdb, 0x0f, 0x49, 0x40,
+            F64CONST as u8, 0x18, 0x2d, 0x44, 0x54, 0xfb, 0x21, 0x09, 0x40,
+        ];
+        assert_eq!(code_builder.code.as_slice(), expected);
+    }
+
+    #[test]
+    fn test_const_from_bits() {
+        fn check(ty: ValueType, bits: u64, specific: impl Fn(&mut CodeBuilder)) {
+            let arena = &Bump::new();
+
+            let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+            code_builder.const_from_bits(ty, bits);
+
+            let mut expected = CodeBuilder::new(arena, WasmFeatures::all());
+            specific(&mut expected);
+
+            assert_eq!(code_builder.code, expected.code);
+            assert_eq!(code_builder.current_stack().len(), 1);
+        }
+
+        check(ValueType::I32, 0xffff_fff6, |b| b.i32_const(-10));
+        check(ValueType::I64, u64::MAX, |b| b.i64_const(-1));
+        check(ValueType::F32, 1.5f32.to_bits() as u64, |b| {
+            b.f32_const(1.5)
+        });
+        check(ValueType::F64, (-0.25f64).to_bits(), |b| b.f64_const(-0.25));
+    }
+
+    #[test]
+    fn test_remove_identities() {
+        fn check(
+            push_const: impl Fn(&mut CodeBuilder, i64),
+            binop: impl Fn(&mut CodeBuilder),
+            identity: i64,
+        ) {
+            let arena = &Bump::new();
+
+            // Before: x op identity
+            let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+            code_builder.get_local(LocalId(0));
+            push_const(&mut code_builder, identity);
+            binop(&mut code_builder);
+
+            // After: x
+            assert_eq!(code_builder.call_trace(), ["GETLOCAL 0"]);
+            assert_eq!(code_builder.code, [GETLOCAL as u8, 0]);
+            assert_eq!(code_builder.current_stack().len(), 1);
+
+            // Any other constant is kept
+            code_builder.clear();
+            code_builder.get_local(LocalId(0));
+            push_const(&mut code_builder, identity + 2);
+            binop(&mut code_builder);
+            assert_eq!(code_builder.call_trace().len(), 3);
+            assert_eq!(code_builder.current_stack().len(), 1);
+        }
+
+        let i32_const = |b: &mut CodeBuilder, x: i64| b.i32_const(x as i32);
+        let i64_const = |b: &mut CodeBuilder, x: i64| b.i64_const(x);
+
+        check(i32_const, |b| b.i32_add(), 0);
+        check(i32_const, |b| b.i32_sub(), 0);
+        check(i32_const, |b| b.i32_mul(), 1);
+        check(i32_const, |b| b.i32_and(), -1);
+        check(i64_const, |b| b.i64_add(), 0);
+        check(i64_const, |b| b.i64_sub(), 0);
+        check(i64_const, |b| b.i64_mul(), 1);
+        check(i64_const, |b| b.i64_and(), -1);
+    }
+
+    #[test]
+    fn test_keep_identity_const_if_referenced() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        // The constant is named, so it might be loaded again
+        code_builder.get_local(LocalId(0));
+        code_builder.i32_const(0);
+        code_builder.set_top_symbol(Symbol::ARG_1);
+        code_builder.i32_add();
+        assert_eq!(
+            code_builder.call_trace(),
+            ["GETLOCAL 0", "I32CONST 0", "I32ADD"]
+        );
+
+        // Something has been emitted in between
+        code_builder.clear();
+        code_builder.get_local(LocalId(0));
+        code_builder.i32_const(0);
+        code_builder.nop();
+        code_builder.i32_add();
+        assert_eq!(
+            code_builder.call_trace(),
+            ["GETLOCAL 0", "I32CONST 0", "NOP", "I32ADD"]
+        );
+
+        // An insertion goes after the start of the constant
+        code_builder.clear();
+        code_builder.get_local(LocalId(0));
+        code_builder.i32_const(0);
+        let end_of_const = code_builder.code.len();
+        code_builder.add_insertion(end_of_const, TEELOCAL, 1);
+        code_builder.i32_add();
+        assert_eq!(
+            code_builder.call_trace(),
+            ["GETLOCAL 0", "I32CONST 0", "I32ADD"]
+        );
+    }
+
+    #[test]
+    fn test_restore_stack() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        code_builder.get_local(LocalId(0));
+        code_builder.set_top_symbol(Symbol::ARG_1);
+        let code_before = code_builder.code.clone();
+        let snapshot = code_builder.snapshot_stack();
+
+        // Speculatively emit a block that uses the symbol and pushes more values
+        code_builder.block();
+        code_builder.i32_const(1);
+        code_builder.end();
+        code_builder.i32_const(2);
+        code_builder.i32_add();
+        code_builder.call_import(3, 1, true);
+        assert_ne!(code_builder.code, code_before);
+
+        code_builder.restore_stack(snapshot);
+        assert_eq!(code_builder.code, code_before);
+        assert_eq!(code_builder.call_trace(), ["GETLOCAL 0"]);
+        assert!(code_builder.verify_stack_match(&[Symbol::ARG_1]));
+        assert_eq!(code_builder.vm_block_stack.len(), 1);
+        assert!(code_builder.import_relocations.is_empty());
+
+        // We can carry on as if the speculative code was never emitted
+        code_builder.i32_const(0);
+        code_builder.i32_add();
+        assert_eq!(code_builder.call_trace(), ["GETLOCAL 0"]);
+    }
+
+    #[test]
+    fn test_br_table() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        code_builder.block();
+        code_builder.block();
+        code_builder.i32_const(1);
+        code_builder.br_table(&[0, 1], 2);
+        assert_eq!(
+            code_builder.call_trace(),
+            ["BLOCK", "BLOCK", "I32CONST 1", "BRTABLE [0, 1] 2"]
+        );
+        assert_eq!(
+            &code_builder.code[code_builder.code.len() - 5..],
+            &[BRTABLE as u8, 2, 0, 1, 2]
+        );
+        assert!(code_builder.current_stack().is_empty());
+    }
+
+    #[test]
+    fn test_switch_on_i32() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        code_builder.switch_on_i32(LocalId(0), 4);
+        assert_eq!(
+            code_builder.call_trace(),
+            [
+                "BLOCK",
+                "BLOCK",
+                "BLOCK",
+                "BLOCK",
+                "GETLOCAL 0",
+                "BRTABLE [0, 1, 2] 3"
+            ]
+        );
+        #[rustfmt::skip]
+        let expected = [
+            BLOCK as u8, ValueType::VOID,
+            BLOCK as u8, ValueType::VOID,
+            BLOCK as u8, ValueType::VOID,
+            BLOCK as u8, ValueType::VOID,
+            GETLOCAL as u8, 0,
+            BRTABLE as u8, 3, 0, 1, 2, 3,
+        ];
+        assert_eq!(&code_builder.code[..], &expected);
+        assert_eq!(code_builder.vm_block_stack.len(), 5);
+        assert!(code_builder.current_stack().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "br_table target 3 at index 1 is out of range")]
+    fn test_br_table_target_out_of_range() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        code_builder.block();
+        code_builder.i32_const(0);
+        code_builder.br_table(&[0, 3], 1);
+    }
+
+    #[test]
+    fn test_fn_footer_with_result() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        code_builder.i32_const(42);
+        code_builder.build_fn_header_and_footer(0, &[], 0, None, &[ValueType::I32]);
+        assert_eq!(code_builder.code.last(), Some(&(END as u8)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Function should return [I32]")]
+    fn test_fn_footer_missing_result() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        code_builder.nop();
+        code_builder.build_fn_header_and_footer(0, &[], 0, None, &[ValueType::I32]);
+    }
+
+    #[test]
+    fn test_fn_without_stack_frame() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+        let frame_pointer = LocalId(0);
+
+        // A leaf function with nothing in stack memory doesn't touch the stack pointer
+        code_builder.i32_const(1);
+        code_builder.build_fn_header_and_footer(
+            0,
+            &[ValueType::I32],
+            0,
+            Some(frame_pointer),
+            &[ValueType::I32],
+        );
+        assert_eq!(code_builder.preamble, [1, 1, ValueType::I32 as u8]);
+        assert_eq!(code_builder.call_trace(), ["I32CONST 1"]);
+
+        // With a frame, the preamble grabs stack memory and the footer gives it back
+        code_builder.clear();
+        code_builder.get_local(frame_pointer);
+        code_builder.build_fn_header_and_footer(
+            0,
+            &[ValueType::I32],
+            16,
+            Some(frame_pointer),
+            &[ValueType::I32],
+        );
+        assert!(code_builder.preamble.contains(&(GETGLOBAL as u8)));
+        assert!(code_builder
+            .call_trace()
+            .contains(&format!("{:?} {}", SETGLOBAL, STACK_POINTER_GLOBAL_ID)));
+    }
+
+    #[test]
+    fn test_stack_check() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+        let frame_pointer = LocalId(0);
+        let stack_limit = 3;
+
+        #[rustfmt::skip]
+        let check = [
+            GETLOCAL as u8, 0,
+            GETGLOBAL as u8, stack_limit as u8,
+            I32LTU as u8,
+            IF as u8, ValueType::VOID,
+            UNREACHABLE as u8,
+            END as u8,
+        ];
+        let has_check = |preamble: &[u8]| preamble.windows(check.len()).any(|w| w == check);
+
+        let build = |code_builder: &mut CodeBuilder, frame_size| {
+            code_builder.clear();
+            code_builder.build_fn_header_and_footer(
+                0,
+                &[ValueType::I32],
+                frame_size,
+                Some(frame_pointer),
+                &[],
+            );
+        };
+
+        // Off by default
+        build(&mut code_builder, 16);
+        assert!(!has_check(&code_builder.preamble));
+
+        // Only functions with a stack frame are checked
+        code_builder.set_stack_check(Some(stack_limit));
+        build(&mut code_builder, 0);
+        assert!(!has_check(&code_builder.preamble));
+        build(&mut code_builder, 16);
+        assert!(has_check(&code_builder.preamble));
+
+        // The check comes after the stack pointer is moved
+        let set_sp = [SETGLOBAL as u8, STACK_POINTER_GLOBAL_ID as u8];
+        assert!(code_builder
+            .preamble
+            .ends_with(&[&set_sp[..], &check[..]].concat()));
+    }
+
+    #[test]
+    fn test_unreachable_after_infinite_loop() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        // The loop never exits, so there's no i32 to return
+        code_builder.loop_();
+        code_builder.br(0);
+        code_builder.end();
+        code_builder.unreachable_();
+        code_builder.build_fn_header_and_footer(0, &[], 0, None, &[ValueType::I32]);
+        assert_eq!(code_builder.code.last(), Some(&(END as u8)));
+    }
+
+    #[test]
+    fn test_unreachable_stack_is_polymorphic() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        // Operands can be popped from the polymorphic stack
+        code_builder.block();
+        code_builder.unreachable_();
+        code_builder.i32_const(1);
+        code_builder.i32_add();
+        code_builder.end();
+
+        // But only until the end of the block
+        code_builder.i32_const(2);
+        code_builder.build_fn_header_and_footer(0, &[], 0, None, &[ValueType::I32]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Wasm value stack underflow")]
+    fn test_else_after_unreachable_is_reachable() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        code_builder.i32_const(1);
+        code_builder.if_();
+        code_builder.unreachable_();
+        code_builder.else_();
+        code_builder.i32_eqz();
+    }
+
+    #[test]
+    fn test_fn_footer_params_and_locals() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        code_builder.get_local(LocalId(0));
+        code_builder.set_local(LocalId(1));
+        code_builder.get_local(LocalId(1));
+        code_builder.build_fn_header_and_footer(1, &[ValueType::I32], 0, None, &[ValueType::I32]);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Local 2 is out of range. The function only declares 1 params and 1 locals"
+    )]
+    fn test_fn_footer_local_out_of_range() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        code_builder.get_local(LocalId(0));
+        code_builder.tee_local(LocalId(2));
+        code_builder.build_fn_header_and_footer(1, &[ValueType::I32], 0, None, &[ValueType::I32]);
+    }
+
+    #[test]
+    fn test_if_nonnull() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        code_builder.if_nonnull(LocalId(3));
+        code_builder.nop();
+        code_builder.end();
+        assert_eq!(
+            code_builder.call_trace(),
+            ["GETLOCAL 3", "IF", "NOP", "END"]
+        );
+        assert!(code_builder.current_stack().is_empty());
+    }
+
+    #[test]
+    fn test_scoped_block() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        let result = code_builder.scoped_block(|b| {
+            b.i32_const(1);
+            b.br_if(0);
+            42
+        });
+        assert_eq!(result, 42);
+        assert_eq!(
+            code_builder.call_trace(),
+            ["BLOCK", "I32CONST 1", "BRIF 0", "END"]
+        );
+        assert_eq!(code_builder.vm_block_stack.len(), 1);
+
+        #[rustfmt::skip]
+        let expected = [
+            BLOCK as u8, ValueType::VOID,
+            I32CONST as u8, 1,
+            BRIF as u8, 0,
+            END as u8,
+        ];
+        assert_eq!(code_builder.code.as_slice(), expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "Wasm scoped_block must leave the value stack empty")]
+    fn test_scoped_block_unbalanced() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+        code_builder.scoped_block(|b| b.i32_const(1));
+    }
+
+    #[test]
+    fn test_trap_if() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        code_builder.i32_const(7);
+        code_builder.get_local(LocalId(3));
+        code_builder.trap_if();
+        assert_eq!(
+            code_builder.call_trace(),
+            ["I32CONST 7", "GETLOCAL 3", "IF", "UNREACHABLE", "END"]
+        );
+
+        // Only the condition is consumed, and code after the trap is still reachable
+        assert_eq!(code_builder.current_stack().len(), 1);
+        assert!(!code_builder.vm_block_stack.last().unwrap().polymorphic);
+    }
+
+    #[test]
+    fn test_call_with_result() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        code_builder.i32_const(1);
+        code_builder.i32_const(2);
+        let state = code_builder.call_with_result(5, 2, Symbol::ARG_1);
+        assert_eq!(
+            code_builder.call_trace(),
+            ["I32CONST 1", "I32CONST 2", "CALL 5"]
+        );
+        assert_eq!(code_builder.current_stack().len(), 1);
+        assert!(code_builder.verify_stack_match(&[Symbol::ARG_1]));
+        assert!(matches!(
+            state,
+            VmSymbolState::Pushed { pushed_at } if pushed_at == code_builder.code.len()
+        ));
+    }
+
+    #[test]
+    fn test_call_drop() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        code_builder.i32_const(1);
+        code_builder.call_drop(5, 1, true);
+        assert_eq!(code_builder.call_trace(), ["I32CONST 1", "CALL 5", "DROP"]);
+        assert!(code_builder.current_stack().is_empty());
+
+        code_builder.clear();
+        code_builder.i32_const(1);
+        code_builder.call_drop(5, 1, false);
+        assert_eq!(code_builder.call_trace(), ["I32CONST 1", "CALL 5"]);
+        assert!(code_builder.current_stack().is_empty());
+    }
+
+    #[test]
+    fn test_init_from_data_segment() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        code_builder.init_from_data_segment(2, LocalId(3), 1000);
+        assert_eq!(
+            code_builder.call_trace(),
+            [
+                "GETLOCAL 3",
+                "I32CONST 0",
+                "I32CONST 1000",
+                "MEMORYINIT 2",
+                "DATADROP 2"
+            ]
+        );
+        assert!(code_builder.current_stack().is_empty());
+
+        #[rustfmt::skip]
+        let expected = [
+            GETLOCAL as u8, 3,
+            I32CONST as u8, 0,
+            I32CONST as u8, 0xe8, 0x07,
+            MISC as u8, MiscOp::MEMORYINIT as u8, 2, 0,
+            MISC as u8, MiscOp::DATADROP as u8, 2,
+        ];
+        assert_eq!(code_builder.code.as_slice(), expected);
+    }
+
+    #[test]
+    fn test_i32x4_lane_ops() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        code_builder.i32_const(7);
+        code_builder.i32x4_splat();
+        code_builder.i32_const(9);
+        code_builder.i32x4_replace_lane(2);
+        code_builder.i32x4_extract_lane(3);
+        assert_eq!(
+            code_builder.call_trace(),
+            [
+                "I32CONST 7",
+                "I32X4SPLAT",
+                "I32CONST 9",
+                "I32X4REPLACELANE 2",
+                "I32X4EXTRACTLANE 3"
+            ]
+        );
+        assert_eq!(code_builder.current_stack().len(), 1);
+
+        #[rustfmt::skip]
+        let expected = [
+            I32CONST as u8, 7,
+            SIMD as u8, SimdOp::I32X4SPLAT as u8,
+            I32CONST as u8, 9,
+            SIMD as u8, SimdOp::I32X4REPLACELANE as u8, 2,
+            SIMD as u8, SimdOp::I32X4EXTRACTLANE as u8, 3,
+        ];
+        assert_eq!(code_builder.code.as_slice(), expected);
+    }
+
+    #[test]
+    fn test_memset_zero_bulk() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        code_builder.memset_zero(LocalId(1), 20, LocalId(2));
+        assert_eq!(
+            code_builder.call_trace(),
+            ["GETLOCAL 1", "I32CONST 0", "I32CONST 20", "MEMORYFILL 0"]
+        );
+        assert!(code_builder.current_stack().is_empty());
+
+        #[rustfmt::skip]
+        let expected = [
+            GETLOCAL as u8, 1,
+            I32CONST as u8, 0,
+            I32CONST as u8, 20,
+            MISC as u8, MiscOp::MEMORYFILL as u8, 0,
+        ];
+        assert_eq!(code_builder.code.as_slice(), expected);
+    }
+
+    #[test]
+    fn test_memset_zero_loop() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::empty());
+
+        code_builder.memset_zero(LocalId(1), 21, LocalId(2));
+        assert_eq!(
+            code_builder.call_trace(),
+            [
+                "I32CONST 16",
+                "SETLOCAL 2",
+                "LOOP",
+                "GETLOCAL 2",
+                "I32CONST 8",
+                "I32SUB",
+                "TEELOCAL 2",
+                "GETLOCAL 1",
+                "I32ADD",
+                "I64CONST 0",
+                "I64STORE Bytes1 0",
+                "GETLOCAL 2",
+                "BRIF 0",
+                "END",
+                "GETLOCAL 1",
+                "I32CONST 0",
+                "I32STORE Bytes1 16",
+                "GETLOCAL 1",
+                "I32CONST 0",
+                "I32STORE8 Bytes1 20",
+            ]
+        );
+        assert!(code_builder.current_stack().is_empty());
+
+        code_builder.clear();
+        code_builder.memset_zero(LocalId(1), 0, LocalId(2));
+        assert!(code_builder.call_trace().is_empty());
+    }
+
+    #[test]
+    fn test_return_call() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        code_builder.i32_const(1);
+        code_builder.i32_const(2);
+        code_builder.return_call(7, 2);
+        assert_eq!(
+            code_builder.call_trace(),
+            ["I32CONST 1", "I32CONST 2", "RETURNCALL 7"]
+        );
+        assert!(code_builder.current_stack().is_empty());
+        assert!(code_builder.vm_block_stack.last().unwrap().polymorphic);
+
+        #[rustfmt::skip]
+        let expected = [
+            I32CONST as u8, 1,
+            I32CONST as u8, 2,
+            RETURNCALL as u8, 0x87, 0x80, 0x80, 0x80, 0x00,
+        ];
+        assert_eq!(code_builder.code.as_slice(), expected);
+    }
+
+    #[test]
+    fn test_return_call_import() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        code_builder.i32_const(1);
+        code_builder.return_call_import(3, 1);
+        assert_eq!(code_builder.call_trace(), ["I32CONST 1", "RETURNCALL 3"]);
+
+        // The function index gets a linker relocation, like a regular call to an import
+        assert_eq!(code_builder.import_relocations.as_slice(), [(3, 3)]);
+        assert!(code_builder.vm_block_stack.last().unwrap().polymorphic);
+
+        code_builder.return_call(4, 0);
+        assert_eq!(code_builder.import_relocations.len(), 1);
+    }
+
+    #[test]
+    fn test_return_call_indirect() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        code_builder.i32_const(1);
+        code_builder.i32_const(3);
+        code_builder.return_call_indirect(4, 0, 2);
+        assert_eq!(
+            code_builder.call_trace(),
+            ["I32CONST 1", "I32CONST 3", "RETURNCALLINDIRECT 4 0"]
+        );
+        assert!(code_builder.current_stack().is_empty());
+        assert!(code_builder.vm_block_stack.last().unwrap().polymorphic);
+
+        #[rustfmt::skip]
+        let expected = [
+            I32CONST as u8, 1,
+            I32CONST as u8, 3,
+            RETURNCALLINDIRECT as u8, 4, 0,
+        ];
+        assert_eq!(code_builder.code.as_slice(), expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "Wasm feature SIMD is not enabled for this module")]
+    fn test_simd_requires_feature() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::ROC_TARGETS);
+
+        code_builder.i32_const(7);
+        code_builder.i32x4_splat();
+    }
+
+    #[test]
+    fn test_memory_size_and_grow() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        code_builder.memory_size();
+        code_builder.memory_grow();
+        code_builder.memory_size_indexed(200);
+        code_builder.memory_grow_indexed(1);
+        assert_eq!(
+            code_builder.call_trace(),
+            [
+                "CURRENTMEMORY 0",
+                "GROWMEMORY 0",
+                "CURRENTMEMORY 200",
+                "GROWMEMORY 1"
+            ]
+        );
+        assert_eq!(code_builder.current_stack().len(), 2);
+
+        #[rustfmt::skip]
+        let expected = [
+            CURRENTMEMORY as u8, 0,
+            GROWMEMORY as u8, 0,
+            CURRENTMEMORY as u8, 0xc8, 0x01,
+            GROWMEMORY as u8, 1,
+        ];
+        assert_eq!(code_builder.code.as_slice(), expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "Wasm feature MULTI_MEMORY is not enabled for this module")]
+    fn test_nonzero_memory_index_requires_feature() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::ROC_TARGETS);
+
+        code_builder.memory_size_indexed(1);
+    }
+
+    #[test]
+    fn test_reference_instructions() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        code_builder.ref_null(ValueType::FuncRef);
+        code_builder.ref_is_null();
+        code_builder.drop_();
+        code_builder.ref_func(300);
+        assert_eq!(
+            code_builder.call_trace(),
+            ["REFNULL FuncRef", "REFISNULL", "DROP", "REFFUNC 300"]
+        );
+        assert_eq!(code_builder.current_stack().len(), 1);
+
+        #[rustfmt::skip]
+        let expected = [
+            REFNULL as u8, 0x70,
+            REFISNULL as u8,
+            DROP as u8,
+            REFFUNC as u8, 0xac, 0x02,
+        ];
+        assert_eq!(code_builder.code.as_slice(), expected);
+    }
+
+    #[test]
+    fn test_local_declarations_keep_order() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        let local_types = [
+            ValueType::I32,
+            ValueType::I64,
+            ValueType::I64,
+            ValueType::I32,
+        ];
+        code_builder.build_fn_header_and_footer(1, &local_types, 0, None, &[]);
+        assert_eq!(code_builder.preamble, [3, 1, 0x7f, 2, 0x7e, 1, 0x7f]);
+
+        // Expanding the batches gives back each local's type at its original index
+        let mut decoded = std::vec::Vec::new();
+        for batch in code_builder.preamble[1..].chunks(2) {
+            for _ in 0..batch[0] {
+                decoded.push(ValueType::from(batch[1]));
+            }
+        }
+        assert_eq!(decoded, local_types);
+    }
+
+    #[test]
+    fn test_reference_type_locals() {
+        let arena = &Bump::new();
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::all());
+
+        let local_types = [ValueType::FuncRef, ValueType::FuncRef, ValueType::ExternRef];
+        code_builder.build_fn_header_and_footer(0, &local_types, 0, None, &[]);
+        assert_eq!(code_builder.preamble, [2, 2, 0x70, 1, 0x6f]);
+    }
 }
@@ -1,4 +1,5 @@
 use roc_builtins::bitcode::{FloatWidth, IntWidth};
+use roc_error_macros::internal_error;
 use roc_mono::layout::{InLayout, Layout, LayoutInterner, STLayoutInterner, UnionLayout};
 
 use crate::{PTR_SIZE, PTR_TYPE};
@@ -113,6 +114,9 @@ impl WasmLayout {
             Self::Primitive(I64, _) => &[I64],
             Self::Primitive(F32, _) => &[F32],
             Self::Primitive(F64, _) => &[F64],
+            Self::Primitive(FuncRef | ExternRef, _) => {
+                internal_error!("Roc values are never Wasm reference types")
+            }
 
             // 1 Roc argument => 0-2 Wasm arguments (depending on size and calling convention)
             Self::StackMemory { size, format, .. } => conv.stack_memory_arg_types(*size, *format),
@@ -74,6 +74,9 @@ impl From<ValueType> for CodeGenNumType {
             ValueType::I64 => CodeGenNumType::I64,
             ValueType::F32 => CodeGenNumType::F32,
             ValueType::F64 => CodeGenNumType::F64,
+            ValueType::FuncRef | ValueType::ExternRef => {
+                internal_error!("{:?} is not a number type", value_type)
+            }
         }
     }
 }
@@ -1958,6 +1961,9 @@ impl<'a> LowLevelCall<'a> {
             }
             RefCountIsUnique => self.load_args_and_call_zig(backend, bitcode::UTILS_IS_UNIQUE),
 
+            // Wasm has no prefetch instruction, and the hint has no effect on the result
+            Prefetch => {}
+
             PtrCast => {
                 let code_builder = &mut backend.code_builder;
                 backend.storage.load_symbols(code_builder, self.arguments);
@@ -1980,6 +1986,9 @@ impl<'a> LowLevelCall<'a> {
                     ValueType::I64 => backend.code_builder.i64_const(0),
                     ValueType::F32 => backend.code_builder.f32_const(0.0),
                     ValueType::F64 => backend.code_builder.f64_const(0.0),
+                    ValueType::FuncRef | ValueType::ExternRef => {
+                        internal_error!("Roc values are never Wasm reference types")
+                    }
                 },
                 StoredValue::StackMemory { .. } => { /* do nothing */ }
             },
@@ -2072,12 +2081,18 @@ impl<'a> LowLevelCall<'a> {
                         ValueType::I64 => backend.code_builder.i64_eq(),
                         ValueType::F32 => backend.code_builder.f32_eq(),
                         ValueType::F64 => backend.code_builder.f64_eq(),
+                        ValueType::FuncRef | ValueType::ExternRef => {
+                            internal_error!("Roc values are never Wasm reference types")
+                        }
                     },
                     LowLevel::NotEq => match value_type {
                         ValueType::I32 => backend.code_builder.i32_ne(),
                         ValueType::I64 => backend.code_builder.i64_ne(),
                         ValueType::F32 => backend.code_builder.f32_ne(),
                         ValueType::F64 => backend.code_builder.f64_ne(),
+                        ValueType::FuncRef | ValueType::ExternRef => {
+                            internal_error!("Roc values are never Wasm reference types")
+                        }
                     },
                     _ => internal_error!("{:?} ended up in Equality code", self.lowlevel),
                 }
@@ -2215,6 +2230,9 @@ fn num_is_nan(backend: &mut WasmBackend<'_, '_>, argument: Symbol) {
                     backend.code_builder.i64_ne(); // Mantissa is non-zero
                     backend.code_builder.i32_and();
                 }
+                ValueType::FuncRef | ValueType::ExternRef => {
+                    internal_error!("Roc values are never Wasm reference types")
+                }
             }
         }
         StackMemory { format, .. } => {
@@ -2258,6 +2276,9 @@ fn num_is_infinite(backend: &mut WasmBackend<'_, '_>, argument: Symbol) {
                     backend.code_builder.i64_const(0x7ff0_0000_0000_0000);
                     backend.code_builder.i64_eq();
                 }
+                ValueType::FuncRef | ValueType::ExternRef => {
+                    internal_error!("Roc values are never Wasm reference types")
+                }
             }
         }
         StackMemory { format, .. } => {
@@ -2301,6 +2322,9 @@ fn num_is_finite(backend: &mut WasmBackend<'_, '_>, argument: Symbol) {
                     backend.code_builder.i64_const(0x7ff0_0000_0000_0000);
                     backend.code_builder.i64_ne();
                 }
+                ValueType::FuncRef | ValueType::ExternRef => {
+                    internal_error!("Roc values are never Wasm reference types")
+                }
             }
         }
         StackMemory { format, .. } => {
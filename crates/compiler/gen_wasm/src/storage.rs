@@ -69,6 +69,9 @@ impl StoredValue {
                     I64 => &[I64],
                     F32 => &[F32],
                     F64 => &[F64],
+                    FuncRef | ExternRef => {
+                        internal_error!("Roc values are never Wasm reference types")
+                    }
                 }
             }
             // Stack memory values: 1 Roc argument => 0-2 Wasm arguments
@@ -11,10 +11,10 @@ use roc_mono::layout::{Builtin, InLayout, Layout, LayoutInterner, UnionLayout};
 use roc_std::{RocBox, RocDec, RocList, RocOrder, RocResult, RocStr, I128, U128};
 use roc_wasm_module::{
     linking::SymInfo, linking::WasmObjectSymbol, Align, Export, ExportType, LocalId, Signature,
-    ValueType, WasmModule,
+    ValueType, ValueType::I32, WasmModule,
 };
 
-use crate::code_builder::CodeBuilder;
+use crate::code_builder::{CodeBuilder, WasmFeatures};
 use crate::wasm32_sized::Wasm32Sized;
 
 /// Type-driven wrapper generation
@@ -26,7 +26,7 @@ pub trait Wasm32Result {
         main_function_index: u32,
     ) {
         insert_wrapper_metadata(arena, module, wrapper_name);
-        let mut code_builder = CodeBuilder::new(arena);
+        let mut code_builder = CodeBuilder::new(arena, WasmFeatures::ROC_TARGETS);
         Self::build_wrapper_body(&mut code_builder, main_function_index);
         code_builder.insert_into_module(module);
     }
@@ -49,7 +49,7 @@ pub fn insert_wrapper_for_layout<'a>(
             <() as Wasm32Result>::insert_wrapper(arena, module, wrapper_name, main_fn_index);
         } else {
             insert_wrapper_metadata(arena, module, wrapper_name);
-            let mut code_builder = CodeBuilder::new(arena);
+            let mut code_builder = CodeBuilder::new(arena, WasmFeatures::ROC_TARGETS);
             build_wrapper_body_stack_memory(&mut code_builder, main_fn_index, size as usize);
             code_builder.insert_into_module(module);
         }
@@ -126,7 +126,13 @@ macro_rules! build_wrapper_body_primitive {
             code_builder.$store_instruction($align, 0);
             code_builder.get_local(frame_pointer_id);
 
-            code_builder.build_fn_header_and_footer(local_types, frame_size, frame_pointer);
+            code_builder.build_fn_header_and_footer(
+                0,
+                local_types,
+                frame_size,
+                frame_pointer,
+                &[I32],
+            );
         }
     };
 }
@@ -149,9 +155,9 @@ fn build_wrapper_body_stack_memory(
     let frame_pointer = Some(local_id);
 
     code_builder.get_local(local_id);
-    code_builder.call(main_function_index, 0, true);
+    code_builder.call(main_function_index, 1, false);
     code_builder.get_local(local_id);
-    code_builder.build_fn_header_and_footer(local_types, size as i32, frame_pointer);
+    code_builder.build_fn_header_and_footer(0, local_types, size as i32, frame_pointer, &[I32]);
 }
 
 macro_rules! wasm_result_stack_memory {
@@ -238,7 +244,7 @@ impl Wasm32Result for () {
     fn build_wrapper_body(code_builder: &mut CodeBuilder, main_function_index: u32) {
         code_builder.call(main_function_index, 0, false);
         code_builder.get_global(0);
-        code_builder.build_fn_header_and_footer(&[], 0, None);
+        code_builder.build_fn_header_and_footer(0, &[], 0, None, &[I32]);
     }
 }
 
@@ -246,7 +252,7 @@ impl Wasm32Result for std::convert::Infallible {
     fn build_wrapper_body(code_builder: &mut CodeBuilder, main_function_index: u32) {
         code_builder.call(main_function_index, 0, false);
         code_builder.get_global(0);
-        code_builder.build_fn_header_and_footer(&[], 0, None);
+        code_builder.build_fn_header_and_footer(0, &[], 0, None, &[I32]);
     }
 }
 
@@ -123,6 +123,7 @@ pub enum LowLevel {
     RefCountIncDataPtr,
     RefCountDecDataPtr,
     RefCountIsUnique,
+    Prefetch,
     BoxExpr,
     UnboxExpr,
     Unreachable,
@@ -232,6 +233,7 @@ macro_rules! map_symbol_to_lowlevel {
                 LowLevel::RefCountIncDataPtr => unimplemented!(),
                 LowLevel::RefCountDecDataPtr=> unimplemented!(),
                 LowLevel::RefCountIsUnique => unimplemented!(),
+                LowLevel::Prefetch => unimplemented!(),
 
                 // these are not implemented, not sure why
                 LowLevel::StrFromInt => unimplemented!(),
@@ -1034,7 +1034,7 @@ pub fn lowlevel_borrow_signature(arena: &Bump, op: LowLevel) -> &[Ownership] {
         }
 
         PtrCast | PtrWrite | RefCountIncRcPtr | RefCountDecRcPtr | RefCountIncDataPtr
-        | RefCountDecDataPtr | RefCountIsUnique => {
+        | RefCountDecDataPtr | RefCountIsUnique | Prefetch => {
             unreachable!("Only inserted *after* borrow checking: {:?}", op);
         }
     }
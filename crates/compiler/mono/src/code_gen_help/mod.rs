@@ -1,9 +1,11 @@
 use bumpalo::collections::vec::Vec;
 use bumpalo::collections::CollectIn;
 use bumpalo::Bump;
+use roc_collections::all::WyHash;
 use roc_module::low_level::LowLevel;
 use roc_module::symbol::{IdentIds, ModuleId, Symbol};
 use roc_target::TargetInfo;
+use std::hash::Hasher;
 
 use crate::ir::{
     Call, CallSpecId, CallType, Expr, HostExposedLayouts, JoinPointId, ModifyRc, PassedFunction,
@@ -16,6 +18,8 @@ use crate::layout::{
 mod equality;
 mod refcount;
 
+pub use refcount::emit_is_unique;
+
 const LAYOUT_BOOL: InLayout = Layout::BOOL;
 const LAYOUT_UNIT: InLayout = Layout::UNIT;
 
@@ -27,6 +31,12 @@ const ARG_3: Symbol = Symbol::ARG_3;
 /// Ref counts are encoded as negative numbers where isize::MIN represents 1
 pub const REFCOUNT_MAX: usize = 0;
 
+/// Host function called before every refcount modification, when tracing is enabled.
+/// Arguments are the data pointer, the kind of operation, and the interned layout index.
+pub const RC_TRACE_FN_NAME: &str = "roc_refcount_trace";
+pub const RC_TRACE_INC: u32 = 0;
+pub const RC_TRACE_DEC: u32 = 1;
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum HelperOp {
     Inc,
@@ -36,6 +46,7 @@ pub enum HelperOp {
     DecRef(JoinPointId),
     Reset,
     ResetRef,
+    AssertBorrowed,
     Eq,
 }
 
@@ -57,6 +68,7 @@ impl HelperOp {
 struct Specialization<'a> {
     op: HelperOp,
     layout: InLayout<'a>,
+    known_nonempty: bool,
     symbol: Symbol,
     proc: Option<Proc<'a>>,
 }
@@ -65,6 +77,9 @@ struct Specialization<'a> {
 pub struct Context<'a> {
     new_linker_data: Vec<'a, (Symbol, ProcLayout<'a>)>,
     recursive_union: Option<UnionLayout<'a>>,
+    /// The List being refcounted is statically known to be non-empty.
+    /// Only applies to the outermost List, not to any Lists nested inside it.
+    known_nonempty: bool,
     op: HelperOp,
 }
 
@@ -93,6 +108,12 @@ pub struct CodeGenHelp<'a> {
     layout_isize: InLayout<'a>,
     specializations: Vec<'a, Specialization<'a>>,
     debug_recursion_depth: usize,
+    rc_prefer_recursion: bool,
+    rc_work_list: bool,
+    rc_prefetch: bool,
+    rc_debug_assertions: bool,
+    rc_trace_stderr: bool,
+    min_alloc_alignment: u32,
 }
 
 impl<'a> CodeGenHelp<'a> {
@@ -106,9 +127,57 @@ impl<'a> CodeGenHelp<'a> {
             layout_isize,
             specializations: Vec::with_capacity_in(16, arena),
             debug_recursion_depth: 0,
+            rc_prefer_recursion: false,
+            rc_work_list: false,
+            rc_prefetch: false,
+            rc_debug_assertions: false,
+            rc_trace_stderr: false,
+            min_alloc_alignment: target_info.ptr_width() as u32,
         }
     }
 
+    /// Generate recursive unions' refcounting helpers as self-recursive procs, even where
+    /// a tail-recursive loop is possible. Useful for debugging, and for backends without
+    /// good join point support.
+    pub fn set_rc_prefer_recursion(&mut self, prefer_recursion: bool) {
+        self.rc_prefer_recursion = prefer_recursion;
+    }
+
+    /// Decrement recursive unions using a heap-allocated work list of pending nodes,
+    /// instead of recursive helper calls or a tail loop. This keeps native stack usage
+    /// constant for any shape of data, including trees with several recursive fields.
+    /// Takes priority over `set_rc_prefer_recursion`.
+    pub fn set_rc_work_list(&mut self, work_list: bool) {
+        self.rc_work_list = work_list;
+    }
+
+    /// In loops over list elements, prefetch the next element before refcounting the current one.
+    /// This can help with lists of pointers to scattered heap data. Backends that have no
+    /// prefetch instruction can ignore it, since it's only a hint.
+    pub fn set_rc_prefetch(&mut self, prefetch: bool) {
+        self.rc_prefetch = prefetch;
+    }
+
+    /// Add runtime checks to refcounting helpers, crashing on invalid pointers
+    /// instead of silently using a bad refcount address. Useful for debugging.
+    pub fn set_rc_debug_assertions(&mut self, debug_assertions: bool) {
+        self.rc_debug_assertions = debug_assertions;
+    }
+
+    /// Call the host's `roc_refcount_trace` before each refcount modification, so it can
+    /// print them to stderr. Ignored in release builds of the compiler.
+    pub fn set_rc_trace_stderr(&mut self, trace: bool) {
+        self.rc_trace_stderr = trace;
+    }
+
+    /// Set the platform's minimum alignment for heap allocations.
+    /// Deallocations always use at least this alignment, even for less-aligned layouts.
+    pub fn set_min_alloc_alignment(&mut self, alignment: u32) {
+        debug_assert!(alignment >= self.target_info.ptr_width() as u32);
+        debug_assert!(alignment.is_power_of_two());
+        self.min_alloc_alignment = alignment;
+    }
+
     pub fn take_procs(&mut self) -> Vec<'a, Proc<'a>> {
         let procs_iter = self
             .specializations
@@ -132,6 +201,45 @@ impl<'a> CodeGenHelp<'a> {
         layout: InLayout<'a>,
         modify: &ModifyRc,
         following: &'a Stmt<'a>,
+    ) -> (&'a Stmt<'a>, Vec<'a, (Symbol, ProcLayout<'a>)>) {
+        self.expand_refcount_stmt_help(ident_ids, layout_interner, layout, modify, following, false)
+    }
+
+    /// Like `expand_refcount_stmt`, for a List that is statically known to be non-empty,
+    /// such as the result of `List.append`. The helper skips the empty-list check.
+    pub fn expand_nonempty_list_refcount_stmt(
+        &mut self,
+        ident_ids: &mut IdentIds,
+        layout_interner: &mut STLayoutInterner<'a>,
+        list_layout: InLayout<'a>,
+        modify: &ModifyRc,
+        following: &'a Stmt<'a>,
+    ) -> (&'a Stmt<'a>, Vec<'a, (Symbol, ProcLayout<'a>)>) {
+        debug_assert!(
+            matches!(
+                layout_interner.get(list_layout),
+                Layout::Builtin(Builtin::List(_))
+            ),
+            "Only a List can be known to be non-empty"
+        );
+        self.expand_refcount_stmt_help(
+            ident_ids,
+            layout_interner,
+            list_layout,
+            modify,
+            following,
+            true,
+        )
+    }
+
+    fn expand_refcount_stmt_help(
+        &mut self,
+        ident_ids: &mut IdentIds,
+        layout_interner: &mut STLayoutInterner<'a>,
+        layout: InLayout<'a>,
+        modify: &ModifyRc,
+        following: &'a Stmt<'a>,
+        known_nonempty: bool,
     ) -> (&'a Stmt<'a>, Vec<'a, (Symbol, ProcLayout<'a>)>) {
         let op = match modify {
             ModifyRc::Inc(..) => HelperOp::Inc,
@@ -145,6 +253,7 @@ impl<'a> CodeGenHelp<'a> {
         let mut ctx = Context {
             new_linker_data: Vec::new_in(self.arena),
             recursive_union: None,
+            known_nonempty,
             op,
         };
 
@@ -160,6 +269,37 @@ impl<'a> CodeGenHelp<'a> {
         (rc_stmt, ctx.new_linker_data)
     }
 
+    /// Increment `element` once for every element of `list`, using a single helper call
+    /// with the list length as the amount. For a new list with the same value in every slot.
+    pub fn expand_inc_by_list_len(
+        &mut self,
+        ident_ids: &mut IdentIds,
+        layout_interner: &mut STLayoutInterner<'a>,
+        elem_layout: InLayout<'a>,
+        element: Symbol,
+        list: Symbol,
+        following: &'a Stmt<'a>,
+    ) -> (&'a Stmt<'a>, Vec<'a, (Symbol, ProcLayout<'a>)>) {
+        let mut ctx = Context {
+            new_linker_data: Vec::new_in(self.arena),
+            recursive_union: None,
+            known_nonempty: false,
+            op: HelperOp::Inc,
+        };
+
+        let rc_stmt = refcount::refcount_inc_by_len(
+            self,
+            ident_ids,
+            &mut ctx,
+            layout_interner,
+            elem_layout,
+            element,
+            list,
+            following,
+        );
+        (rc_stmt, ctx.new_linker_data)
+    }
+
     pub fn call_reset_refcount(
         &mut self,
         ident_ids: &mut IdentIds,
@@ -197,6 +337,7 @@ impl<'a> CodeGenHelp<'a> {
         let mut ctx = Context {
             new_linker_data: Vec::new_in(self.arena),
             recursive_union: None,
+            known_nonempty: false,
             op: if resetref {
                 HelperOp::ResetRef
             } else {
@@ -207,7 +348,7 @@ impl<'a> CodeGenHelp<'a> {
         let proc_name = self.find_or_create_proc(ident_ids, &mut ctx, layout_interner, layout);
 
         let arguments = self.arena.alloc([argument]);
-        let ret_layout = layout;
+        let ret_layout = reset_ret_layout(layout_interner, layout);
         let arg_layouts = self.arena.alloc([layout]);
         let expr = Expr::Call(Call {
             call_type: CallType::ByName {
@@ -222,6 +363,35 @@ impl<'a> CodeGenHelp<'a> {
         (expr, ctx.new_linker_data)
     }
 
+    /// Call a helper that crashes if a value which should be borrowed from a shared owner
+    /// is actually unique. For debugging the compiler's ownership decisions.
+    pub fn call_assert_borrowed(
+        &mut self,
+        ident_ids: &mut IdentIds,
+        layout_interner: &mut STLayoutInterner<'a>,
+        layout: InLayout<'a>,
+        argument: Symbol,
+    ) -> (Expr<'a>, Vec<'a, (Symbol, ProcLayout<'a>)>) {
+        let mut ctx = Context {
+            new_linker_data: Vec::new_in(self.arena),
+            recursive_union: None,
+            known_nonempty: false,
+            op: HelperOp::AssertBorrowed,
+        };
+
+        let expr = self
+            .call_specialized_op(
+                ident_ids,
+                &mut ctx,
+                layout_interner,
+                layout,
+                self.arena.alloc([argument]),
+            )
+            .unwrap();
+
+        (expr, ctx.new_linker_data)
+    }
+
     /// Generate a refcount increment procedure, *without* a Call expression.
     /// *This method should be rarely used* - only when the proc is to be called from Zig.
     /// Otherwise you want to generate the Proc and the Call together, using another method.
@@ -235,6 +405,7 @@ impl<'a> CodeGenHelp<'a> {
         let mut ctx = Context {
             new_linker_data: Vec::new_in(self.arena),
             recursive_union: None,
+            known_nonempty: false,
             op,
         };
 
@@ -243,6 +414,53 @@ impl<'a> CodeGenHelp<'a> {
         (proc_name, ctx.new_linker_data)
     }
 
+    /// Estimate how many statements the helper procs for a refcount operation would contain,
+    /// without generating them. Useful for deciding whether to inline.
+    /// Only Inc and Dec are modelled. Other operations are estimated like Dec.
+    pub fn estimate_refcount_helper_size(
+        &self,
+        layout_interner: &STLayoutInterner<'a>,
+        layout: InLayout<'a>,
+        op: HelperOp,
+    ) -> usize {
+        let mut visited = Vec::new_in(self.arena);
+        refcount::estimate_helper_size(self, layout_interner, layout, op, &mut visited)
+    }
+
+    /// A hash of everything that determines the refcount helper procs for a layout and operation.
+    /// It depends on the structure of the layout rather than its interned index, and every value is
+    /// written with a fixed width, so it is stable across interners, modules, runs and hosts.
+    /// It can be used as a key to share helpers between them.
+    pub fn refcount_helper_fingerprint(
+        &self,
+        layout_interner: &STLayoutInterner<'a>,
+        layout: InLayout<'a>,
+        op: HelperOp,
+    ) -> u64 {
+        let mut hasher = WyHash::with_seed(0);
+        hasher.write_u8(self.target_info.ptr_width() as u8);
+        hasher.write_u8(self.rc_prefer_recursion as u8);
+        hasher.write_u8(self.rc_work_list as u8);
+        hasher.write_u8(self.rc_prefetch as u8);
+        hasher.write_u8(self.rc_debug_assertions as u8);
+        hasher.write_u8(self.rc_trace_stderr as u8);
+        hasher.write_u32(self.min_alloc_alignment);
+        hasher.write_u8(match op {
+            HelperOp::Inc => 0,
+            HelperOp::Dec => 1,
+            HelperOp::IndirectInc => 2,
+            HelperOp::IndirectDec => 3,
+            // The join point is local to the proc that uses it, and doesn't affect the helper
+            HelperOp::DecRef(_) => 4,
+            HelperOp::Reset => 5,
+            HelperOp::ResetRef => 6,
+            HelperOp::AssertBorrowed => 7,
+            HelperOp::Eq => 8,
+        });
+        refcount::hash_layout_structure(layout_interner, layout, &mut hasher);
+        hasher.finish()
+    }
+
     /// Replace a generic `Lowlevel::Eq` call with a specialized helper proc.
     /// The helper procs themselves are to be generated later with `generate_procs`
     pub fn call_specialized_equals(
@@ -255,6 +473,7 @@ impl<'a> CodeGenHelp<'a> {
         let mut ctx = Context {
             new_linker_data: Vec::new_in(self.arena),
             recursive_union: None,
+            known_nonempty: false,
             op: HelperOp::Eq,
         };
 
@@ -303,8 +522,11 @@ impl<'a> CodeGenHelp<'a> {
                 let box_arg = layout_interner.insert(Layout::Boxed(arg));
 
                 match ctx.op {
-                    Dec | DecRef(_) => (LAYOUT_UNIT, self.arena.alloc([arg])),
-                    Reset | ResetRef => (layout, self.arena.alloc([layout])),
+                    Dec | DecRef(_) | AssertBorrowed => (LAYOUT_UNIT, self.arena.alloc([arg])),
+                    Reset | ResetRef => (
+                        reset_ret_layout(layout_interner, layout),
+                        self.arena.alloc([layout]),
+                    ),
                     Inc => (LAYOUT_UNIT, self.arena.alloc([arg, self.layout_isize])),
                     IndirectDec => (LAYOUT_UNIT, arena.alloc([box_arg])),
                     IndirectInc => (LAYOUT_UNIT, arena.alloc([box_arg, self.layout_isize])),
@@ -345,10 +567,9 @@ impl<'a> CodeGenHelp<'a> {
 
         let layout = self.replace_rec_ptr(ctx, layout_interner, orig_layout);
 
-        let found = self
-            .specializations
-            .iter()
-            .find(|spec| spec.op == ctx.op && spec.layout == layout);
+        let found = self.specializations.iter().find(|spec| {
+            spec.op == ctx.op && spec.layout == layout && spec.known_nonempty == ctx.known_nonempty
+        });
 
         if let Some(spec) = found {
             return spec.symbol;
@@ -364,6 +585,7 @@ impl<'a> CodeGenHelp<'a> {
         self.specializations.push(Specialization {
             op: ctx.op,
             layout,
+            known_nonempty: ctx.known_nonempty,
             symbol: proc_symbol,
             proc: None,
         });
@@ -393,7 +615,7 @@ impl<'a> CodeGenHelp<'a> {
                 ),
             ),
             Reset => (
-                layout,
+                reset_ret_layout(layout_interner, layout),
                 refcount::refcount_reset_proc_body(
                     self,
                     ident_ids,
@@ -404,7 +626,7 @@ impl<'a> CodeGenHelp<'a> {
                 ),
             ),
             ResetRef => (
-                layout,
+                reset_ret_layout(layout_interner, layout),
                 refcount::refcount_resetref_proc_body(
                     self,
                     ident_ids,
@@ -414,6 +636,16 @@ impl<'a> CodeGenHelp<'a> {
                     Symbol::ARG_1,
                 ),
             ),
+            AssertBorrowed => (
+                LAYOUT_UNIT,
+                refcount::refcount_assert_borrowed_proc_body(
+                    self,
+                    ident_ids,
+                    layout_interner,
+                    layout,
+                    Symbol::ARG_1,
+                ),
+            ),
             Eq => (
                 LAYOUT_BOOL,
                 equality::eq_generic(self, ident_ids, ctx, layout_interner, layout),
@@ -427,7 +659,9 @@ impl<'a> CodeGenHelp<'a> {
                     let inc_amount = (self.layout_isize, ARG_2);
                     self.arena.alloc([roc_value, inc_amount])
                 }
-                Dec | DecRef(_) | Reset | ResetRef => self.arena.alloc([roc_value]),
+                Dec | DecRef(_) | Reset | ResetRef | AssertBorrowed => {
+                    self.arena.alloc([roc_value])
+                }
                 IndirectInc => {
                     let box_layout = layout_interner.insert(Layout::Boxed(layout));
                     let inc_amount = (self.layout_isize, ARG_2);
@@ -476,7 +710,7 @@ impl<'a> CodeGenHelp<'a> {
                 result: LAYOUT_UNIT,
                 niche: Niche::NONE,
             },
-            HelperOp::Dec => ProcLayout {
+            HelperOp::Dec | HelperOp::AssertBorrowed => ProcLayout {
                 arguments: self.arena.alloc([layout]),
                 result: LAYOUT_UNIT,
                 niche: Niche::NONE,
@@ -499,13 +733,12 @@ impl<'a> CodeGenHelp<'a> {
                     niche: Niche::NONE,
                 }
             }
-            HelperOp::Reset => ProcLayout {
+            HelperOp::Reset | HelperOp::ResetRef => ProcLayout {
                 arguments: self.arena.alloc([layout]),
-                result: layout,
+                result: reset_ret_layout(layout_interner, layout),
                 niche: Niche::NONE,
             },
             HelperOp::DecRef(_) => unreachable!("No generated Proc for DecRef"),
-            HelperOp::ResetRef => unreachable!("No generated Proc for ResetRef"),
             HelperOp::Eq => ProcLayout {
                 arguments: self.arena.alloc([layout, layout]),
                 result: LAYOUT_BOOL,
@@ -589,6 +822,7 @@ impl<'a> CodeGenHelp<'a> {
 
     fn union_tail_recursion_fields(
         &self,
+        layout_interner: &STLayoutInterner<'a>,
         union_in_layout: InLayout<'a>,
         union: UnionLayout<'a>,
     ) -> Option<Vec<'a, Option<usize>>> {
@@ -596,36 +830,49 @@ impl<'a> CodeGenHelp<'a> {
         match union {
             NonRecursive(_) => None,
 
-            Recursive(tags) => self.union_tail_recursion_fields_help(union_in_layout, tags),
-
-            NonNullableUnwrapped(field_layouts) => {
-                self.union_tail_recursion_fields_help(union_in_layout, &[field_layouts])
+            Recursive(tags) => {
+                self.union_tail_recursion_fields_help(layout_interner, union_in_layout, tags)
             }
 
+            NonNullableUnwrapped(field_layouts) => self.union_tail_recursion_fields_help(
+                layout_interner,
+                union_in_layout,
+                &[field_layouts],
+            ),
+
             NullableWrapped {
                 other_tags: tags, ..
-            } => self.union_tail_recursion_fields_help(union_in_layout, tags),
+            } => self.union_tail_recursion_fields_help(layout_interner, union_in_layout, tags),
 
-            NullableUnwrapped { other_fields, .. } => {
-                self.union_tail_recursion_fields_help(union_in_layout, &[other_fields])
-            }
+            NullableUnwrapped { other_fields, .. } => self.union_tail_recursion_fields_help(
+                layout_interner,
+                union_in_layout,
+                &[other_fields],
+            ),
         }
     }
 
     fn union_tail_recursion_fields_help(
         &self,
+        layout_interner: &STLayoutInterner<'a>,
         in_layout: InLayout<'a>,
         tags: &[&'a [InLayout<'a>]],
     ) -> Option<Vec<'a, Option<usize>>> {
+        // A field pointing directly back to this union is stored as a RecursivePointer
+        let is_tail = |field: &InLayout<'a>| {
+            *field == in_layout
+                || matches!(layout_interner.get(*field), Layout::RecursivePointer(_))
+        };
+
         let tailrec_indices = tags
             .iter()
-            .map(|fields| fields.iter().position(|f| *f == in_layout))
+            .map(|fields| fields.iter().position(is_tail))
             .collect_in::<Vec<_>>(self.arena);
 
         if tailrec_indices.iter().any(|i| i.is_some()) {
-            None
-        } else {
             Some(tailrec_indices)
+        } else {
+            None
         }
     }
 }
@@ -664,6 +911,7 @@ impl<'a> CallerProc<'a> {
         let mut ctx = Context {
             new_linker_data: Vec::new_in(arena),
             recursive_union: None,
+            known_nonempty: false,
             op: HelperOp::Eq,
         };
 
@@ -821,6 +1069,20 @@ fn let_lowlevel<'a>(
     )
 }
 
+/// Reset returns a pointer to the allocation it's safe to reuse, or NULL.
+/// For a List, that's the element buffer rather than the List itself.
+fn reset_ret_layout<'a>(
+    layout_interner: &mut STLayoutInterner<'a>,
+    layout: InLayout<'a>,
+) -> InLayout<'a> {
+    match layout_interner.get(layout) {
+        Layout::Builtin(Builtin::List(elem_layout)) => {
+            layout_interner.insert(Layout::Boxed(elem_layout))
+        }
+        _ => layout,
+    }
+}
+
 fn layout_needs_helper_proc<'a>(
     layout_interner: &STLayoutInterner<'a>,
     layout: InLayout<'a>,
@@ -834,11 +1096,21 @@ fn layout_needs_helper_proc<'a>(
             // Str type can use either Zig functions or generated IR, since it's not generic.
             // Eq uses a Zig function, refcount uses generated IR.
             // Both are fine, they were just developed at different times.
-            matches!(op, HelperOp::Inc | HelperOp::Dec | HelperOp::DecRef(_))
+            matches!(
+                op,
+                HelperOp::Inc | HelperOp::Dec | HelperOp::DecRef(_) | HelperOp::AssertBorrowed
+            )
         }
         Layout::Builtin(Builtin::List(_)) => true,
-        Layout::Struct { .. } => true, // note: we do generate a helper for Unit, with just a Stmt::Ret
-        Layout::Union(UnionLayout::NonRecursive(tags)) => !tags.is_empty(),
+        Layout::Struct { field_layouts, .. } => {
+            // An empty struct has nothing to refcount, so skip the helper entirely.
+            // We do still generate an equality helper for Unit, with just a Stmt::Ret
+            !field_layouts.is_empty() || op == HelperOp::Eq
+        }
+        Layout::Union(UnionLayout::NonRecursive(tags)) => {
+            // An enum (no tag has a payload) is just a tag id, so only equality needs a helper
+            !tags.is_empty() && (op == HelperOp::Eq || tags.iter().any(|t| !t.is_empty()))
+        }
         Layout::Union(_) => true,
         Layout::LambdaSet(_) => true,
         Layout::RecursivePointer(_) => false,
@@ -2,20 +2,24 @@
 
 use bumpalo::collections::vec::Vec;
 use bumpalo::collections::CollectIn;
+use roc_builtins::bitcode::{FloatWidth, IntWidth};
+use roc_module::ident::ForeignSymbol;
 use roc_module::low_level::{LowLevel, LowLevel::*};
 use roc_module::symbol::{IdentIds, Symbol};
-use roc_target::PtrWidth;
+use std::hash::Hasher;
 
 use crate::borrow::Ownership;
 use crate::code_gen_help::let_lowlevel;
 use crate::ir::{
-    BranchInfo, Call, CallType, Expr, JoinPointId, Literal, ModifyRc, Param, Stmt, UpdateModeId,
+    BranchInfo, Call, CallSpecId, CallType, CrashTag, Expr, JoinPointId, Literal, ModifyRc, Param,
+    ProcLayout, Stmt, UpdateModeId,
 };
 use crate::layout::{
-    Builtin, InLayout, Layout, LayoutInterner, STLayoutInterner, TagIdIntType, UnionLayout,
+    Builtin, InLayout, LambdaName, Layout, LayoutInterner, STLayoutInterner, TagIdIntType,
+    UnionLayout,
 };
 
-use super::{CodeGenHelp, Context, HelperOp};
+use super::{CodeGenHelp, Context, HelperOp, RC_TRACE_DEC, RC_TRACE_FN_NAME, RC_TRACE_INC};
 
 const LAYOUT_BOOL: InLayout = Layout::BOOL;
 const LAYOUT_UNIT: InLayout = Layout::UNIT;
@@ -36,22 +40,39 @@ pub fn refcount_stmt<'a>(
         ModifyRc::Inc(structure, amount) => {
             let layout_isize = root.layout_isize;
 
+            // Merge any Incs of the same structure that directly follow this one
+            let mut amount = *amount;
+            let mut following = following;
+            while let Stmt::Refcounting(ModifyRc::Inc(next_structure, next_amount), next) =
+                following
+            {
+                if next_structure != structure {
+                    break;
+                }
+                amount += next_amount;
+                following = next;
+            }
+
             // Define a constant for the amount to increment
+            // Literals are host-endian i128s for any target. Backends decode them with
+            // `from_ne_bytes` on the same host and truncate the value, not the bytes.
             let amount_sym = root.create_symbol(ident_ids, "amount");
-            let amount_expr = Expr::Literal(Literal::Int((*amount as i128).to_ne_bytes()));
+            let amount_expr = Expr::Literal(Literal::Int((amount as i128).to_ne_bytes()));
             let amount_stmt = |next| Stmt::Let(amount_sym, amount_expr, layout_isize, next);
 
             // Call helper proc, passing the Roc structure and constant amount
             let call_result_empty = root.create_symbol(ident_ids, "call_result_empty");
-            let call_expr = root
-                .call_specialized_op(
-                    ident_ids,
-                    ctx,
-                    layout_interner,
-                    layout,
-                    arena.alloc([*structure, amount_sym]),
-                )
-                .unwrap();
+            let call_expr = match root.call_specialized_op(
+                ident_ids,
+                ctx,
+                layout_interner,
+                layout,
+                arena.alloc([*structure, amount_sym]),
+            ) {
+                Some(call_expr) => call_expr,
+                // Nothing to refcount (e.g. an empty struct)
+                None => return following,
+            };
 
             let call_stmt = Stmt::Let(call_result_empty, call_expr, LAYOUT_UNIT, following);
             arena.alloc(amount_stmt(arena.alloc(call_stmt)))
@@ -60,15 +81,17 @@ pub fn refcount_stmt<'a>(
         ModifyRc::Dec(structure) => {
             // Call helper proc, passing the Roc structure
             let call_result_empty = root.create_symbol(ident_ids, "call_result_empty");
-            let call_expr = root
-                .call_specialized_op(
-                    ident_ids,
-                    ctx,
-                    layout_interner,
-                    layout,
-                    arena.alloc([*structure]),
-                )
-                .unwrap();
+            let call_expr = match root.call_specialized_op(
+                ident_ids,
+                ctx,
+                layout_interner,
+                layout,
+                arena.alloc([*structure]),
+            ) {
+                Some(call_expr) => call_expr,
+                // Nothing to refcount (e.g. an empty struct)
+                None => return following,
+            };
             let call_stmt = Stmt::Let(call_result_empty, call_expr, LAYOUT_UNIT, following);
             arena.alloc(call_stmt)
         }
@@ -204,6 +227,10 @@ pub fn refcount_generic<'a>(
             field_layouts,
             structure,
         ),
+        Layout::Union(UnionLayout::NonRecursive(tags)) if tags.iter().all(|t| t.is_empty()) => {
+            // An enum is just a tag id, with no payload to refcount
+            rc_return_stmt(root, ident_ids, ctx)
+        }
         Layout::Union(union_layout) => refcount_union(
             root,
             ident_ids,
@@ -239,6 +266,252 @@ pub fn refcount_generic<'a>(
     }
 }
 
+/// Increment `element` by the length of `list`, in one call to its Inc helper
+pub fn refcount_inc_by_len<'a>(
+    root: &mut CodeGenHelp<'a>,
+    ident_ids: &mut IdentIds,
+    ctx: &mut Context<'a>,
+    layout_interner: &mut STLayoutInterner<'a>,
+    elem_layout: InLayout<'a>,
+    element: Symbol,
+    list: Symbol,
+    following: &'a Stmt<'a>,
+) -> &'a Stmt<'a> {
+    let arena = root.arena;
+
+    let len = root.create_symbol(ident_ids, "len");
+    let call_result_empty = root.create_symbol(ident_ids, "call_result_empty");
+    let call_expr = match root.call_specialized_op(
+        ident_ids,
+        ctx,
+        layout_interner,
+        elem_layout,
+        arena.alloc([element, len]),
+    ) {
+        Some(call_expr) => call_expr,
+        // Nothing to refcount (e.g. an empty struct)
+        None => return following,
+    };
+
+    let call_stmt = Stmt::Let(call_result_empty, call_expr, LAYOUT_UNIT, following);
+    arena.alloc(let_lowlevel(
+        arena,
+        root.layout_isize,
+        len,
+        ListLen,
+        arena.alloc([list]),
+        arena.alloc(call_stmt),
+    ))
+}
+
+/// Hash a layout by its structure, rather than by its interned index, which differs between interners.
+/// Only the parts that affect refcounting are included. For example, the field order hash is not.
+/// Every value is written with a fixed width, so the hash is the same on every host.
+pub fn hash_layout_structure<'a>(
+    layout_interner: &STLayoutInterner<'a>,
+    layout: InLayout<'a>,
+    state: &mut impl Hasher,
+) {
+    fn hash_fields<'a>(
+        layout_interner: &STLayoutInterner<'a>,
+        fields: &[InLayout<'a>],
+        state: &mut impl Hasher,
+    ) {
+        state.write_u32(fields.len() as u32);
+        for field in fields {
+            hash_layout_structure(layout_interner, *field, state);
+        }
+    }
+
+    fn hash_tags<'a>(
+        layout_interner: &STLayoutInterner<'a>,
+        tags: &[&[InLayout<'a>]],
+        state: &mut impl Hasher,
+    ) {
+        state.write_u32(tags.len() as u32);
+        for fields in tags {
+            hash_fields(layout_interner, fields, state);
+        }
+    }
+
+    match layout_interner.get(layout) {
+        Layout::Builtin(builtin) => {
+            state.write_u8(0);
+            match builtin {
+                Builtin::Int(width) => {
+                    state.write_u8(0);
+                    state.write_u8(match width {
+                        IntWidth::U8 => 0,
+                        IntWidth::U16 => 1,
+                        IntWidth::U32 => 2,
+                        IntWidth::U64 => 3,
+                        IntWidth::U128 => 4,
+                        IntWidth::I8 => 5,
+                        IntWidth::I16 => 6,
+                        IntWidth::I32 => 7,
+                        IntWidth::I64 => 8,
+                        IntWidth::I128 => 9,
+                    });
+                }
+                Builtin::Float(width) => {
+                    state.write_u8(1);
+                    state.write_u8(match width {
+                        FloatWidth::F32 => 0,
+                        FloatWidth::F64 => 1,
+                    });
+                }
+                Builtin::Bool => state.write_u8(2),
+                Builtin::Decimal => state.write_u8(3),
+                Builtin::Str => state.write_u8(4),
+                Builtin::List(elem_layout) => {
+                    state.write_u8(5);
+                    hash_layout_structure(layout_interner, elem_layout, state);
+                }
+            }
+        }
+        Layout::Struct { field_layouts, .. } => {
+            state.write_u8(1);
+            hash_fields(layout_interner, field_layouts, state);
+        }
+        Layout::Boxed(inner_layout) => {
+            state.write_u8(2);
+            hash_layout_structure(layout_interner, inner_layout, state);
+        }
+        Layout::Union(union_layout) => {
+            state.write_u8(3);
+            match union_layout {
+                UnionLayout::NonRecursive(tags) => {
+                    state.write_u8(0);
+                    hash_tags(layout_interner, tags, state);
+                }
+                UnionLayout::Recursive(tags) => {
+                    state.write_u8(1);
+                    hash_tags(layout_interner, tags, state);
+                }
+                UnionLayout::NonNullableUnwrapped(fields) => {
+                    state.write_u8(2);
+                    hash_fields(layout_interner, fields, state);
+                }
+                UnionLayout::NullableWrapped {
+                    nullable_id,
+                    other_tags,
+                } => {
+                    state.write_u8(3);
+                    state.write_u16(nullable_id);
+                    hash_tags(layout_interner, other_tags, state);
+                }
+                UnionLayout::NullableUnwrapped {
+                    nullable_id,
+                    other_fields,
+                } => {
+                    state.write_u8(4);
+                    state.write_u8(nullable_id as u8);
+                    hash_fields(layout_interner, other_fields, state);
+                }
+            }
+        }
+        // Refcounted exactly like its runtime representation
+        Layout::LambdaSet(lambda_set) => {
+            hash_layout_structure(layout_interner, lambda_set.representation, state)
+        }
+        // Always points back to the enclosing recursive union, which is already being hashed
+        Layout::RecursivePointer(_) => state.write_u8(4),
+    }
+}
+
+/// Approximate statement count of the helper procs `refcount_generic` would create for a layout.
+/// The constants come from the shapes of the generated IR. Each layout's helper is counted once.
+pub fn estimate_helper_size<'a>(
+    root: &CodeGenHelp<'a>,
+    layout_interner: &STLayoutInterner<'a>,
+    layout: InLayout<'a>,
+    op: HelperOp,
+    visited: &mut Vec<'a, InLayout<'a>>,
+) -> usize {
+    if visited.contains(&layout) {
+        return 0;
+    }
+    visited.push(layout);
+
+    let is_inc = op.is_inc();
+    let dec_extra = !is_inc as usize;
+
+    // Each refcounted field is loaded, then passed to a helper call
+    let fields_size = |fields: &[InLayout<'a>], visited: &mut Vec<'a, InLayout<'a>>| {
+        fields
+            .iter()
+            .filter(|f| layout_interner.contains_refcounted(**f))
+            .map(|f| match layout_interner.get(*f) {
+                Layout::RecursivePointer(_) => 2,
+                _ => 2 + estimate_helper_size(root, layout_interner, *f, op, visited),
+            })
+            .sum::<usize>()
+    };
+
+    match layout_interner.get(layout) {
+        Layout::Builtin(Builtin::Int(_) | Builtin::Float(_) | Builtin::Bool | Builtin::Decimal) => {
+            0
+        }
+        Layout::Builtin(Builtin::Str) => 10 + dec_extra,
+        Layout::Builtin(Builtin::List(elem_layout)) => {
+            let modify_list = 10 + dec_extra;
+            if layout_interner.contains_refcounted(elem_layout) {
+                let elem_loop = 13;
+                let elem_size =
+                    estimate_helper_size(root, layout_interner, elem_layout, op, visited);
+                modify_list + elem_loop + elem_size
+            } else {
+                modify_list
+            }
+        }
+        Layout::Struct { field_layouts, .. } => 2 + fields_size(field_layouts, visited),
+        Layout::Boxed(inner_layout) => {
+            if is_inc {
+                3
+            } else {
+                11 + estimate_helper_size(root, layout_interner, inner_layout, op, visited)
+            }
+        }
+        Layout::LambdaSet(lambda_set) => estimate_helper_size(
+            root,
+            layout_interner,
+            lambda_set.representation,
+            op,
+            visited,
+        ),
+        Layout::RecursivePointer(_) => 0,
+        Layout::Union(union_layout) => {
+            use UnionLayout::*;
+
+            let tags: &[&[InLayout<'a>]] = match union_layout {
+                NonRecursive(tags) => {
+                    let branches: usize = tags.iter().map(|t| 2 + fields_size(t, visited)).sum();
+                    return 2 + branches;
+                }
+                // Inc on a recursive union only touches the outer allocation
+                _ if is_inc => return 4,
+                Recursive(tags)
+                | NullableWrapped {
+                    other_tags: tags, ..
+                } => tags,
+                NonNullableUnwrapped(fields)
+                | NullableUnwrapped {
+                    other_fields: fields,
+                    ..
+                } => root.arena.alloc([fields]),
+            };
+
+            let use_tailrec = !root.rc_prefer_recursion
+                && root
+                    .union_tail_recursion_fields(layout_interner, layout, union_layout)
+                    .is_some();
+            let base = if use_tailrec { 16 } else { 9 };
+            let branches: usize = tags.iter().map(|t| 2 + fields_size(t, visited)).sum();
+            base + branches
+        }
+    }
+}
+
 fn if_unique<'a>(
     root: &mut CodeGenHelp<'a>,
     ident_ids: &mut IdentIds,
@@ -284,6 +557,278 @@ fn if_unique<'a>(
     )
 }
 
+/// Bind `result` to a Bool that is true if `structure` can be modified in place.
+/// Small strings, empty lists and layouts that are never heap-allocated are trivially unique.
+pub fn emit_is_unique<'a>(
+    root: &mut CodeGenHelp<'a>,
+    ident_ids: &mut IdentIds,
+    layout_interner: &mut STLayoutInterner<'a>,
+    layout: InLayout<'a>,
+    structure: Symbol,
+    result: Symbol,
+    following: &'a Stmt<'a>,
+) -> Stmt<'a> {
+    is_unique_help(
+        root,
+        ident_ids,
+        layout_interner,
+        layout,
+        structure,
+        result,
+        true,
+        following,
+    )
+}
+
+/// Bind `result` to a Bool that is true if `structure` is the only reference to its allocation.
+/// Values with no allocation get `no_allocation_result` instead.
+fn is_unique_help<'a>(
+    root: &mut CodeGenHelp<'a>,
+    ident_ids: &mut IdentIds,
+    layout_interner: &mut STLayoutInterner<'a>,
+    layout: InLayout<'a>,
+    structure: Symbol,
+    result: Symbol,
+    no_allocation_result: bool,
+    following: &'a Stmt<'a>,
+) -> Stmt<'a> {
+    let arena = root.arena;
+    let layout_isize = root.layout_isize;
+
+    // Not heap-allocated
+    let no_allocation_stmt = || {
+        Stmt::Let(
+            result,
+            Expr::Literal(Literal::Bool(no_allocation_result)),
+            LAYOUT_BOOL,
+            following,
+        )
+    };
+
+    let (is_str, data_ptr_layout) = match layout_interner.get(layout) {
+        Layout::Builtin(Builtin::Str) => (true, Layout::OPAQUE_PTR),
+        Layout::Builtin(Builtin::List(elem_layout)) => {
+            (false, layout_interner.insert(Layout::Boxed(elem_layout)))
+        }
+        Layout::Union(UnionLayout::NonRecursive(_)) => return no_allocation_stmt(),
+        Layout::Union(union_layout) if union_layout.is_nullable() && !no_allocation_result => {
+            // RefCountIsUnique treats the null pointer as unique, so check for it first
+            return is_unique_nullable_help(
+                root,
+                ident_ids,
+                union_layout,
+                structure,
+                result,
+                following,
+            );
+        }
+        Layout::Union(_) | Layout::Boxed(_) | Layout::RecursivePointer(_) => {
+            // The structure is itself the data pointer
+            return let_lowlevel(
+                arena,
+                LAYOUT_BOOL,
+                result,
+                LowLevel::RefCountIsUnique,
+                &[structure],
+                following,
+            );
+        }
+        _ => return no_allocation_stmt(),
+    };
+    let field_layouts = arena.alloc([data_ptr_layout, layout_isize, layout_isize]);
+
+    //  joinpoint jp_is_unique(result) =
+    //      <following>
+    //  in
+    //      if has_allocation then
+    //          jump jp_is_unique (is_unique data_ptr)
+    //      else
+    //          jump jp_is_unique no_allocation_result
+    let jp_is_unique = JoinPointId(root.create_symbol(ident_ids, "jp_is_unique"));
+
+    let data_ptr = root.create_symbol(ident_ids, "data_ptr");
+    let rc_is_unique = root.create_symbol(ident_ids, "rc_is_unique");
+    let then_stmt = Stmt::Let(
+        data_ptr,
+        Expr::StructAtIndex {
+            index: 0,
+            field_layouts,
+            structure,
+        },
+        data_ptr_layout,
+        arena.alloc(let_lowlevel(
+            arena,
+            LAYOUT_BOOL,
+            rc_is_unique,
+            LowLevel::RefCountIsUnique,
+            &[data_ptr],
+            arena.alloc(Stmt::Jump(jp_is_unique, arena.alloc([rc_is_unique]))),
+        )),
+    );
+
+    let no_allocation = root.create_symbol(ident_ids, "no_allocation");
+    let else_stmt = Stmt::Let(
+        no_allocation,
+        Expr::Literal(Literal::Bool(no_allocation_result)),
+        LAYOUT_BOOL,
+        arena.alloc(Stmt::Jump(jp_is_unique, arena.alloc([no_allocation]))),
+    );
+
+    let has_allocation = root.create_symbol(ident_ids, "has_allocation");
+    let if_stmt = Stmt::if_then_else(
+        arena,
+        has_allocation,
+        LAYOUT_BOOL,
+        then_stmt,
+        arena.alloc(else_stmt),
+    );
+
+    let zero = root.create_symbol(ident_ids, "zero");
+    let zero_expr = Expr::Literal(Literal::Int(0i128.to_ne_bytes()));
+    let zero_stmt = |next| Stmt::Let(zero, zero_expr, layout_isize, next);
+
+    let has_allocation_stmt = if is_str {
+        // has_allocation = (last_word >= 0);
+        // The small string flag is the sign bit of the last word
+        let last_word = root.create_symbol(ident_ids, "last_word");
+        let last_word_expr = Expr::StructAtIndex {
+            index: 2,
+            field_layouts,
+            structure,
+        };
+        Stmt::Let(
+            last_word,
+            last_word_expr,
+            layout_isize,
+            arena.alloc(zero_stmt(arena.alloc(let_lowlevel(
+                arena,
+                LAYOUT_BOOL,
+                has_allocation,
+                NumGte,
+                &[last_word, zero],
+                arena.alloc(if_stmt),
+            )))),
+        )
+    } else {
+        // has_allocation = (len != 0);
+        let len = root.create_symbol(ident_ids, "len");
+        let_lowlevel(
+            arena,
+            layout_isize,
+            len,
+            ListLen,
+            &[structure],
+            arena.alloc(zero_stmt(arena.alloc(let_lowlevel(
+                arena,
+                LAYOUT_BOOL,
+                has_allocation,
+                NotEq,
+                &[len, zero],
+                arena.alloc(if_stmt),
+            )))),
+        )
+    };
+
+    Stmt::Join {
+        id: jp_is_unique,
+        parameters: arena.alloc([Param {
+            symbol: result,
+            ownership: Ownership::Owned,
+            layout: LAYOUT_BOOL,
+        }]),
+        body: following,
+        remainder: arena.alloc(has_allocation_stmt),
+    }
+}
+
+/// Bind `result` to false if `structure` is the null tag of a nullable union,
+/// or otherwise to whether it is the only reference to its allocation.
+fn is_unique_nullable_help<'a>(
+    root: &mut CodeGenHelp<'a>,
+    ident_ids: &mut IdentIds,
+    union_layout: UnionLayout<'a>,
+    structure: Symbol,
+    result: Symbol,
+    following: &'a Stmt<'a>,
+) -> Stmt<'a> {
+    let arena = root.arena;
+    let tag_id_layout = union_layout.tag_id_layout();
+    let nullable_id = match union_layout {
+        UnionLayout::NullableWrapped { nullable_id, .. } => nullable_id,
+        UnionLayout::NullableUnwrapped { nullable_id, .. } => nullable_id as TagIdIntType,
+        _ => unreachable!("Expected a nullable union"),
+    };
+
+    //  joinpoint jp_is_unique(result) =
+    //      <following>
+    //  in
+    //      if tag_id == nullable_id then
+    //          jump jp_is_unique false
+    //      else
+    //          jump jp_is_unique (is_unique structure)
+    let jp_is_unique = JoinPointId(root.create_symbol(ident_ids, "jp_is_unique"));
+
+    let rc_is_unique = root.create_symbol(ident_ids, "rc_is_unique");
+    let else_stmt = let_lowlevel(
+        arena,
+        LAYOUT_BOOL,
+        rc_is_unique,
+        LowLevel::RefCountIsUnique,
+        &[structure],
+        arena.alloc(Stmt::Jump(jp_is_unique, arena.alloc([rc_is_unique]))),
+    );
+
+    let null_is_unique = root.create_symbol(ident_ids, "null_is_unique");
+    let then_stmt = Stmt::Let(
+        null_is_unique,
+        Expr::Literal(Literal::Bool(false)),
+        LAYOUT_BOOL,
+        arena.alloc(Stmt::Jump(jp_is_unique, arena.alloc([null_is_unique]))),
+    );
+
+    let tag_id = root.create_symbol(ident_ids, "tag_id");
+    let null_id = root.create_symbol(ident_ids, "null_id");
+    let is_null = root.create_symbol(ident_ids, "is_null");
+    let is_null_stmt = Stmt::Let(
+        tag_id,
+        Expr::GetTagId {
+            structure,
+            union_layout,
+        },
+        tag_id_layout,
+        arena.alloc(Stmt::Let(
+            null_id,
+            Expr::Literal(Literal::Int((nullable_id as i128).to_ne_bytes())),
+            tag_id_layout,
+            arena.alloc(let_lowlevel(
+                arena,
+                LAYOUT_BOOL,
+                is_null,
+                Eq,
+                &[tag_id, null_id],
+                arena.alloc(Stmt::if_then_else(
+                    arena,
+                    is_null,
+                    LAYOUT_BOOL,
+                    then_stmt,
+                    arena.alloc(else_stmt),
+                )),
+            )),
+        )),
+    );
+
+    Stmt::Join {
+        id: jp_is_unique,
+        parameters: arena.alloc([Param {
+            symbol: result,
+            ownership: Ownership::Owned,
+            layout: LAYOUT_BOOL,
+        }]),
+        body: following,
+        remainder: arena.alloc(is_null_stmt),
+    }
+}
+
 pub fn refcount_reset_proc_body<'a>(
     root: &mut CodeGenHelp<'a>,
     ident_ids: &mut IdentIds,
@@ -292,21 +837,27 @@ pub fn refcount_reset_proc_body<'a>(
     layout: InLayout<'a>,
     structure: Symbol,
 ) -> Stmt<'a> {
-    let rc_ptr = root.create_symbol(ident_ids, "rc_ptr");
-    let rc = root.create_symbol(ident_ids, "rc");
-    let refcount_1 = root.create_symbol(ident_ids, "refcount_1");
     let is_unique = root.create_symbol(ident_ids, "is_unique");
     let addr = root.create_symbol(ident_ids, "addr");
 
     let union_layout = match layout_interner.get(layout) {
         Layout::Union(u) => u,
-        _ => unimplemented!("Reset is only implemented for UnionLayout"),
+        Layout::Builtin(Builtin::List(elem_layout)) => {
+            return refcount_list_reset(
+                root,
+                ident_ids,
+                ctx,
+                layout_interner,
+                elem_layout,
+                structure,
+            );
+        }
+        _ => unimplemented!("Reset is only implemented for UnionLayout and List"),
     };
 
     // Whenever we recurse into a child layout we will want to Decrement
     ctx.op = HelperOp::Dec;
     ctx.recursive_union = Some(union_layout);
-    let recursion_ptr = layout_interner.insert(Layout::RecursivePointer(layout));
 
     // Reset structure is unique. Decrement its children and return a pointer to the allocation.
     let then_stmt = {
@@ -340,22 +891,7 @@ pub fn refcount_reset_proc_body<'a>(
             }
         };
 
-        let tag_id_layout = union_layout.tag_id_layout();
-
-        let tag_id_sym = root.create_symbol(ident_ids, "tag_id");
-        let tag_id_stmt = |next| {
-            Stmt::Let(
-                tag_id_sym,
-                Expr::GetTagId {
-                    structure,
-                    union_layout,
-                },
-                tag_id_layout,
-                next,
-            )
-        };
-
-        let rc_contents_stmt = refcount_union_contents(
+        refcount_union_contents(
             root,
             ident_ids,
             ctx,
@@ -364,15 +900,8 @@ pub fn refcount_reset_proc_body<'a>(
             tag_layouts,
             null_id,
             structure,
-            tag_id_sym,
-            tag_id_layout,
             Stmt::Ret(addr),
-        );
-
-        tag_id_stmt(root.arena.alloc(
-            //
-            rc_contents_stmt,
-        ))
+        )
     };
 
     // Reset structure is not unique. Decrement it and return a NULL pointer.
@@ -411,59 +940,160 @@ pub fn refcount_reset_proc_body<'a>(
     };
 
     // Uniqueness test
-    let is_unique_stmt = {
-        let_lowlevel(
-            root.arena,
-            LAYOUT_BOOL,
-            is_unique,
-            Eq,
-            &[rc, refcount_1],
-            root.arena.alloc(if_stmt),
-        )
-    };
-
-    // Constant for unique refcount
-    let refcount_1_encoded = match root.target_info.ptr_width() {
-        PtrWidth::Bytes4 => i32::MIN as i128,
-        PtrWidth::Bytes8 => i64::MIN as i128,
-    }
-    .to_ne_bytes();
-    let refcount_1_expr = Expr::Literal(Literal::Int(refcount_1_encoded));
-    let refcount_1_stmt = Stmt::Let(
-        refcount_1,
-        refcount_1_expr,
-        root.layout_isize,
-        root.arena.alloc(is_unique_stmt),
+    let is_unique_stmt = emit_is_unique(
+        root,
+        ident_ids,
+        layout_interner,
+        layout,
+        structure,
+        is_unique,
+        root.arena.alloc(if_stmt),
     );
 
-    // Refcount value
-    let rc_expr = Expr::ExprUnbox { symbol: rc_ptr };
+    // Pointer-tagged unions store the tag id in the lower bits of the address
+    let mask_lower_bits = union_layout.stores_tag_id_in_pointer(root.target_info);
 
-    let rc_stmt = Stmt::Let(
-        rc,
-        rc_expr,
-        root.layout_isize,
-        root.arena.alloc(refcount_1_stmt),
-    );
+    // Address of the allocation, for reuse
+    addr_from_data_ptr_help(
+        root,
+        ident_ids,
+        structure,
+        addr,
+        mask_lower_bits,
+        is_unique_stmt,
+        layout,
+    )
+}
+
+/// Reset a List. If it's unique, decrement its elements and return the element buffer for reuse.
+/// Otherwise decrement the List itself and return NULL.
+fn refcount_list_reset<'a>(
+    root: &mut CodeGenHelp<'a>,
+    ident_ids: &mut IdentIds,
+    ctx: &mut Context<'a>,
+    layout_interner: &mut STLayoutInterner<'a>,
+    elem_layout: InLayout<'a>,
+    structure: Symbol,
+) -> Stmt<'a> {
+    let layout_isize = root.layout_isize;
+    let arena = root.arena;
+
+    let is_unique = root.create_symbol(ident_ids, "is_unique");
+
+    // Whenever we recurse into a child layout we will want to Decrement
+    ctx.op = HelperOp::Dec;
+
+    let list_layout = layout_interner.insert(Layout::Builtin(Builtin::List(elem_layout)));
+    let box_layout = layout_interner.insert(Layout::Boxed(elem_layout));
+
+    let len = root.create_symbol(ident_ids, "len");
+    let len_stmt = |next| let_lowlevel(arena, layout_isize, len, ListLen, &[structure], next);
+
+    let zero = root.create_symbol(ident_ids, "zero");
+    let zero_expr = Expr::Literal(Literal::Int(0i128.to_ne_bytes()));
+    let zero_stmt = |next| Stmt::Let(zero, zero_expr, layout_isize, next);
 
-    // a Box never masks bits
-    let mask_lower_bits = false;
+    let is_empty = root.create_symbol(ident_ids, "is_empty");
+    let is_empty_stmt = |next| let_lowlevel(arena, LAYOUT_BOOL, is_empty, Eq, &[len, zero], next);
+
+    let elements = root.create_symbol(ident_ids, "elements");
+    let elements_expr = Expr::StructAtIndex {
+        index: 0,
+        field_layouts: arena.alloc([box_layout, layout_isize, layout_isize]),
+        structure,
+    };
+    let elements_stmt = |next| Stmt::Let(elements, elements_expr, box_layout, next);
 
-    // Refcount pointer
-    let rc_ptr_stmt = {
-        rc_ptr_from_data_ptr_help(
+    // List is unique. Decrement its elements and return the buffer.
+    let then_stmt = if layout_interner.contains_refcounted(elem_layout) {
+        refcount_list_elems(
             root,
             ident_ids,
-            structure,
-            rc_ptr,
-            mask_lower_bits,
-            root.arena.alloc(rc_stmt),
-            addr,
-            recursion_ptr,
+            ctx,
+            layout_interner,
+            elem_layout,
+            box_layout,
+            box_layout,
+            len,
+            elements,
+            None,
+            Stmt::Ret(elements),
+        )
+    } else {
+        Stmt::Ret(elements)
+    };
+
+    // Null pointer with the buffer's layout
+    let null_stmt = |root: &mut CodeGenHelp<'a>, ident_ids: &mut IdentIds| {
+        let null = root.create_symbol(ident_ids, "null");
+        Stmt::Let(
+            null,
+            Expr::NullPointer,
+            box_layout,
+            root.arena.alloc(Stmt::Ret(null)),
+        )
+    };
+
+    // List is not unique. Decrement it and return a NULL pointer.
+    let else_stmt = {
+        let decrement_unit = root.create_symbol(ident_ids, "decrement_unit");
+        let decrement_expr = root
+            .call_specialized_op(
+                ident_ids,
+                ctx,
+                layout_interner,
+                list_layout,
+                arena.alloc([structure]),
+            )
+            .unwrap();
+        let null_stmt = null_stmt(root, ident_ids);
+        Stmt::Let(
+            decrement_unit,
+            decrement_expr,
+            LAYOUT_UNIT,
+            arena.alloc(null_stmt),
         )
     };
 
-    rc_ptr_stmt
+    let if_stmt = Stmt::if_then_else(
+        arena,
+        is_unique,
+        box_layout,
+        then_stmt,
+        arena.alloc(else_stmt),
+    );
+
+    // Uniqueness test, on the element buffer
+    let is_unique_stmt = emit_is_unique(
+        root,
+        ident_ids,
+        layout_interner,
+        box_layout,
+        elements,
+        is_unique,
+        arena.alloc(if_stmt),
+    );
+
+    // An empty List has no buffer to reuse
+    let empty_stmt = null_stmt(root, ident_ids);
+    let non_empty_stmt = elements_stmt(arena.alloc(is_unique_stmt));
+
+    len_stmt(arena.alloc(
+        //
+        zero_stmt(arena.alloc(
+            //
+            is_empty_stmt(arena.alloc(
+                //
+                Stmt::if_then_else(
+                    arena,
+                    is_empty,
+                    box_layout,
+                    empty_stmt,
+                    arena.alloc(non_empty_stmt),
+                ),
+            )),
+        )),
+    ))
 }
 
 pub fn refcount_resetref_proc_body<'a>(
@@ -474,21 +1104,21 @@ pub fn refcount_resetref_proc_body<'a>(
     layout: InLayout<'a>,
     structure: Symbol,
 ) -> Stmt<'a> {
-    let rc_ptr = root.create_symbol(ident_ids, "rc_ptr");
-    let rc = root.create_symbol(ident_ids, "rc");
-    let refcount_1 = root.create_symbol(ident_ids, "refcount_1");
     let is_unique = root.create_symbol(ident_ids, "is_unique");
     let addr = root.create_symbol(ident_ids, "addr");
 
-    let union_layout = match layout_interner.get(layout) {
-        Layout::Union(u) => u,
-        _ => unimplemented!("Reset is only implemented for UnionLayout"),
-    };
-
     // Whenever we recurse into a child layout we will want to Decrement
     ctx.op = HelperOp::Dec;
-    ctx.recursive_union = Some(union_layout);
-    let recursion_ptr = layout_interner.insert(Layout::RecursivePointer(layout));
+    let mask_lower_bits = match layout_interner.get(layout) {
+        Layout::Union(union_layout) => {
+            ctx.recursive_union = Some(union_layout);
+            // Pointer-tagged unions store the tag id in the lower bits of the address
+            union_layout.stores_tag_id_in_pointer(root.target_info)
+        }
+        // A Box has no tags. Its contents are left for the reuse to deal with.
+        Layout::Boxed(_) => false,
+        _ => unimplemented!("ResetRef is only implemented for UnionLayout and Box"),
+    };
 
     // Reset structure is unique. Return a pointer to the allocation.
     let then_stmt = Stmt::Ret(addr);
@@ -529,59 +1159,82 @@ pub fn refcount_resetref_proc_body<'a>(
     );
 
     // Uniqueness test
-    let is_unique_stmt = {
-        let_lowlevel(
-            root.arena,
-            LAYOUT_BOOL,
-            is_unique,
-            Eq,
-            &[rc, refcount_1],
-            root.arena.alloc(if_stmt),
-        )
-    };
-
-    // Constant for unique refcount
-    let refcount_1_encoded = match root.target_info.ptr_width() {
-        PtrWidth::Bytes4 => i32::MIN as i128,
-        PtrWidth::Bytes8 => i64::MIN as i128,
-    }
-    .to_ne_bytes();
-    let refcount_1_expr = Expr::Literal(Literal::Int(refcount_1_encoded));
-    let refcount_1_stmt = Stmt::Let(
-        refcount_1,
-        refcount_1_expr,
-        root.layout_isize,
-        root.arena.alloc(is_unique_stmt),
+    let is_unique_stmt = emit_is_unique(
+        root,
+        ident_ids,
+        layout_interner,
+        layout,
+        structure,
+        is_unique,
+        root.arena.alloc(if_stmt),
     );
 
-    // Refcount value
-    let rc_expr = Expr::ExprUnbox { symbol: rc_ptr };
+    // Address of the allocation, for reuse
+    addr_from_data_ptr_help(
+        root,
+        ident_ids,
+        structure,
+        addr,
+        mask_lower_bits,
+        is_unique_stmt,
+        layout,
+    )
+}
 
-    let rc_stmt = Stmt::Let(
-        rc,
-        rc_expr,
-        root.layout_isize,
-        root.arena.alloc(refcount_1_stmt),
-    );
+/// Crash if a value that should be borrowed from a shared owner is unique.
+/// A unique value could be freed or mutated in place by its owner while we're still using it.
+/// Static values have no owner, so they are always fine to borrow. So are small strings,
+/// empty lists and the null tag of a nullable union, since they have no allocation at all.
+pub fn refcount_assert_borrowed_proc_body<'a>(
+    root: &mut CodeGenHelp<'a>,
+    ident_ids: &mut IdentIds,
+    layout_interner: &mut STLayoutInterner<'a>,
+    layout: InLayout<'a>,
+    structure: Symbol,
+) -> Stmt<'a> {
+    let is_unique = root.create_symbol(ident_ids, "is_unique");
 
-    // a Box never masks bits
-    let mask_lower_bits = false;
+    // Unique. Someone else could free this value while we're borrowing it.
+    let crash_stmt = {
+        let msg = root.create_symbol(ident_ids, "msg");
+        Stmt::Let(
+            msg,
+            Expr::Literal(Literal::Str("Borrowed value is unique")),
+            Layout::STR,
+            root.arena.alloc(Stmt::Crash(msg, CrashTag::Roc)),
+        )
+    };
 
-    // Refcount pointer
-    let rc_ptr_stmt = {
-        rc_ptr_from_data_ptr_help(
-            root,
-            ident_ids,
-            structure,
-            rc_ptr,
-            mask_lower_bits,
-            root.arena.alloc(rc_stmt),
-            addr,
-            recursion_ptr,
+    // Shared or static. Nothing to do.
+    let ret_unit_stmt = {
+        let unit = root.create_symbol(ident_ids, "unit");
+        Stmt::Let(
+            unit,
+            Expr::Struct(&[]),
+            LAYOUT_UNIT,
+            root.arena.alloc(Stmt::Ret(unit)),
         )
     };
 
-    rc_ptr_stmt
+    let if_stmt = Stmt::if_then_else(
+        root.arena,
+        is_unique,
+        LAYOUT_UNIT,
+        crash_stmt,
+        root.arena.alloc(ret_unit_stmt),
+    );
+
+    // Uniqueness test
+    is_unique_help(
+        root,
+        ident_ids,
+        layout_interner,
+        layout,
+        structure,
+        is_unique,
+        false,
+        root.arena.alloc(if_stmt),
+    )
 }
 
 fn rc_return_stmt<'a>(
@@ -607,15 +1260,14 @@ fn refcount_args<'a>(root: &CodeGenHelp<'a>, ctx: &Context<'a>, structure: Symbo
     }
 }
 
-fn rc_ptr_from_data_ptr_help<'a>(
+fn addr_from_data_ptr_help<'a>(
     root: &CodeGenHelp<'a>,
     ident_ids: &mut IdentIds,
     structure: Symbol,
-    rc_ptr_sym: Symbol,
-    mask_lower_bits: bool,
-    following: &'a Stmt<'a>,
     addr_sym: Symbol,
-    recursion_ptr: InLayout<'a>,
+    mask_lower_bits: bool,
+    following: Stmt<'a>,
+    ret_layout: InLayout<'a>,
 ) -> Stmt<'a> {
     use std::ops::Neg;
 
@@ -651,33 +1303,51 @@ fn rc_ptr_from_data_ptr_help<'a>(
     });
     let and_stmt = |next| Stmt::Let(addr_sym, and_expr, root.layout_isize, next);
 
-    // Pointer size constant
-    let ptr_size_sym = root.create_symbol(ident_ids, "ptr_size");
-    let ptr_size_expr = Expr::Literal(Literal::Int(
-        (root.target_info.ptr_width() as i128).to_ne_bytes(),
-    ));
-    let ptr_size_stmt = |next| Stmt::Let(ptr_size_sym, ptr_size_expr, root.layout_isize, next);
-
-    // Refcount address
-    let rc_addr_sym = root.create_symbol(ident_ids, "rc_addr");
-    let sub_expr = Expr::Call(Call {
-        call_type: CallType::LowLevel {
-            op: LowLevel::NumSubSaturated,
-            update_mode: UpdateModeId::BACKEND_DUMMY,
-        },
-        arguments: root.arena.alloc([addr_sym, ptr_size_sym]),
-    });
-    let sub_stmt = |next| Stmt::Let(rc_addr_sym, sub_expr, Layout::usize(root.target_info), next);
+    // Crash rather than let the refcount be read from below the null pointer
+    let checked_stmt = if root.rc_debug_assertions {
+        // Pointer size constant
+        let ptr_size_sym = root.create_symbol(ident_ids, "ptr_size");
+        let ptr_size_expr = Expr::Literal(Literal::Int(
+            (root.target_info.ptr_width() as i128).to_ne_bytes(),
+        ));
+
+        let is_valid = root.create_symbol(ident_ids, "is_valid_data_ptr");
+        let is_valid_expr = Expr::Call(Call {
+            call_type: CallType::LowLevel {
+                op: LowLevel::NumGte,
+                update_mode: UpdateModeId::BACKEND_DUMMY,
+            },
+            arguments: root.arena.alloc([addr_sym, ptr_size_sym]),
+        });
+
+        let msg = root.create_symbol(ident_ids, "msg");
+        let crash_stmt = Stmt::Let(
+            msg,
+            Expr::Literal(Literal::Str("Refcount pointer would be null")),
+            Layout::STR,
+            root.arena.alloc(Stmt::Crash(msg, CrashTag::Roc)),
+        );
 
-    // Typecast the refcount address from integer to pointer
-    let cast_expr = Expr::Call(Call {
-        call_type: CallType::LowLevel {
-            op: LowLevel::PtrCast,
-            update_mode: UpdateModeId::BACKEND_DUMMY,
-        },
-        arguments: root.arena.alloc([rc_addr_sym]),
-    });
-    let cast_stmt = |next| Stmt::Let(rc_ptr_sym, cast_expr, recursion_ptr, next);
+        Stmt::Let(
+            ptr_size_sym,
+            ptr_size_expr,
+            root.layout_isize,
+            root.arena.alloc(Stmt::Let(
+                is_valid,
+                is_valid_expr,
+                LAYOUT_BOOL,
+                root.arena.alloc(Stmt::if_then_else(
+                    root.arena,
+                    is_valid,
+                    ret_layout,
+                    following,
+                    root.arena.alloc(crash_stmt),
+                )),
+            )),
+        )
+    } else {
+        following
+    };
 
     if mask_lower_bits {
         as_int_stmt(root.arena.alloc(
@@ -686,32 +1356,14 @@ fn rc_ptr_from_data_ptr_help<'a>(
                 //
                 and_stmt(root.arena.alloc(
                     //
-                    ptr_size_stmt(root.arena.alloc(
-                        //
-                        sub_stmt(root.arena.alloc(
-                            //
-                            cast_stmt(root.arena.alloc(
-                                //
-                                following,
-                            )),
-                        )),
-                    )),
+                    checked_stmt,
                 )),
             )),
         ))
     } else {
         as_int_stmt(root.arena.alloc(
             //
-            ptr_size_stmt(root.arena.alloc(
-                //
-                sub_stmt(root.arena.alloc(
-                    //
-                    cast_stmt(root.arena.alloc(
-                        //
-                        following,
-                    )),
-                )),
-            )),
+            checked_stmt,
         ))
     }
 }
@@ -720,13 +1372,14 @@ fn modify_refcount<'a>(
     root: &CodeGenHelp<'a>,
     ident_ids: &mut IdentIds,
     ctx: &mut Context<'a>,
+    layout: InLayout<'a>,
     data_ptr: Symbol,
     alignment: u32,
     following: &'a Stmt<'a>,
 ) -> Stmt<'a> {
     // Call the relevant Zig lowlevel to actually modify the refcount
     let zig_call_result = root.create_symbol(ident_ids, "zig_call_result");
-    match ctx.op {
+    let modify_stmt = match ctx.op {
         HelperOp::Inc => {
             let zig_call_expr = Expr::Call(Call {
                 call_type: CallType::LowLevel {
@@ -740,6 +1393,13 @@ fn modify_refcount<'a>(
 
         HelperOp::Dec | HelperOp::DecRef(_) => {
             debug_assert!(alignment >= root.target_info.ptr_width() as u32);
+            debug_assert!(
+                alignment.is_power_of_two(),
+                "Alignment {} for deallocating layout {:?} is not a power of two",
+                alignment,
+                layout
+            );
+            let alignment = Ord::max(alignment, root.min_alloc_alignment);
             let alignment_sym = root.create_symbol(ident_ids, "alignment");
             let alignment_expr = Expr::Literal(Literal::Int((alignment as i128).to_ne_bytes()));
             let alignment_stmt = |next| Stmt::Let(alignment_sym, alignment_expr, LAYOUT_U32, next);
@@ -760,9 +1420,62 @@ fn modify_refcount<'a>(
         }
 
         _ => unreachable!(),
+    };
+
+    if cfg!(debug_assertions) && root.rc_trace_stderr {
+        rc_trace_stmt(root, ident_ids, ctx, layout, data_ptr, modify_stmt)
+    } else {
+        modify_stmt
     }
 }
 
+/// Call the host's trace function, just before modifying a refcount
+fn rc_trace_stmt<'a>(
+    root: &CodeGenHelp<'a>,
+    ident_ids: &mut IdentIds,
+    ctx: &Context<'a>,
+    layout: InLayout<'a>,
+    data_ptr: Symbol,
+    following: Stmt<'a>,
+) -> Stmt<'a> {
+    let op_kind = root.create_symbol(ident_ids, "op_kind");
+    let op_kind_value = if ctx.op.is_inc() {
+        RC_TRACE_INC
+    } else {
+        RC_TRACE_DEC
+    };
+    let op_kind_expr = Expr::Literal(Literal::Int((op_kind_value as i128).to_ne_bytes()));
+
+    let layout_id = root.create_symbol(ident_ids, "layout_id");
+    let layout_id_expr = Expr::Literal(Literal::Int((layout.index() as i128).to_ne_bytes()));
+
+    let trace_result = root.create_symbol(ident_ids, "trace_result");
+    let trace_expr = Expr::Call(Call {
+        call_type: CallType::Foreign {
+            foreign_symbol: ForeignSymbol::from(RC_TRACE_FN_NAME),
+            ret_layout: LAYOUT_UNIT,
+        },
+        arguments: root.arena.alloc([data_ptr, op_kind, layout_id]),
+    });
+
+    Stmt::Let(
+        op_kind,
+        op_kind_expr,
+        LAYOUT_U32,
+        root.arena.alloc(Stmt::Let(
+            layout_id,
+            layout_id_expr,
+            LAYOUT_U32,
+            root.arena.alloc(Stmt::Let(
+                trace_result,
+                trace_expr,
+                LAYOUT_UNIT,
+                root.arena.alloc(following),
+            )),
+        )),
+    )
+}
+
 /// Generate a procedure to modify the reference count of a Str
 fn refcount_str<'a>(
     root: &CodeGenHelp<'a>,
@@ -813,11 +1526,14 @@ fn refcount_str<'a>(
     // A pointer to the refcount value itself
     let alignment = root.target_info.ptr_width() as u32;
 
+    // Big string literals are static, with a REFCOUNT_MAX refcount.
+    // The Zig refcount functions never modify that, so there is no need to check for it here.
     let ret_unit_stmt = rc_return_stmt(root, ident_ids, ctx);
     let mod_rc_stmt = modify_refcount(
         root,
         ident_ids,
         ctx,
+        Layout::STR,
         elements,
         alignment,
         root.arena.alloc(ret_unit_stmt),
@@ -858,6 +1574,9 @@ fn refcount_list<'a>(
     let layout_isize = root.layout_isize;
     let arena = root.arena;
 
+    // The hint is only for this List, not for any Lists among its elements
+    let known_nonempty = std::mem::take(&mut ctx.known_nonempty);
+
     // A "Box" layout (heap pointer to a single list element)
     let box_layout = layout_interner.insert(Layout::Boxed(elem_layout));
 
@@ -903,18 +1622,21 @@ fn refcount_list<'a>(
         layout_interner.alignment_bytes(elem_layout),
     );
 
+    let list_layout = layout_interner.insert(Layout::Builtin(Builtin::List(elem_layout)));
     let ret_stmt = rc_return_stmt(root, ident_ids, ctx);
     let modify_list = modify_refcount(
         root,
         ident_ids,
         ctx,
+        list_layout,
         elements,
         alignment,
         arena.alloc(ret_stmt),
     );
 
     let relevant_op = ctx.op.is_dec() || ctx.op.is_inc();
-    let modify_elems_and_list = if relevant_op && layout_interner.get(elem_layout).is_refcounted() {
+    let modify_elems = relevant_op && layout_interner.contains_refcounted(elem_layout);
+    let modify_elems_and_list = if modify_elems {
         refcount_list_elems(
             root,
             ident_ids,
@@ -925,6 +1647,7 @@ fn refcount_list<'a>(
             box_layout,
             len,
             elements,
+            None,
             modify_list,
         )
     } else {
@@ -935,13 +1658,20 @@ fn refcount_list<'a>(
     // Do nothing if the list is empty
     //
 
-    let non_empty_branch = root.arena.alloc(
+    let non_empty_stmt = elements_stmt(root.arena.alloc(
         //
-        elements_stmt(root.arena.alloc(
-            //
-            modify_elems_and_list,
-        )),
-    );
+        modify_elems_and_list,
+    ));
+
+    // Skip the check if the caller knows the list is not empty
+    if known_nonempty {
+        return if modify_elems {
+            len_stmt(arena.alloc(non_empty_stmt))
+        } else {
+            non_empty_stmt
+        };
+    }
+    let non_empty_branch = root.arena.alloc(non_empty_stmt);
 
     let if_stmt = Stmt::if_then_else(
         root.arena,
@@ -973,6 +1703,7 @@ fn refcount_list_elems<'a>(
     box_layout: InLayout<'a>,
     length: Symbol,
     elements: Symbol,
+    elem_helper: Option<(Symbol, ProcLayout<'a>)>,
     following: Stmt<'a>,
 ) -> Stmt<'a> {
     use LowLevel::*;
@@ -1043,9 +1774,21 @@ fn refcount_list_elems<'a>(
 
     let mod_elem_unit = root.create_symbol(ident_ids, "mod_elem_unit");
     let mod_elem_args = refcount_args(root, ctx, elem);
-    let mod_elem_expr = root
-        .call_specialized_op(ident_ids, ctx, layout_interner, elem_layout, mod_elem_args)
-        .unwrap();
+    let mod_elem_expr = match elem_helper {
+        // The caller already has a specialized helper proc for the elements
+        Some((proc_symbol, proc_layout)) => Expr::Call(Call {
+            call_type: CallType::ByName {
+                name: LambdaName::no_niche(proc_symbol),
+                ret_layout: proc_layout.result,
+                arg_layouts: proc_layout.arguments,
+                specialization_id: CallSpecId::BACKEND_DUMMY,
+            },
+            arguments: mod_elem_args,
+        }),
+        None => root
+            .call_specialized_op(ident_ids, ctx, layout_interner, elem_layout, mod_elem_args)
+            .unwrap(),
+    };
     let mod_elem_stmt = |next| Stmt::Let(mod_elem_unit, mod_elem_expr, LAYOUT_UNIT, next);
 
     //
@@ -1070,27 +1813,53 @@ fn refcount_list_elems<'a>(
     let is_end = root.create_symbol(ident_ids, "is_end");
     let is_end_stmt = |next| let_lowlevel(arena, LAYOUT_BOOL, is_end, NumGte, &[addr, end], next);
 
-    let if_end_of_list = Stmt::Switch {
-        cond_symbol: is_end,
-        cond_layout: LAYOUT_BOOL,
-        ret_layout,
-        branches: root.arena.alloc([(1, BranchInfo::None, following)]),
-        default_branch: (
-            BranchInfo::None,
-            arena.alloc(box_stmt(arena.alloc(
-                //
-                elem_stmt(arena.alloc(
+    let jump_to_loop = Stmt::Jump(elems_loop, arena.alloc([next_addr]));
+    let loop_iteration = if root.rc_prefetch {
+        // Get the next address first, so that its memory can load while we work on this element.
+        // On the last iteration it points past the end, but a prefetch never faults.
+        let prefetch_unit = root.create_symbol(ident_ids, "prefetch_unit");
+        next_addr_stmt(arena.alloc(
+            //
+            let_lowlevel(
+                arena,
+                LAYOUT_UNIT,
+                prefetch_unit,
+                Prefetch,
+                &[next_addr],
+                arena.alloc(box_stmt(arena.alloc(
                     //
-                    mod_elem_stmt(arena.alloc(
+                    elem_stmt(arena.alloc(
                         //
-                        next_addr_stmt(arena.alloc(
+                        mod_elem_stmt(arena.alloc(
                             //
-                            Stmt::Jump(elems_loop, arena.alloc([next_addr])),
+                            jump_to_loop,
                         )),
                     )),
+                ))),
+            ),
+        ))
+    } else {
+        box_stmt(arena.alloc(
+            //
+            elem_stmt(arena.alloc(
+                //
+                mod_elem_stmt(arena.alloc(
+                    //
+                    next_addr_stmt(arena.alloc(
+                        //
+                        jump_to_loop,
+                    )),
                 )),
-            ))),
-        ),
+            )),
+        ))
+    };
+
+    let if_end_of_list = Stmt::Switch {
+        cond_symbol: is_end,
+        cond_layout: LAYOUT_BOOL,
+        ret_layout,
+        branches: root.arena.alloc([(1, BranchInfo::None, following)]),
+        default_branch: (BranchInfo::None, arena.alloc(loop_iteration)),
     };
 
     let joinpoint_loop = Stmt::Join {
@@ -1174,11 +1943,22 @@ fn refcount_union<'a>(
 ) -> Stmt<'a> {
     use UnionLayout::*;
 
+    // Every value of this union is the null tag, so there's no allocation to refcount
+    if let NullableWrapped {
+        other_tags: &[], ..
+    } = union
+    {
+        return rc_return_stmt(root, ident_ids, ctx);
+    }
+
     let parent_rec_ptr_layout = ctx.recursive_union;
     if !matches!(union, NonRecursive(_)) {
         ctx.recursive_union = Some(union);
     }
 
+    let use_tailrec = ctx.op.is_dec() && !root.rc_prefer_recursion;
+    let use_work_list = ctx.op.is_dec() && root.rc_work_list;
+
     let body = match union {
         NonRecursive(tags) => refcount_union_nonrec(
             root,
@@ -1191,8 +1971,20 @@ fn refcount_union<'a>(
         ),
 
         Recursive(tags) => {
-            let tailrec_idx = root.union_tail_recursion_fields(union_in_layout, union);
-            if let (Some(tail_idx), true) = (tailrec_idx, ctx.op.is_dec()) {
+            let tailrec_idx =
+                root.union_tail_recursion_fields(layout_interner, union_in_layout, union);
+            if use_work_list && tailrec_idx.is_some() {
+                refcount_union_work_list(
+                    root,
+                    ident_ids,
+                    ctx,
+                    layout_interner,
+                    union,
+                    tags,
+                    None,
+                    structure,
+                )
+            } else if let (Some(tail_idx), true) = (tailrec_idx, use_tailrec) {
                 refcount_union_tailrec(
                     root,
                     ident_ids,
@@ -1241,8 +2033,20 @@ fn refcount_union<'a>(
             nullable_id,
         } => {
             let null_id = Some(nullable_id);
-            let tailrec_idx = root.union_tail_recursion_fields(union_in_layout, union);
-            if let (Some(tail_idx), true) = (tailrec_idx, ctx.op.is_dec()) {
+            let tailrec_idx =
+                root.union_tail_recursion_fields(layout_interner, union_in_layout, union);
+            if use_work_list && tailrec_idx.is_some() {
+                refcount_union_work_list(
+                    root,
+                    ident_ids,
+                    ctx,
+                    layout_interner,
+                    union,
+                    tags,
+                    null_id,
+                    structure,
+                )
+            } else if let (Some(tail_idx), true) = (tailrec_idx, use_tailrec) {
                 refcount_union_tailrec(
                     root,
                     ident_ids,
@@ -1274,8 +2078,20 @@ fn refcount_union<'a>(
         } => {
             let null_id = Some(nullable_id as TagIdIntType);
             let tags = root.arena.alloc([other_fields]);
-            let tailrec_idx = root.union_tail_recursion_fields(union_in_layout, union);
-            if let (Some(tail_idx), true) = (tailrec_idx, ctx.op.is_dec()) {
+            let tailrec_idx =
+                root.union_tail_recursion_fields(layout_interner, union_in_layout, union);
+            if use_work_list && tailrec_idx.is_some() {
+                refcount_union_work_list(
+                    root,
+                    ident_ids,
+                    ctx,
+                    layout_interner,
+                    union,
+                    tags,
+                    null_id,
+                    structure,
+                )
+            } else if let (Some(tail_idx), true) = (tailrec_idx, use_tailrec) {
                 refcount_union_tailrec(
                     root,
                     ident_ids,
@@ -1316,27 +2132,12 @@ fn refcount_union_nonrec<'a>(
     tag_layouts: &'a [&'a [InLayout<'a>]],
     structure: Symbol,
 ) -> Stmt<'a> {
-    let tag_id_layout = union_layout.tag_id_layout();
-
-    let tag_id_sym = root.create_symbol(ident_ids, "tag_id");
-    let tag_id_stmt = |next| {
-        Stmt::Let(
-            tag_id_sym,
-            Expr::GetTagId {
-                structure,
-                union_layout,
-            },
-            tag_id_layout,
-            next,
-        )
-    };
-
     let continuation = rc_return_stmt(root, ident_ids, ctx);
 
     if tag_layouts.is_empty() {
         continuation
     } else {
-        let switch_stmt = refcount_union_contents(
+        refcount_union_contents(
             root,
             ident_ids,
             ctx,
@@ -1345,15 +2146,8 @@ fn refcount_union_nonrec<'a>(
             tag_layouts,
             None,
             structure,
-            tag_id_sym,
-            tag_id_layout,
             continuation,
-        );
-
-        tag_id_stmt(root.arena.alloc(
-            //
-            switch_stmt,
-        ))
+        )
     }
 }
 
@@ -1366,13 +2160,19 @@ fn refcount_union_contents<'a>(
     tag_layouts: &'a [&'a [InLayout<'a>]],
     null_id: Option<TagIdIntType>,
     structure: Symbol,
-    tag_id_sym: Symbol,
-    tag_id_layout: InLayout<'a>,
     next_stmt: Stmt<'a>,
 ) -> Stmt<'a> {
+    let tag_id_layout = union_layout.tag_id_layout();
+    let tag_id_sym = root.create_symbol(ident_ids, "tag_id");
+
     let jp_contents_modified = JoinPointId(root.create_symbol(ident_ids, "jp_contents_modified"));
     let mut tag_branches = Vec::with_capacity_in(tag_layouts.len() + 1, root.arena);
 
+    // Tags with identical field layouts would get identical bodies.
+    // Generate each such body once, in a join point that all of those tags jump to.
+    let mut shared_jps: Vec<(&'a [InLayout<'a>], JoinPointId)> = Vec::new_in(root.arena);
+    let mut shared_bodies: Vec<(JoinPointId, Stmt<'a>)> = Vec::new_in(root.arena);
+
     if let Some(id) = null_id {
         let ret = rc_return_stmt(root, ident_ids, ctx);
         tag_branches.push((id as u64, BranchInfo::None, ret));
@@ -1382,10 +2182,21 @@ fn refcount_union_contents<'a>(
         .iter()
         .zip((0..).filter(|tag_id| !matches!(null_id, Some(id) if tag_id == &id)))
     {
+        if let Some((_, jp)) = shared_jps.iter().find(|(l, _)| l == field_layouts) {
+            tag_branches.push((tag_id as u64, BranchInfo::None, Stmt::Jump(*jp, &[])));
+            continue;
+        }
+
+        let is_shared = field_layouts
+            .iter()
+            .any(|l| layout_interner.contains_refcounted(*l))
+            && tag_layouts.iter().filter(|l| *l == field_layouts).count() > 1;
+
         // After refcounting the fields, jump to modify the union itself
         // (Order is important, to avoid use-after-free for Dec)
         let following = Stmt::Jump(jp_contents_modified, &[]);
 
+        let field_layouts_slice = *field_layouts;
         let field_layouts = field_layouts
             .iter()
             .copied()
@@ -1405,12 +2216,19 @@ fn refcount_union_contents<'a>(
             following,
         );
 
-        tag_branches.push((tag_id as u64, BranchInfo::None, fields_stmt));
+        if is_shared {
+            let jp = JoinPointId(root.create_symbol(ident_ids, &format!("jp_tag_{}", tag_id)));
+            shared_jps.push((field_layouts_slice, jp));
+            shared_bodies.push((jp, fields_stmt));
+            tag_branches.push((tag_id as u64, BranchInfo::None, Stmt::Jump(jp, &[])));
+        } else {
+            tag_branches.push((tag_id as u64, BranchInfo::None, fields_stmt));
+        }
     }
 
     let default_stmt: Stmt<'a> = tag_branches.pop().unwrap().2;
 
-    let tag_id_switch = Stmt::Switch {
+    let mut tag_id_switch = Stmt::Switch {
         cond_symbol: tag_id_sym,
         cond_layout: tag_id_layout,
         branches: tag_branches.into_bump_slice(),
@@ -1418,30 +2236,52 @@ fn refcount_union_contents<'a>(
         ret_layout: LAYOUT_UNIT,
     };
 
+    for (jp, body) in shared_bodies {
+        tag_id_switch = Stmt::Join {
+            id: jp,
+            parameters: &[],
+            body: root.arena.alloc(body),
+            remainder: root.arena.alloc(tag_id_switch),
+        };
+    }
+
+    let tag_id_switch_and_let = Stmt::Let(
+        tag_id_sym,
+        Expr::GetTagId {
+            structure,
+            union_layout,
+        },
+        tag_id_layout,
+        root.arena.alloc(tag_id_switch),
+    );
+
     if let UnionLayout::NonRecursive(_) = union_layout {
         Stmt::Join {
             id: jp_contents_modified,
             parameters: &[],
             body: root.arena.alloc(next_stmt),
-            remainder: root.arena.alloc(tag_id_switch),
+            remainder: root.arena.alloc(tag_id_switch_and_let),
         }
     } else {
+        // A shared union only needs its own refcount modified, so it doesn't need its tag id
         let is_unique = root.create_symbol(ident_ids, "is_unique");
 
         let switch_with_unique_check = Stmt::if_then_else(
             root.arena,
             is_unique,
             Layout::UNIT,
-            tag_id_switch,
+            tag_id_switch_and_let,
             root.arena.alloc(Stmt::Jump(jp_contents_modified, &[])),
         );
 
-        let switch_with_unique_check_and_let = let_lowlevel(
-            root.arena,
-            Layout::BOOL,
+        let union_in_layout = layout_interner.insert(Layout::Union(union_layout));
+        let switch_with_unique_check_and_let = emit_is_unique(
+            root,
+            ident_ids,
+            layout_interner,
+            union_in_layout,
+            structure,
             is_unique,
-            LowLevel::RefCountIsUnique,
-            &[structure],
             root.arena.alloc(switch_with_unique_check),
         );
 
@@ -1482,19 +2322,22 @@ fn refcount_union_rec<'a>(
     let rc_structure_stmt = {
         let alignment = Layout::Union(union_layout)
             .allocation_alignment_bytes(layout_interner, root.target_info);
+        let layout = layout_interner.insert(Layout::Union(union_layout));
         let ret_stmt = rc_return_stmt(root, ident_ids, ctx);
 
         modify_refcount(
             root,
             ident_ids,
             ctx,
+            layout,
             structure,
             alignment,
             root.arena.alloc(ret_stmt),
         )
     };
 
-    let rc_contents_then_structure = if ctx.op.is_dec() {
+    if ctx.op.is_dec() {
+        // The contents only need the tag id if the union is unique, so they get it themselves
         refcount_union_contents(
             root,
             ident_ids,
@@ -1504,20 +2347,14 @@ fn refcount_union_rec<'a>(
             tag_layouts,
             null_id,
             structure,
-            tag_id_sym,
-            tag_id_layout,
             rc_structure_stmt,
         )
-    } else {
+    } else if ctx.op.is_decref() && null_id.is_none() {
         rc_structure_stmt
-    };
-
-    if ctx.op.is_decref() && null_id.is_none() {
-        rc_contents_then_structure
     } else {
         tag_id_stmt(root.arena.alloc(
             //
-            rc_contents_then_structure,
+            rc_structure_stmt,
         ))
     }
 }
@@ -1563,6 +2400,8 @@ fn refcount_union_tailrec<'a>(
         let exit_stmt = rc_return_stmt(root, ident_ids, ctx);
         let jump_to_loop = Stmt::Jump(tailrec_loop, root.arena.alloc([next_ptr]));
 
+        // `next` is null if the tail is the null tag, if this tag has no tail-recursive field,
+        // or if the structure was shared. So non-nullable unions need this check too.
         let loop_or_exit = Stmt::Switch {
             cond_symbol: next_addr,
             cond_layout: root.layout_isize,
@@ -1586,6 +2425,7 @@ fn refcount_union_tailrec<'a>(
             root,
             ident_ids,
             ctx,
+            layout,
             current,
             alignment,
             root.arena.alloc(loop_or_exit_based_on_next_addr),
@@ -1693,16 +2533,17 @@ fn refcount_union_tailrec<'a>(
             root.arena,
             is_unique,
             Layout::UNIT,
-            tag_id_switch,
+            tag_id_stmt(root.arena.alloc(tag_id_switch)),
             root.arena.alloc(jump_with_null_ptr),
         );
 
-        let switch_with_unique_check_and_let = let_lowlevel(
-            root.arena,
-            Layout::BOOL,
+        let switch_with_unique_check_and_let = emit_is_unique(
+            root,
+            ident_ids,
+            layout_interner,
+            layout,
+            current,
             is_unique,
-            LowLevel::RefCountIsUnique,
-            &[current],
             root.arena.alloc(switch_with_unique_check),
         );
 
@@ -1720,11 +2561,6 @@ fn refcount_union_tailrec<'a>(
         }
     };
 
-    let loop_body = tag_id_stmt(root.arena.alloc(
-        //
-        rc_contents_then_structure,
-    ));
-
     let loop_init = Stmt::Jump(tailrec_loop, root.arena.alloc([initial_structure]));
     let union_layout = layout_interner.insert(Layout::Union(union_layout));
     let loop_param = Param {
@@ -1736,11 +2572,343 @@ fn refcount_union_tailrec<'a>(
     Stmt::Join {
         id: tailrec_loop,
         parameters: root.arena.alloc([loop_param]),
-        body: root.arena.alloc(loop_body),
+        body: root.arena.alloc(rc_contents_then_structure),
         remainder: root.arena.alloc(loop_init),
     }
 }
 
+/// Decrement a recursive union without native recursion. Instead of calling a helper for each
+/// recursive field, push it onto a work list of pending nodes, and keep popping until it's empty.
+/// Nodes are stored as integer addresses, so the list never refcounts them itself.
+fn refcount_union_work_list<'a>(
+    root: &mut CodeGenHelp<'a>,
+    ident_ids: &mut IdentIds,
+    ctx: &mut Context<'a>,
+    layout_interner: &mut STLayoutInterner<'a>,
+    union_layout: UnionLayout<'a>,
+    tag_layouts: &'a [&'a [InLayout<'a>]],
+    null_id: Option<TagIdIntType>,
+    initial_structure: Symbol,
+) -> Stmt<'a> {
+    let arena = root.arena;
+    let layout_isize = root.layout_isize;
+    let layout = layout_interner.insert(Layout::Union(union_layout));
+    let work_list_layout = layout_interner.insert(Layout::Builtin(Builtin::List(layout_isize)));
+
+    let work_loop = JoinPointId(root.create_symbol(ident_ids, "work_loop"));
+    let jp_pop = JoinPointId(root.create_symbol(ident_ids, "jp_pop"));
+    let jp_modify_union = JoinPointId(root.create_symbol(ident_ids, "jp_modify_union"));
+    let current = root.create_symbol(ident_ids, "current");
+    let work_list = root.create_symbol(ident_ids, "work_list");
+
+    let zero = root.create_symbol(ident_ids, "zero");
+    let zero_expr = Expr::Literal(Literal::Int(0i128.to_ne_bytes()));
+    let zero_stmt = |next| Stmt::Let(zero, zero_expr, layout_isize, next);
+
+    let one = root.create_symbol(ident_ids, "one");
+    let one_expr = Expr::Literal(Literal::Int(1i128.to_ne_bytes()));
+    let one_stmt = |next| Stmt::Let(one, one_expr, layout_isize, next);
+
+    let work_list_param = |symbol| Param {
+        symbol,
+        ownership: Ownership::Owned,
+        layout: work_list_layout,
+    };
+
+    //
+    // Pop the next node, or free the empty work list and return
+    //
+
+    let pop_stmt = {
+        let popped_list = root.create_symbol(ident_ids, "popped_list");
+        let len = root.create_symbol(ident_ids, "len");
+        let is_empty = root.create_symbol(ident_ids, "is_empty");
+        let last = root.create_symbol(ident_ids, "last");
+        let next_addr = root.create_symbol(ident_ids, "next_addr");
+        let next = root.create_symbol(ident_ids, "next");
+        let rest = root.create_symbol(ident_ids, "rest");
+        let released = root.create_symbol(ident_ids, "released");
+
+        // The list is unique and empty, but it may still have an allocation
+        let done_stmt = let_lowlevel(
+            arena,
+            work_list_layout,
+            released,
+            ListReleaseExcessCapacity,
+            &[popped_list],
+            arena.alloc(rc_return_stmt(root, ident_ids, ctx)),
+        );
+
+        let continue_stmt = {
+            let jump = Stmt::Jump(work_loop, arena.alloc([next, rest]));
+            let rest_stmt = let_lowlevel(
+                arena,
+                work_list_layout,
+                rest,
+                ListDropAt,
+                &[popped_list, last],
+                arena.alloc(jump),
+            );
+            let next_stmt = let_lowlevel(
+                arena,
+                layout,
+                next,
+                PtrCast,
+                &[next_addr],
+                arena.alloc(rest_stmt),
+            );
+            let next_addr_stmt = let_lowlevel(
+                arena,
+                layout_isize,
+                next_addr,
+                ListGetUnsafe,
+                &[popped_list, last],
+                arena.alloc(next_stmt),
+            );
+            let_lowlevel(
+                arena,
+                layout_isize,
+                last,
+                NumSubSaturated,
+                &[len, one],
+                arena.alloc(next_addr_stmt),
+            )
+        };
+
+        let if_stmt = Stmt::if_then_else(
+            arena,
+            is_empty,
+            LAYOUT_UNIT,
+            done_stmt,
+            arena.alloc(continue_stmt),
+        );
+        let body = let_lowlevel(
+            arena,
+            layout_isize,
+            len,
+            ListLen,
+            &[popped_list],
+            arena.alloc(let_lowlevel(
+                arena,
+                LAYOUT_BOOL,
+                is_empty,
+                Eq,
+                &[len, zero],
+                arena.alloc(if_stmt),
+            )),
+        );
+
+        (popped_list, body)
+    };
+
+    //
+    // Modify the refcount of the current node, after its fields
+    //
+
+    let modified_list = root.create_symbol(ident_ids, "modified_list");
+    let rc_structure_stmt = {
+        let alignment = layout_interner.allocation_alignment_bytes(layout);
+        let jump = Stmt::Jump(jp_pop, arena.alloc([modified_list]));
+        modify_refcount(
+            root,
+            ident_ids,
+            ctx,
+            layout,
+            current,
+            alignment,
+            arena.alloc(jump),
+        )
+    };
+
+    //
+    // If the node is unique, refcount its fields and push its recursive fields to the work list
+    //
+
+    let tag_id_layout = union_layout.tag_id_layout();
+    let tag_id_sym = root.create_symbol(ident_ids, "tag_id");
+
+    let mut tag_branches = Vec::with_capacity_in(tag_layouts.len() + 1, arena);
+
+    // A null node has no refcount and no fields. Go straight to the next node.
+    if let Some(id) = null_id {
+        let jump = Stmt::Jump(jp_pop, arena.alloc([work_list]));
+        tag_branches.push((id as u64, BranchInfo::None, jump));
+    }
+
+    for (field_layouts, tag_id) in tag_layouts
+        .iter()
+        .zip((0..).filter(|tag_id| !matches!(null_id, Some(id) if tag_id == &id)))
+    {
+        let mut other_fields = Vec::with_capacity_in(field_layouts.len(), arena);
+        let mut recursive_fields = Vec::with_capacity_in(field_layouts.len(), arena);
+        for (i, field) in field_layouts.iter().enumerate() {
+            if *field == layout
+                || matches!(layout_interner.get(*field), Layout::RecursivePointer(_))
+            {
+                recursive_fields.push((i, *field));
+            } else {
+                other_fields.push((i, *field));
+            }
+        }
+
+        // Push each recursive field, threading the work list through
+        let mut list_after = work_list;
+        let mut pushes = Vec::with_capacity_in(recursive_fields.len(), arena);
+        for (i, field) in recursive_fields {
+            let child = root.create_symbol(ident_ids, &format!("field_{}_{}", tag_id, i));
+            let child_addr = root.create_symbol(ident_ids, &format!("addr_{}_{}", tag_id, i));
+            let reserved = root.create_symbol(ident_ids, "reserved");
+            let pushed = root.create_symbol(ident_ids, "pushed");
+            pushes.push((i, field, child, child_addr, list_after, reserved, pushed));
+            list_after = pushed;
+        }
+
+        let mut push_stmt = Stmt::Jump(jp_modify_union, arena.alloc([list_after]));
+        for (i, field, child, child_addr, list_before, reserved, pushed) in pushes.into_iter().rev()
+        {
+            push_stmt = Stmt::Let(
+                child,
+                Expr::UnionAtIndex {
+                    union_layout,
+                    tag_id,
+                    index: i as u64,
+                    structure: current,
+                },
+                field,
+                arena.alloc(let_lowlevel(
+                    arena,
+                    layout_isize,
+                    child_addr,
+                    PtrCast,
+                    &[child],
+                    arena.alloc(let_lowlevel(
+                        arena,
+                        work_list_layout,
+                        reserved,
+                        ListReserve,
+                        &[list_before, one],
+                        arena.alloc(let_lowlevel(
+                            arena,
+                            work_list_layout,
+                            pushed,
+                            ListAppendUnsafe,
+                            &[reserved, child_addr],
+                            arena.alloc(push_stmt),
+                        )),
+                    )),
+                )),
+            );
+        }
+
+        let fields_stmt = refcount_tag_fields(
+            root,
+            ident_ids,
+            ctx,
+            layout_interner,
+            union_layout,
+            other_fields.into_bump_slice(),
+            current,
+            tag_id,
+            push_stmt,
+        );
+
+        tag_branches.push((tag_id as u64, BranchInfo::None, fields_stmt));
+    }
+
+    let default_stmt: Stmt<'a> = tag_branches.pop().unwrap().2;
+
+    let tag_id_switch = Stmt::Switch {
+        cond_symbol: tag_id_sym,
+        cond_layout: tag_id_layout,
+        branches: tag_branches.into_bump_slice(),
+        default_branch: (BranchInfo::None, arena.alloc(default_stmt)),
+        ret_layout: LAYOUT_UNIT,
+    };
+
+    // A shared node only needs its own refcount decremented
+    let is_unique = root.create_symbol(ident_ids, "is_unique");
+    let tag_id_switch_and_let = Stmt::Let(
+        tag_id_sym,
+        Expr::GetTagId {
+            structure: current,
+            union_layout,
+        },
+        tag_id_layout,
+        arena.alloc(tag_id_switch),
+    );
+    let switch_with_unique_check = Stmt::if_then_else(
+        arena,
+        is_unique,
+        LAYOUT_UNIT,
+        tag_id_switch_and_let,
+        arena.alloc(Stmt::Jump(jp_modify_union, arena.alloc([work_list]))),
+    );
+    let switch_with_unique_check_and_let = emit_is_unique(
+        root,
+        ident_ids,
+        layout_interner,
+        layout,
+        current,
+        is_unique,
+        arena.alloc(switch_with_unique_check),
+    );
+
+    let modify_node_stmt = Stmt::Join {
+        id: jp_modify_union,
+        parameters: arena.alloc([work_list_param(modified_list)]),
+        body: arena.alloc(rc_structure_stmt),
+        remainder: arena.alloc(switch_with_unique_check_and_let),
+    };
+
+    // The pop is inside the loop body, so that it can jump back to the start of the loop
+    let (popped_list, pop_body) = pop_stmt;
+    let loop_body = Stmt::Join {
+        id: jp_pop,
+        parameters: arena.alloc([work_list_param(popped_list)]),
+        body: arena.alloc(pop_body),
+        remainder: arena.alloc(modify_node_stmt),
+    };
+
+    //
+    // Start with an empty work list, which has no allocation
+    //
+
+    let empty = root.create_symbol(ident_ids, "empty");
+    let loop_init = let_lowlevel(
+        arena,
+        work_list_layout,
+        empty,
+        ListWithCapacity,
+        &[zero],
+        arena.alloc(Stmt::Jump(
+            work_loop,
+            arena.alloc([initial_structure, empty]),
+        )),
+    );
+
+    let work_loop_stmt = Stmt::Join {
+        id: work_loop,
+        parameters: arena.alloc([
+            Param {
+                symbol: current,
+                ownership: Ownership::Borrowed,
+                layout,
+            },
+            work_list_param(work_list),
+        ]),
+        body: arena.alloc(loop_body),
+        remainder: arena.alloc(loop_init),
+    };
+
+    zero_stmt(arena.alloc(
+        //
+        one_stmt(arena.alloc(
+            //
+            work_loop_stmt,
+        )),
+    ))
+}
+
 fn refcount_tag_fields<'a>(
     root: &mut CodeGenHelp<'a>,
     ident_ids: &mut IdentIds,
@@ -1808,13 +2976,14 @@ fn refcount_boxed<'a>(
         root,
         ident_ids,
         ctx,
+        layout,
         outer,
         alignment,
         arena.alloc(ret_stmt),
     );
 
     // decrement the inner value if the operation is a decrement and the box itself is unique
-    if layout_interner.is_refcounted(inner_layout) && ctx.op.is_dec() {
+    if layout_interner.contains_refcounted(inner_layout) && ctx.op.is_dec() {
         let inner = root.create_symbol(ident_ids, "inner");
         let inner_expr = Expr::ExprUnbox { symbol: outer };
 
@@ -1853,3 +3022,6 @@ fn refcount_boxed<'a>(
         modify_outer
     }
 }
+
+#[cfg(test)]
+mod tests;
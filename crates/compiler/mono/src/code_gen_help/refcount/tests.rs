@@ -0,0 +1,3732 @@
+use std::collections::HashMap;
+use std::vec::Vec;
+
+use bumpalo::Bump;
+use roc_module::symbol::ModuleId;
+use roc_target::TargetInfo;
+
+use super::*;
+use crate::code_gen_help::REFCOUNT_MAX;
+use crate::ir::Proc;
+use crate::layout::{LambdaSet, Niche};
+
+const TARGET_INFO: TargetInfo = TargetInfo::default_x86_64();
+
+fn expand<'a>(
+    arena: &'a Bump,
+    layout_interner: &mut STLayoutInterner<'a>,
+    layout: InLayout<'a>,
+    modify: impl FnOnce(Symbol) -> ModifyRc,
+    following: &'a Stmt<'a>,
+) -> (&'a Stmt<'a>, CodeGenHelp<'a>) {
+    expand_with(arena, layout_interner, layout, modify, following, |_| {})
+}
+
+/// Like `expand`, but lets the test set options on `CodeGenHelp` first
+fn expand_with<'a>(
+    arena: &'a Bump,
+    layout_interner: &mut STLayoutInterner<'a>,
+    layout: InLayout<'a>,
+    modify: impl FnOnce(Symbol) -> ModifyRc,
+    following: &'a Stmt<'a>,
+    configure: impl FnOnce(&mut CodeGenHelp<'a>),
+) -> (&'a Stmt<'a>, CodeGenHelp<'a>) {
+    let mut ident_ids = IdentIds::default();
+    let mut help = CodeGenHelp::new(arena, TARGET_INFO, ModuleId::ATTR);
+    configure(&mut help);
+    let structure = help.create_symbol(&mut ident_ids, "structure");
+
+    let (stmt, _) = help.expand_refcount_stmt(
+        &mut ident_ids,
+        layout_interner,
+        layout,
+        &modify(structure),
+        following,
+    );
+    (stmt, help)
+}
+
+/// Visit every statement in the tree, including join point bodies and switch branches
+fn walk<'a>(stmt: &'a Stmt<'a>, f: &mut impl FnMut(&'a Stmt<'a>)) {
+    f(stmt);
+    match stmt {
+        Stmt::Let(_, _, _, next)
+        | Stmt::Refcounting(_, next)
+        | Stmt::Expect {
+            remainder: next, ..
+        }
+        | Stmt::ExpectFx {
+            remainder: next, ..
+        }
+        | Stmt::Dbg {
+            remainder: next, ..
+        } => walk(next, f),
+        Stmt::Switch {
+            branches,
+            default_branch,
+            ..
+        } => {
+            for (_, _, branch) in branches.iter() {
+                walk(branch, f);
+            }
+            walk(default_branch.1, f);
+        }
+        Stmt::Join {
+            body, remainder, ..
+        } => {
+            walk(body, f);
+            walk(remainder, f);
+        }
+        Stmt::Ret(_) | Stmt::Jump(..) | Stmt::Crash(..) => {}
+    }
+}
+
+fn count_exprs<'a>(stmt: &'a Stmt<'a>, pred: impl Fn(&Expr<'a>) -> bool) -> usize {
+    let mut count = 0;
+    walk(stmt, &mut |s| {
+        if let Stmt::Let(_, expr, _, _) = s {
+            if pred(expr) {
+                count += 1;
+            }
+        }
+    });
+    count
+}
+
+fn count_lowlevel<'a>(stmt: &'a Stmt<'a>, op: LowLevel) -> usize {
+    count_exprs(
+        stmt,
+        |expr| matches!(expr, Expr::Call(Call { call_type: CallType::LowLevel { op: o, .. }, .. }) if *o == op),
+    )
+}
+
+fn count_calls_by_name<'a>(stmt: &'a Stmt<'a>) -> usize {
+    count_exprs(stmt, |expr| {
+        matches!(
+            expr,
+            Expr::Call(Call {
+                call_type: CallType::ByName { .. },
+                ..
+            })
+        )
+    })
+}
+
+/// Count the calls to other helpers that can run after a helper decrements its own allocation.
+/// Follows jumps into join points, so a loop that comes back round is included.
+fn calls_after_own_dec<'a>(body: &'a Stmt<'a>) -> usize {
+    let mut join_bodies = HashMap::new();
+    let mut own_dec = None;
+    walk(body, &mut |s| match s {
+        Stmt::Join { id, body, .. } => {
+            join_bodies.insert(*id, *body);
+        }
+        Stmt::Let(_, expr, _, next) => {
+            if matches!(
+                expr,
+                Expr::Call(Call {
+                    call_type: CallType::LowLevel {
+                        op: RefCountDecDataPtr,
+                        ..
+                    },
+                    ..
+                })
+            ) {
+                assert!(own_dec.is_none(), "expected one RefCountDecDataPtr");
+                own_dec = Some(*next);
+            }
+        }
+        _ => {}
+    });
+
+    let mut count = 0;
+    let mut visited = Vec::new();
+    let mut stack = vec![own_dec.expect("expected a RefCountDecDataPtr")];
+    while let Some(stmt) = stack.pop() {
+        match stmt {
+            Stmt::Let(_, expr, _, next) => {
+                count += matches!(
+                    expr,
+                    Expr::Call(Call {
+                        call_type: CallType::ByName { .. },
+                        ..
+                    })
+                ) as usize;
+                stack.push(next);
+            }
+            Stmt::Switch {
+                branches,
+                default_branch,
+                ..
+            } => {
+                stack.extend(branches.iter().map(|(_, _, branch)| branch));
+                stack.push(default_branch.1);
+            }
+            Stmt::Join { remainder, .. } => stack.push(remainder),
+            Stmt::Jump(id, _) if !visited.contains(id) => {
+                visited.push(*id);
+                stack.push(join_bodies[id]);
+            }
+            Stmt::Refcounting(_, next) => stack.push(next),
+            _ => {}
+        }
+    }
+    count
+}
+
+#[test]
+fn dec_children_before_own_allocation() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+    let box_str = interner.insert(Layout::Boxed(Layout::STR));
+    let list_str = interner.insert(Layout::Builtin(Builtin::List(Layout::STR)));
+    let cons_list = Shape::ConsList(Box::new(Shape::Str)).layout(arena, &mut interner);
+
+    // The tail loop for recursive unions moves on to the next node after freeing this one,
+    // so check the self-recursive version, where every child is handled by a call.
+    let configure = |help: &mut CodeGenHelp| help.set_rc_prefer_recursion(true);
+    for layout in [box_str, list_str, cons_list] {
+        let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+        let (_, mut help) = expand_with(
+            arena,
+            &mut interner,
+            layout,
+            ModifyRc::Dec,
+            following,
+            configure,
+        );
+        let procs = help.take_procs();
+        let dec = procs.iter().find(|p| p.args[0].0 == layout).unwrap();
+
+        assert!(count_calls_by_name(&dec.body) > 0);
+        assert_eq!(
+            calls_after_own_dec(&dec.body),
+            0,
+            "{} is freed before its children",
+            interner.dbg(layout)
+        );
+    }
+}
+
+#[test]
+fn dec_list_of_boxed_strings() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+    let box_str = interner.insert(Layout::Boxed(Layout::STR));
+    let list = interner.insert(Layout::Builtin(Builtin::List(box_str)));
+
+    // List calls Box once per element, and Box calls Str. Each frees its own allocation last.
+    let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+    let (_, mut help) = expand(arena, &mut interner, list, ModifyRc::Dec, following);
+    let procs = help.take_procs();
+    assert_eq!(procs.len(), 3);
+    for layout in [list, box_str] {
+        let dec = procs.iter().find(|p| p.args[0].0 == layout).unwrap();
+        assert_eq!(count_calls_by_name(&dec.body), 1);
+        assert_eq!(calls_after_own_dec(&dec.body), 0);
+    }
+    let list_dec = procs.iter().find(|p| p.args[0].0 == list).unwrap();
+    assert_eq!(count_loops(&list_dec.body), 1);
+
+    // Every box and string is freed, except for a shared box, which keeps its string
+    let mut heap = Heap::default();
+    let mut boxes = Vec::new();
+    let mut strs = Vec::new();
+    for rc in [1, 1, 2] {
+        let str_addr = heap.alloc(vec![], 1, 1);
+        let str_value = Value::Struct(vec![Value::Int(str_addr), Value::Int(30), Value::Int(30)]);
+        boxes.push(heap.alloc(vec![str_value], 0, rc));
+        strs.push(str_addr);
+    }
+    let elems = Vec::from_iter(boxes.iter().map(|addr| Value::Int(*addr)));
+    let list_addr = heap.alloc(elems, interner.stack_size(box_str), 1);
+    let value = Value::Struct(vec![Value::Int(list_addr), Value::Int(3), Value::Int(3)]);
+    let heap = simulate_dec(arena, &mut interner, list, &value, heap).unwrap();
+
+    assert_eq!(heap.refcounts[&list_addr], 0);
+    assert_eq!(heap.refcounts[&boxes[0]], 0);
+    assert_eq!(heap.refcounts[&boxes[1]], 0);
+    assert_eq!(heap.refcounts[&boxes[2]], 1);
+    assert_eq!(heap.refcounts[&strs[0]], 0);
+    assert_eq!(heap.refcounts[&strs[1]], 0);
+    assert_eq!(heap.refcounts[&strs[2]], 1);
+}
+
+#[test]
+fn inc_amount_on_32_bit_target() {
+    let arena = &Bump::new();
+    let target_info = TargetInfo::default_wasm32();
+    let mut interner = STLayoutInterner::with_capacity(4, target_info);
+    let list_str = interner.insert(Layout::Builtin(Builtin::List(Layout::STR)));
+
+    let mut ident_ids = IdentIds::default();
+    let mut help = CodeGenHelp::new(arena, target_info, ModuleId::ATTR);
+    let structure = help.create_symbol(&mut ident_ids, "structure");
+
+    // inc structure 3; inc structure 2; ret
+    let ret = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+    let second = arena.alloc(Stmt::Refcounting(ModifyRc::Inc(structure, 2), ret));
+    let (stmt, _) = help.expand_refcount_stmt(
+        &mut ident_ids,
+        &mut interner,
+        list_str,
+        &ModifyRc::Inc(structure, 3),
+        second,
+    );
+
+    match stmt {
+        Stmt::Let(_, Expr::Literal(Literal::Int(bytes)), layout, _) => {
+            assert_eq!(*layout, Layout::I32);
+            assert_eq!(i128::from_ne_bytes(*bytes), 5);
+            assert_eq!(i128::from_ne_bytes(*bytes) as i32, 5);
+        }
+        other => panic!("expected the amount literal, found {:?}", other),
+    }
+}
+
+#[test]
+fn dec_list_nested_three_deep() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // List (List (List Str))
+    let list1 = interner.insert(Layout::Builtin(Builtin::List(Layout::STR)));
+    let list2 = interner.insert(Layout::Builtin(Builtin::List(list1)));
+    let list3 = interner.insert(Layout::Builtin(Builtin::List(list2)));
+
+    let mut ident_ids = IdentIds::default();
+    let mut help = CodeGenHelp::new(arena, TARGET_INFO, ModuleId::ATTR);
+    let structure = help.create_symbol(&mut ident_ids, "structure");
+    let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+    for _ in 0..2 {
+        help.expand_refcount_stmt(
+            &mut ident_ids,
+            &mut interner,
+            list3,
+            &ModifyRc::Dec(structure),
+            following,
+        );
+    }
+
+    // One helper per layout, even when expanded twice
+    let procs = help.take_procs();
+    let helper = |layout| procs.iter().find(|p| p.args[0].0 == layout).unwrap();
+    assert_eq!(procs.len(), 4);
+
+    // Each List helper has one element loop, calling the helper for the next level down
+    for (outer, inner) in [(list3, list2), (list2, list1), (list1, Layout::STR)] {
+        let body = &helper(outer).body;
+        assert_eq!(count_loops(body), 1);
+        let mut callees = Vec::new();
+        walk(body, &mut |s| {
+            if let Stmt::Let(_, Expr::Call(call), _, _) = s {
+                if let CallType::ByName { name, .. } = call.call_type {
+                    callees.push(name.name());
+                }
+            }
+        });
+        assert_eq!(callees, [helper(inner).name.name()]);
+    }
+    assert_eq!(
+        count_lowlevel(&helper(Layout::STR).body, RefCountDecDataPtr),
+        1
+    );
+
+    // Everything is freed, down to the strings
+    let shape = Shape::List(Box::new(Shape::List(Box::new(Shape::List(Box::new(
+        Shape::Str,
+    ))))));
+    check_shape(&shape, FUZZ_SEED).unwrap();
+}
+
+/// `RoseTree : [Tree I64 (List RoseTree)]`
+fn rose_tree<'a>(arena: &'a Bump, interner: &mut STLayoutInterner<'a>) -> InLayout<'a> {
+    let rec_ptr = interner.insert(Layout::RecursivePointer(Layout::VOID));
+    let children = interner.insert(Layout::Builtin(Builtin::List(rec_ptr)));
+    let fields = arena.alloc([Layout::I64, children]);
+    interner.insert_recursive(
+        arena,
+        Layout::Union(UnionLayout::NonNullableUnwrapped(fields)),
+    )
+}
+
+#[test]
+fn dec_empty_struct_has_no_helper() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+    assert!(!interner.contains_refcounted(Layout::UNIT));
+
+    let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+    let (stmt, mut help) = expand(arena, &mut interner, Layout::UNIT, ModifyRc::Dec, following);
+
+    assert_eq!(stmt, &*following);
+    assert!(help.take_procs().is_empty());
+}
+
+#[test]
+fn enum_has_no_helper() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+    let no_fields: &[InLayout] = &[];
+    let enum_layout = interner.insert(Layout::Union(UnionLayout::NonRecursive(
+        arena.alloc([no_fields; 4]),
+    )));
+    assert!(!interner.contains_refcounted(enum_layout));
+
+    let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+    let modifies: [fn(Symbol) -> ModifyRc; 2] = [ModifyRc::Dec, |s| ModifyRc::Inc(s, 1)];
+    for modify in modifies {
+        let (stmt, mut help) = expand(arena, &mut interner, enum_layout, modify, following);
+        assert_eq!(stmt, &*following);
+        assert!(help.take_procs().is_empty());
+    }
+
+    // Called directly, the helper body returns without switching on the tag id
+    let mut ident_ids = IdentIds::default();
+    let mut help = CodeGenHelp::new(arena, TARGET_INFO, ModuleId::ATTR);
+    let mut ctx = Context {
+        new_linker_data: bumpalo::vec![in arena],
+        recursive_union: None,
+        known_nonempty: false,
+        op: HelperOp::Dec,
+    };
+    let body = arena.alloc(refcount_generic(
+        &mut help,
+        &mut ident_ids,
+        &mut ctx,
+        &mut interner,
+        enum_layout,
+        Symbol::ARG_1,
+    ));
+    let mut switches = 0;
+    walk(body, &mut |s| {
+        if let Stmt::Switch { .. } = s {
+            switches += 1;
+        }
+    });
+    assert_eq!(switches, 0);
+    assert!(matches!(body, Stmt::Let(_, _, _, Stmt::Ret(_))));
+}
+
+#[test]
+fn decref_non_nullable_unwrapped_skips_children() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+    let layout = rose_tree(arena, &mut interner);
+
+    let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+    let (stmt, mut help) = expand(arena, &mut interner, layout, ModifyRc::DecRef, following);
+
+    // Only this node's allocation is freed. The child list is not touched.
+    assert_eq!(count_lowlevel(stmt, RefCountDecDataPtr), 1);
+    assert_eq!(count_lowlevel(stmt, RefCountIsUnique), 0);
+    assert_eq!(count_calls_by_name(stmt), 0);
+    assert_eq!(
+        count_exprs(stmt, |e| matches!(e, Expr::UnionAtIndex { .. })),
+        0
+    );
+    assert!(help.take_procs().is_empty());
+}
+
+#[test]
+fn dec_struct_with_lambda_set_field() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // { cb : x -> x }, where the closure captures a List U8
+    let list_u8 = interner.insert(Layout::Builtin(Builtin::List(Layout::U8)));
+    let captures = interner.insert(Layout::struct_no_name_order(arena.alloc([list_u8])));
+    let args: &[InLayout] = arena.alloc([Layout::I64]);
+    let captured: &[InLayout] = arena.alloc([list_u8]);
+    let set: &[(Symbol, &[InLayout])] = arena.alloc([(Symbol::ARG_3, captured)]);
+    let lambda_set = interner.insert(Layout::LambdaSet(LambdaSet {
+        args: arena.alloc(args),
+        ret: Layout::I64,
+        set: arena.alloc(set),
+        representation: captures,
+        full_layout: Layout::VOID,
+    }));
+    let record = interner.insert(Layout::struct_no_name_order(arena.alloc([lambda_set])));
+    assert!(interner.contains_refcounted(lambda_set));
+    assert!(interner.contains_refcounted(record));
+
+    let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+    let (stmt, mut help) = expand(arena, &mut interner, record, ModifyRc::Dec, following);
+    assert_eq!(count_calls_by_name(stmt), 1);
+
+    // record -> captures -> list
+    let procs = help.take_procs();
+    assert_eq!(procs.len(), 3);
+    assert_eq!(count_calls_by_name(&procs[0].body), 1);
+    assert_eq!(count_calls_by_name(&procs[1].body), 1);
+    assert_eq!(count_lowlevel(&procs[2].body, RefCountDecDataPtr), 1);
+}
+
+#[test]
+fn dec_list_of_closures_capturing_tree() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // Tree : [Leaf I64, Node Tree Tree], captured either alone or in a struct of captures
+    let tree = Shape::Tree(Box::new(Shape::Int)).layout(arena, &mut interner);
+    let captures = interner.insert(Layout::struct_no_name_order(arena.alloc([tree])));
+    for (representation, wrap) in [
+        (tree, (|ptr| Value::Int(ptr)) as fn(i128) -> Value),
+        (captures, |ptr| Value::Struct(vec![Value::Int(ptr)])),
+    ] {
+        let args: &[InLayout] = arena.alloc([Layout::I64]);
+        let captured: &[InLayout] = arena.alloc([tree]);
+        let set: &[(Symbol, &[InLayout])] = arena.alloc([(Symbol::ARG_3, captured)]);
+        let lambda_set = interner.insert(Layout::LambdaSet(LambdaSet {
+            args: arena.alloc(args),
+            ret: Layout::I64,
+            set: arena.alloc(set),
+            representation,
+            full_layout: Layout::VOID,
+        }));
+        let list = interner.insert(Layout::Builtin(Builtin::List(lambda_set)));
+
+        let leaf = |heap: &mut Heap, x| heap.alloc(vec![Value::Tag(0, vec![Value::Int(x)])], 0, 1);
+        let node = |heap: &mut Heap, left, right| {
+            let tag = Value::Tag(1, vec![Value::Int(left), Value::Int(right)]);
+            heap.alloc(vec![tag], 0, 1)
+        };
+        let mut heap = Heap::default();
+        let mut closures = Vec::new();
+        for i in 0..3 {
+            let left = leaf(&mut heap, i);
+            let right = leaf(&mut heap, i);
+            let inner = node(&mut heap, left, right);
+            let other = leaf(&mut heap, i);
+            closures.push(wrap(node(&mut heap, inner, other)));
+        }
+        let stride = interner.stack_size(lambda_set);
+        let elements = heap.alloc(closures, stride, 1);
+        let value = Value::Struct(vec![Value::Int(elements), Value::Int(3), Value::Int(3)]);
+
+        // The list, and every node of every captured tree, is freed
+        let heap = simulate_dec(arena, &mut interner, list, &value, heap).unwrap();
+        assert!(heap.refcounts.values().all(|rc| *rc == 0));
+    }
+}
+
+/// `ConsList : [Nil, Cons I64 ConsList]`
+fn cons_list<'a>(arena: &'a Bump, interner: &mut STLayoutInterner<'a>) -> InLayout<'a> {
+    let rec_ptr = interner.insert(Layout::RecursivePointer(Layout::VOID));
+    let other_fields = arena.alloc([Layout::I64, rec_ptr]);
+    interner.insert_recursive(
+        arena,
+        Layout::Union(UnionLayout::NullableUnwrapped {
+            nullable_id: false,
+            other_fields,
+        }),
+    )
+}
+
+fn count_loops<'a>(stmt: &'a Stmt<'a>) -> usize {
+    let mut count = 0;
+    walk(stmt, &mut |s| {
+        if let Stmt::Join { parameters, .. } = s {
+            count += !parameters.is_empty() as usize;
+        }
+    });
+    count
+}
+
+#[test]
+fn adjacent_incs_are_coalesced() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+    let list_u8 = interner.insert(Layout::Builtin(Builtin::List(Layout::U8)));
+
+    let mut ident_ids = IdentIds::default();
+    let mut help = CodeGenHelp::new(arena, TARGET_INFO, ModuleId::ATTR);
+    let structure = help.create_symbol(&mut ident_ids, "structure");
+    let other = help.create_symbol(&mut ident_ids, "other");
+
+    // inc structure 1; inc structure 2; inc other 1; ret
+    let ret = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+    let inc_other = arena.alloc(Stmt::Refcounting(ModifyRc::Inc(other, 1), ret));
+    let second = arena.alloc(Stmt::Refcounting(ModifyRc::Inc(structure, 2), inc_other));
+    let (stmt, _) = help.expand_refcount_stmt(
+        &mut ident_ids,
+        &mut interner,
+        list_u8,
+        &ModifyRc::Inc(structure, 1),
+        second,
+    );
+
+    // One call with the total amount, then on to the Inc of a different symbol
+    assert_eq!(count_calls_by_name(stmt), 1);
+    let mut amounts = Vec::new();
+    walk(stmt, &mut |s| {
+        if let Stmt::Let(_, Expr::Literal(Literal::Int(bytes)), _, _) = s {
+            amounts.push(i128::from_ne_bytes(*bytes));
+        }
+    });
+    assert_eq!(amounts, [3]);
+    let mut remaining = 0;
+    walk(stmt, &mut |s| {
+        if let Stmt::Refcounting(ModifyRc::Inc(sym, 1), _) = s {
+            assert!(*sym == other);
+            remaining += 1;
+        }
+    });
+    assert_eq!(remaining, 1);
+}
+
+#[test]
+fn dec_union_prefer_recursion() {
+    for prefer_recursion in [false, true] {
+        let arena = &Bump::new();
+        let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+        let layout = cons_list(arena, &mut interner);
+
+        let mut ident_ids = IdentIds::default();
+        let mut help = CodeGenHelp::new(arena, TARGET_INFO, ModuleId::ATTR);
+        help.set_rc_prefer_recursion(prefer_recursion);
+        let structure = help.create_symbol(&mut ident_ids, "structure");
+        let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+        help.expand_refcount_stmt(
+            &mut ident_ids,
+            &mut interner,
+            layout,
+            &ModifyRc::Dec(structure),
+            following,
+        );
+
+        let procs = help.take_procs();
+        assert_eq!(procs.len(), 1);
+        let body = &procs[0].body;
+        if prefer_recursion {
+            // The tail of the list is handled by the helper calling itself
+            assert_eq!(count_loops(body), 0);
+            assert_eq!(count_calls_by_name(body), 1);
+        } else {
+            // The tail of the list is handled by jumping back to the loop
+            assert_eq!(count_loops(body), 2);
+        }
+    }
+}
+
+#[test]
+fn list_elems_with_supplied_helper() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+    let box_layout = interner.insert(Layout::Boxed(Layout::STR));
+
+    let mut ident_ids = IdentIds::default();
+    let mut help = CodeGenHelp::new(arena, TARGET_INFO, ModuleId::ATTR);
+    let mut ctx = Context {
+        new_linker_data: bumpalo::collections::Vec::new_in(arena),
+        recursive_union: None,
+        known_nonempty: false,
+        op: HelperOp::Dec,
+    };
+    let elem_helper = help.create_symbol(&mut ident_ids, "elem_helper");
+    let proc_layout = ProcLayout {
+        arguments: arena.alloc([Layout::STR]),
+        result: Layout::UNIT,
+        niche: Niche::NONE,
+    };
+
+    let stmt = refcount_list_elems(
+        &mut help,
+        &mut ident_ids,
+        &mut ctx,
+        &mut interner,
+        Layout::STR,
+        Layout::UNIT,
+        box_layout,
+        Symbol::ARG_2,
+        Symbol::ARG_3,
+        Some((elem_helper, proc_layout)),
+        Stmt::Ret(Symbol::ARG_1),
+    );
+
+    let mut targets = Vec::new();
+    walk(arena.alloc(stmt), &mut |s| {
+        if let Stmt::Let(_, Expr::Call(call), _, _) = s {
+            if let CallType::ByName { name, .. } = call.call_type {
+                targets.push(name.name());
+            }
+        }
+    });
+    assert_eq!(targets, [elem_helper]);
+    assert!(help.take_procs().is_empty());
+}
+
+#[test]
+fn resetref_box_reuses_allocation() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+    let list_u8 = interner.insert(Layout::Builtin(Builtin::List(Layout::U8)));
+    let box_layout = interner.insert(Layout::Boxed(list_u8));
+
+    let mut ident_ids = IdentIds::default();
+    let mut help = CodeGenHelp::new(arena, TARGET_INFO, ModuleId::ATTR);
+    let structure = help.create_symbol(&mut ident_ids, "structure");
+    help.call_resetref_refcount(&mut ident_ids, &mut interner, box_layout, structure);
+
+    let procs = help.take_procs();
+    let resetref = procs.iter().find(|p| p.ret_layout == box_layout).unwrap();
+
+    // If unique, return the allocation without looking inside it
+    let mut unique_branch = None;
+    walk(&resetref.body, &mut |s| {
+        if let Stmt::Switch { branches, .. } = s {
+            unique_branch = Some(&branches[0].2);
+        }
+    });
+    assert!(matches!(unique_branch, Some(Stmt::Ret(_))));
+
+    // Otherwise, the Box is decremented as a whole
+    assert_eq!(count_calls_by_name(&resetref.body), 1);
+
+    // The only read is the uniqueness check. The inner list is never read.
+    assert_eq!(count_lowlevel(&resetref.body, RefCountIsUnique), 1);
+    assert_eq!(
+        count_exprs(&resetref.body, |e| matches!(e, Expr::ExprUnbox { .. })),
+        0
+    );
+}
+
+#[test]
+fn reset_masks_pointer_tagged_union() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+    let tree = Shape::Tree(Box::new(Shape::Int)).layout(arena, &mut interner);
+    let cons_list = cons_list(arena, &mut interner);
+    let list_u8 = interner.insert(Layout::Builtin(Builtin::List(Layout::U8)));
+    let box_layout = interner.insert(Layout::Boxed(list_u8));
+
+    let cases = [
+        (tree, true, true),
+        (cons_list, true, false),
+        (box_layout, false, false),
+    ];
+    for (layout, supports_reset, tagged) in cases {
+        for resetref in [false, true] {
+            if !resetref && !supports_reset {
+                continue;
+            }
+            let mut ident_ids = IdentIds::default();
+            let mut help = CodeGenHelp::new(arena, TARGET_INFO, ModuleId::ATTR);
+            let structure = help.create_symbol(&mut ident_ids, "structure");
+            if resetref {
+                help.call_resetref_refcount(&mut ident_ids, &mut interner, layout, structure);
+            } else {
+                help.call_reset_refcount(&mut ident_ids, &mut interner, layout, structure);
+            }
+
+            let procs = help.take_procs();
+            let reset = procs.iter().find(|p| p.ret_layout == layout).unwrap();
+
+            // The reused address has the tag id bits cleared
+            assert_eq!(count_lowlevel(&reset.body, And), tagged as usize);
+        }
+    }
+}
+
+#[test]
+fn resetref_asserts_data_ptr_before_uniqueness_check() {
+    for debug_assertions in [false, true] {
+        let arena = &Bump::new();
+        let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+        let list_u8 = interner.insert(Layout::Builtin(Builtin::List(Layout::U8)));
+        let box_layout = interner.insert(Layout::Boxed(list_u8));
+
+        let mut ident_ids = IdentIds::default();
+        let mut help = CodeGenHelp::new(arena, TARGET_INFO, ModuleId::ATTR);
+        help.set_rc_debug_assertions(debug_assertions);
+        let structure = help.create_symbol(&mut ident_ids, "structure");
+        help.call_resetref_refcount(&mut ident_ids, &mut interner, box_layout, structure);
+
+        let procs = help.take_procs();
+        let resetref = procs.iter().find(|p| p.ret_layout == box_layout).unwrap();
+
+        let mut ops = Vec::new();
+        let mut crashes = 0;
+        walk(&resetref.body, &mut |s| match s {
+            Stmt::Let(
+                _,
+                Expr::Call(Call {
+                    call_type: CallType::LowLevel { op, .. },
+                    ..
+                }),
+                _,
+                _,
+            ) => {
+                if matches!(op, NumGte | RefCountIsUnique) {
+                    ops.push(*op);
+                }
+            }
+            Stmt::Crash(..) => crashes += 1,
+            _ => {}
+        });
+
+        if debug_assertions {
+            assert_eq!(ops, [NumGte, RefCountIsUnique]);
+            assert_eq!(crashes, 1);
+        } else {
+            assert_eq!(ops, [RefCountIsUnique]);
+            assert_eq!(crashes, 0);
+        }
+    }
+}
+
+#[test]
+fn assert_borrowed_crashes_if_unique() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+    let list_u8 = interner.insert(Layout::Builtin(Builtin::List(Layout::U8)));
+    let box_layout = interner.insert(Layout::Boxed(list_u8));
+
+    let mut ident_ids = IdentIds::default();
+    let mut help = CodeGenHelp::new(arena, TARGET_INFO, ModuleId::ATTR);
+    let structure = help.create_symbol(&mut ident_ids, "structure");
+    help.call_assert_borrowed(&mut ident_ids, &mut interner, box_layout, structure);
+
+    let procs = help.take_procs();
+    assert_eq!(procs.len(), 1);
+    let body = &procs[0].body;
+
+    // Checks the refcount and reads nothing else
+    assert_eq!(count_lowlevel(body, RefCountIsUnique), 1);
+    assert_eq!(
+        count_exprs(body, |e| matches!(e, Expr::ExprUnbox { .. })),
+        0
+    );
+    assert_eq!(count_calls_by_name(body), 0);
+
+    // Crashes only in the unique branch
+    let mut unique_branch = None;
+    walk(body, &mut |s| {
+        if let Stmt::Switch { branches, .. } = s {
+            unique_branch = Some(&branches[0].2);
+        }
+    });
+    let mut crashes = 0;
+    walk(unique_branch.unwrap(), &mut |s| {
+        if let Stmt::Crash(_, CrashTag::Roc) = s {
+            crashes += 1;
+        }
+    });
+    assert_eq!(crashes, 1);
+}
+
+#[test]
+fn assert_borrowed_str_list_and_nullable() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+    let list_u8 = interner.insert(Layout::Builtin(Builtin::List(Layout::U8)));
+    let cons_list = cons_list(arena, &mut interner);
+
+    let mut heap = Heap::default();
+    let unique = heap.alloc(vec![Value::Int(0)], 1, 1);
+    let shared = heap.alloc(vec![Value::Int(0)], 1, 2);
+    let node = |next| Value::Tag(1, vec![Value::Int(1), Value::Int(next)]);
+    let unique_node = heap.alloc(vec![node(0)], 0, 1);
+    let shared_node = heap.alloc(vec![node(0)], 0, 2);
+
+    let heap_value = |ptr| Value::Struct(vec![Value::Int(ptr), Value::Int(30), Value::Int(30)]);
+    let small_str = Value::Struct(vec![
+        Value::Int(0x6968),
+        Value::Int(0),
+        Value::Int(i64::MIN as i128),
+    ]);
+    let empty_list = Value::Struct(vec![Value::Int(0), Value::Int(0), Value::Int(0)]);
+
+    let cases = [
+        (Layout::STR, heap_value(unique), false),
+        (Layout::STR, heap_value(shared), true),
+        (Layout::STR, small_str, true),
+        (list_u8, heap_value(unique), false),
+        (list_u8, heap_value(shared), true),
+        (list_u8, empty_list, true),
+        (cons_list, Value::Int(unique_node), false),
+        (cons_list, Value::Int(shared_node), true),
+        (cons_list, Value::Int(0), true),
+    ];
+    for (layout, value, is_ok) in cases {
+        let mut ident_ids = IdentIds::default();
+        let mut help = CodeGenHelp::new(arena, TARGET_INFO, ModuleId::ATTR);
+        let structure = help.create_symbol(&mut ident_ids, "structure");
+        let result = help.create_symbol(&mut ident_ids, "result");
+        let (expr, _) = help.call_assert_borrowed(&mut ident_ids, &mut interner, layout, structure);
+        let stmt = arena.alloc(Stmt::Let(
+            result,
+            expr,
+            LAYOUT_UNIT,
+            arena.alloc(Stmt::Ret(result)),
+        ));
+
+        let procs = help.take_procs();
+        let mut interpreter = Interpreter {
+            procs: HashMap::from_iter(procs.iter().map(|p| (p.name.name(), p))),
+            heap: heap.clone(),
+            fuel: 1000,
+        };
+        let mut env = HashMap::from([(structure, value)]);
+        assert_eq!(interpreter.run(stmt, &mut env).is_ok(), is_ok);
+    }
+}
+
+#[test]
+fn rc_trace_wraps_modify_refcount() {
+    use crate::code_gen_help::{RC_TRACE_DEC, RC_TRACE_FN_NAME, RC_TRACE_INC};
+
+    let inc: fn(Symbol) -> ModifyRc = |s| ModifyRc::Inc(s, 1);
+    let cases = [(inc, RC_TRACE_INC), (ModifyRc::Dec, RC_TRACE_DEC)];
+    for (modify, expected_kind) in cases {
+        for trace in [false, true] {
+            let arena = &Bump::new();
+            let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+            let list_u8 = interner.insert(Layout::Builtin(Builtin::List(Layout::U8)));
+
+            let mut ident_ids = IdentIds::default();
+            let mut help = CodeGenHelp::new(arena, TARGET_INFO, ModuleId::ATTR);
+            help.set_rc_trace_stderr(trace);
+            let structure = help.create_symbol(&mut ident_ids, "structure");
+            let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+            help.expand_refcount_stmt(
+                &mut ident_ids,
+                &mut interner,
+                list_u8,
+                &modify(structure),
+                following,
+            );
+
+            let procs = help.take_procs();
+            let mut literals = HashMap::new();
+            let mut trace_args = None;
+            let mut sequence = Vec::new();
+            walk(&procs[0].body, &mut |s| match s {
+                Stmt::Let(sym, Expr::Literal(Literal::Int(bytes)), _, _) => {
+                    literals.insert(*sym, i128::from_ne_bytes(*bytes));
+                }
+                Stmt::Let(_, Expr::Call(call), _, _) => match &call.call_type {
+                    CallType::Foreign { foreign_symbol, .. } => {
+                        assert_eq!(foreign_symbol, &ForeignSymbol::from(RC_TRACE_FN_NAME));
+                        trace_args = Some(call.arguments);
+                        sequence.push("trace");
+                    }
+                    CallType::LowLevel {
+                        op: RefCountIncDataPtr | RefCountDecDataPtr,
+                        ..
+                    } => sequence.push("modify"),
+                    _ => {}
+                },
+                _ => {}
+            });
+
+            if trace {
+                assert_eq!(sequence, ["trace", "modify"]);
+                let args = trace_args.unwrap();
+                assert_eq!(literals[&args[1]], expected_kind as i128);
+                assert_eq!(literals[&args[2]], list_u8.index() as i128);
+            } else {
+                assert_eq!(sequence, ["modify"]);
+            }
+        }
+    }
+}
+
+#[test]
+fn dec_uses_min_alloc_alignment() {
+    let target_info = TargetInfo::default_wasm32();
+    for (floor, expected) in [(None, 4), (Some(16), 16)] {
+        let arena = &Bump::new();
+        let mut interner = STLayoutInterner::with_capacity(4, target_info);
+        let list_u8 = interner.insert(Layout::Builtin(Builtin::List(Layout::U8)));
+
+        let mut ident_ids = IdentIds::default();
+        let mut help = CodeGenHelp::new(arena, target_info, ModuleId::ATTR);
+        if let Some(alignment) = floor {
+            help.set_min_alloc_alignment(alignment);
+        }
+        let structure = help.create_symbol(&mut ident_ids, "structure");
+        let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+        help.expand_refcount_stmt(
+            &mut ident_ids,
+            &mut interner,
+            list_u8,
+            &ModifyRc::Dec(structure),
+            following,
+        );
+
+        let procs = help.take_procs();
+        let mut alignments = Vec::new();
+        walk(&procs[0].body, &mut |s| {
+            if let Stmt::Let(_, Expr::Literal(Literal::Int(bytes)), LAYOUT_U32, _) = s {
+                alignments.push(i128::from_ne_bytes(*bytes));
+            }
+        });
+        assert_eq!(alignments, [expected]);
+    }
+}
+
+#[test]
+fn dec_rose_tree_recurses_through_child_list() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // Rose : [Rose I64 (List Rose)]. The only recursive field is a List, so there is no tail loop.
+    let tree = rose_tree(arena, &mut interner);
+    let union_layout = match interner.get(tree) {
+        Layout::Union(u) => u,
+        _ => unreachable!(),
+    };
+    let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+    let (_, mut help) = expand(arena, &mut interner, tree, ModifyRc::Dec, following);
+    assert!(help
+        .union_tail_recursion_fields(&interner, tree, union_layout)
+        .is_none());
+
+    // The node helper calls the list helper, which calls the node helper for each child
+    let procs = help.take_procs();
+    assert_eq!(procs.len(), 2);
+    let node = procs.iter().find(|p| p.args[0].0 == tree).unwrap();
+    let list = procs.iter().find(|p| p.args[0].0 != tree).unwrap();
+    assert_eq!(count_loops(&node.body), 0);
+    let callees = |proc: &Proc| {
+        let mut names = Vec::new();
+        walk(&proc.body, &mut |s| {
+            if let Stmt::Let(_, Expr::Call(call), _, _) = s {
+                if let CallType::ByName { name, .. } = call.call_type {
+                    names.push(name.name());
+                }
+            }
+        });
+        names
+    };
+    assert_eq!(callees(node), [list.name.name()]);
+    assert_eq!(callees(list), [node.name.name()]);
+
+    // Every node at every level is freed
+    let ptr_width = TARGET_INFO.ptr_width() as u32;
+    let mut heap = Heap::default();
+    let mut nodes = Vec::new();
+    let mut rose = |heap: &mut Heap, label, children: &[i128]| {
+        let list = if children.is_empty() {
+            Value::Struct(vec![Value::Int(0), Value::Int(0), Value::Int(0)])
+        } else {
+            let elems = Vec::from_iter(children.iter().map(|c| Value::Int(*c)));
+            let addr = heap.alloc(elems, ptr_width, 1);
+            nodes.push(addr);
+            let len = Value::Int(children.len() as _);
+            Value::Struct(vec![Value::Int(addr), len.clone(), len])
+        };
+        let addr = heap.alloc(vec![Value::Tag(0, vec![Value::Int(label), list])], 0, 1);
+        nodes.push(addr);
+        addr
+    };
+    let leaves = [rose(&mut heap, 1, &[]), rose(&mut heap, 2, &[])];
+    let inner = rose(&mut heap, 3, &leaves);
+    let leaf = rose(&mut heap, 4, &[]);
+    let root = rose(&mut heap, 5, &[inner, leaf]);
+    let heap = simulate_dec(arena, &mut interner, tree, &Value::Int(root), heap).unwrap();
+    assert_eq!(nodes.len(), 7);
+    assert!(nodes.iter().all(|addr| heap.refcounts[addr] == 0));
+}
+
+/// Refcount helper procs for decrementing a `List` with the given element layout
+fn dec_list_procs<'a>(
+    arena: &'a Bump,
+    interner: &mut STLayoutInterner<'a>,
+    elem_layout: InLayout<'a>,
+) -> bumpalo::collections::Vec<'a, Proc<'a>> {
+    let list = interner.insert(Layout::Builtin(Builtin::List(elem_layout)));
+    let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+    let (_, mut help) = expand(arena, interner, list, ModifyRc::Dec, following);
+    help.take_procs()
+}
+
+/// The size of each step through the elements of a List
+fn elem_loop_stride<'a>(stmt: &'a Stmt<'a>) -> Option<i128> {
+    let mut literals = HashMap::new();
+    let mut stride_symbol = None;
+    walk(stmt, &mut |s| match s {
+        Stmt::Let(sym, Expr::Literal(Literal::Int(bytes)), _, _) => {
+            literals.insert(*sym, i128::from_ne_bytes(*bytes));
+        }
+        Stmt::Let(_, Expr::Call(call), _, _) => {
+            if let CallType::LowLevel { op: NumMul, .. } = call.call_type {
+                stride_symbol = Some(call.arguments[1]);
+            }
+        }
+        _ => {}
+    });
+    stride_symbol.map(|sym| literals[&sym])
+}
+
+#[test]
+fn dec_list_i128() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+    assert_eq!(interner.stack_size(Layout::I128), 16);
+
+    // Elements need no refcounting, so there's no loop. The allocation is 16-byte aligned.
+    let procs = dec_list_procs(arena, &mut interner, Layout::I128);
+    assert_eq!(procs.len(), 1);
+    let body = &procs[0].body;
+    assert_eq!(count_calls_by_name(body), 0);
+    assert_eq!(elem_loop_stride(body), None);
+    let mut alignments = Vec::new();
+    walk(body, &mut |s| {
+        if let Stmt::Let(_, Expr::Literal(Literal::Int(bytes)), LAYOUT_U32, _) = s {
+            alignments.push(i128::from_ne_bytes(*bytes));
+        }
+    });
+    assert_eq!(alignments, [16]);
+
+    // Alongside a Str, the loop steps over the full 16-byte-aligned element
+    let elem = interner.insert(Layout::struct_no_name_order(
+        arena.alloc([Layout::I128, Layout::STR]),
+    ));
+    let procs = dec_list_procs(arena, &mut interner, elem);
+    assert_eq!(elem_loop_stride(&procs[0].body), Some(48));
+}
+
+/// The shape of the helper procs generated for a refcount operation
+struct RcSummary<'a> {
+    helper_args: Vec<InLayout<'a>>,
+    loops: usize,
+    unique_checks: usize,
+}
+
+fn summarise<'a>(
+    arena: &'a Bump,
+    interner: &mut STLayoutInterner<'a>,
+    layout: InLayout<'a>,
+    modify: impl FnOnce(Symbol) -> ModifyRc,
+) -> (&'a Stmt<'a>, RcSummary<'a>) {
+    let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+    let (stmt, mut help) = expand(arena, interner, layout, modify, following);
+    let procs = help.take_procs();
+    let summary = RcSummary {
+        helper_args: procs.iter().map(|p| p.args[0].0).collect(),
+        loops: procs.iter().map(|p| count_loops(&p.body)).sum(),
+        unique_checks: procs
+            .iter()
+            .map(|p| count_lowlevel(&p.body, RefCountIsUnique))
+            .sum(),
+    };
+    (stmt, summary)
+}
+
+fn assert_same_layouts(actual: &[InLayout], expected: &[InLayout]) {
+    assert_eq!(actual.len(), expected.len());
+    for layout in expected {
+        assert!(actual.contains(layout));
+    }
+}
+
+/// Inc and Dec generate a helper for every refcounted layout in the tree.
+/// Inc never checks uniqueness, Dec checks it for each Box and recursive union.
+fn check_inc_dec<'a>(
+    arena: &'a Bump,
+    interner: &mut STLayoutInterner<'a>,
+    layout: InLayout<'a>,
+    expected_helpers: &[InLayout<'a>],
+    loops: usize,
+    dec_unique_checks: usize,
+) {
+    let (_, inc) = summarise(arena, interner, layout, |s| ModifyRc::Inc(s, 1));
+    assert_same_layouts(&inc.helper_args, expected_helpers);
+    assert_eq!(inc.loops, loops);
+    assert_eq!(inc.unique_checks, 0);
+
+    let (_, dec) = summarise(arena, interner, layout, ModifyRc::Dec);
+    assert_same_layouts(&dec.helper_args, expected_helpers);
+    assert_eq!(dec.loops, loops);
+    assert_eq!(dec.unique_checks, dec_unique_checks);
+}
+
+#[test]
+fn nested_list_of_records() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // List { name : Str, tags : List (Box Str) }
+    let box_str = interner.insert(Layout::Boxed(Layout::STR));
+    let tags = interner.insert(Layout::Builtin(Builtin::List(box_str)));
+    let record = interner.insert(Layout::struct_no_name_order(
+        arena.alloc([Layout::STR, tags]),
+    ));
+    let layout = interner.insert(Layout::Builtin(Builtin::List(record)));
+
+    let helpers = [layout, record, tags, box_str, Layout::STR];
+    check_inc_dec(arena, &mut interner, layout, &helpers, 2, 1);
+
+    // Only the outer list's own refcount
+    let (stmt, decref) = summarise(arena, &mut interner, layout, ModifyRc::DecRef);
+    assert!(decref.helper_args.is_empty());
+    assert_eq!(count_loops(stmt), 0);
+    assert_eq!(count_lowlevel(stmt, RefCountDecDataPtr), 1);
+}
+
+#[test]
+fn nested_json() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // Json : [Null, Bool Bool, Num F64, String Str, Array (List Json), Object (List (Str, Json))]
+    let rec_ptr = interner.insert(Layout::RecursivePointer(Layout::VOID));
+    let array = interner.insert(Layout::Builtin(Builtin::List(rec_ptr)));
+    let pair = interner.insert(Layout::struct_no_name_order(
+        arena.alloc([Layout::STR, rec_ptr]),
+    ));
+    let object = interner.insert(Layout::Builtin(Builtin::List(pair)));
+    let other_tags: &[&[InLayout]] = arena.alloc([
+        arena.alloc([Layout::BOOL]) as &[_],
+        arena.alloc([Layout::F64]),
+        arena.alloc([Layout::STR]),
+        arena.alloc([array]),
+        arena.alloc([object]),
+    ]);
+    let layout = interner.insert_recursive(
+        arena,
+        Layout::Union(UnionLayout::NullableWrapped {
+            nullable_id: 0,
+            other_tags,
+        }),
+    );
+
+    // Inc only touches the outer node. The children are shared along with it.
+    let (_, inc) = summarise(arena, &mut interner, layout, |s| ModifyRc::Inc(s, 1));
+    assert_same_layouts(&inc.helper_args, &[layout]);
+    assert_eq!(inc.loops, 0);
+
+    // Dec visits every child, with the recursive pointers resolved to the Json union
+    let (_, dec) = summarise(arena, &mut interner, layout, ModifyRc::Dec);
+    assert_eq!(dec.helper_args.len(), 5);
+    assert_eq!(dec.helper_args[0], layout);
+    assert!(dec.helper_args.contains(&Layout::STR));
+    for arg in dec.helper_args[1..].iter() {
+        assert!(!matches!(interner.get(*arg), Layout::Union(_)));
+    }
+    // One element loop per list. No tag holds a Json directly, so there's no tail recursion.
+    assert_eq!(dec.loops, 2);
+    assert_eq!(dec.unique_checks, 1);
+
+    // Inlined. Only the outer node is freed.
+    let (stmt, decref) = summarise(arena, &mut interner, layout, ModifyRc::DecRef);
+    assert!(decref.helper_args.is_empty());
+    assert_eq!(count_calls_by_name(stmt), 0);
+    assert_eq!(count_lowlevel(stmt, RefCountDecDataPtr), 1);
+}
+
+#[test]
+fn nested_record_with_boxed_list() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // { name : Str, items : Box (List Str) }
+    let list_str = interner.insert(Layout::Builtin(Builtin::List(Layout::STR)));
+    let box_list = interner.insert(Layout::Boxed(list_str));
+    let layout = interner.insert(Layout::struct_no_name_order(
+        arena.alloc([Layout::STR, box_list]),
+    ));
+
+    // Inc doesn't look inside the Box
+    let (_, inc) = summarise(arena, &mut interner, layout, |s| ModifyRc::Inc(s, 1));
+    assert_same_layouts(&inc.helper_args, &[layout, box_list, Layout::STR]);
+    assert_eq!(inc.loops, 0);
+    assert_eq!(inc.unique_checks, 0);
+
+    let (_, dec) = summarise(arena, &mut interner, layout, ModifyRc::Dec);
+    assert_same_layouts(&dec.helper_args, &[layout, box_list, list_str, Layout::STR]);
+    assert_eq!(dec.loops, 1);
+    assert_eq!(dec.unique_checks, 1);
+
+    // A struct lives on the stack, so DecRef does nothing
+    let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+    let (stmt, _) = expand(arena, &mut interner, layout, ModifyRc::DecRef, following);
+    assert_eq!(stmt, &*following);
+}
+
+#[test]
+fn nested_result_of_list() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // Result (List Str) Str
+    let list_str = interner.insert(Layout::Builtin(Builtin::List(Layout::STR)));
+    let tags: &[&[InLayout]] =
+        arena.alloc([arena.alloc([list_str]) as &[_], arena.alloc([Layout::STR])]);
+    let layout = interner.insert(Layout::Union(UnionLayout::NonRecursive(tags)));
+
+    let helpers = [layout, list_str, Layout::STR];
+    check_inc_dec(arena, &mut interner, layout, &helpers, 1, 0);
+
+    // A non-recursive union lives on the stack, so DecRef does nothing
+    let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+    let (stmt, _) = expand(arena, &mut interner, layout, ModifyRc::DecRef, following);
+    assert_eq!(stmt, &*following);
+}
+
+//
+// Inc/Dec balance fuzzing
+//
+// Generate random layouts and values, run the generated helpers in a tiny IR interpreter,
+// and check that every refcount modified by `Inc` is undone by `Dec`, and that `Dec` on
+// uniquely-owned values frees every allocation exactly once.
+//
+
+/// Deterministic xorshift generator, so failures are reproducible
+struct Rng(u64);
+
+/// Seed for the fuzz test, also used by tests that check a single shape
+const FUZZ_SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Shape {
+    Int,
+    Str,
+    List(Box<Shape>),
+    Struct(Vec<Shape>),
+    Boxed(Box<Shape>),
+    /// `[Left a, Right b]`
+    Either(Box<Shape>, Box<Shape>),
+    /// `ConsList a : [Nil, Cons a (ConsList a)]`
+    ConsList(Box<Shape>),
+    /// `Tree a : [Leaf a, Node (Tree a) (Tree a)]`
+    Tree(Box<Shape>),
+    /// `Rose a : [Rose a (List (Rose a))]`
+    Rose(Box<Shape>),
+}
+
+impl Shape {
+    fn generate(rng: &mut Rng, depth: usize) -> Self {
+        use Shape::*;
+        let inner = |rng: &mut Rng| Box::new(Shape::generate(rng, depth - 1));
+        match rng.below(if depth == 0 { 2 } else { 9 }) {
+            0 => Int,
+            1 => Str,
+            2 => List(inner(rng)),
+            3 => {
+                let len = 1 + rng.below(3) as usize;
+                Struct((0..len).map(|_| *inner(rng)).collect())
+            }
+            4 => Boxed(inner(rng)),
+            5 => Either(inner(rng), inner(rng)),
+            6 => ConsList(inner(rng)),
+            7 => Tree(inner(rng)),
+            _ => Rose(inner(rng)),
+        }
+    }
+
+    /// Strictly smaller shapes to try when minimising a failure
+    fn shrink(&self) -> Vec<Shape> {
+        use Shape::*;
+        let wrap = |inner: &Shape, f: &dyn Fn(Box<Shape>) -> Shape| {
+            let mut smaller = vec![Int, inner.clone()];
+            smaller.extend(inner.shrink().into_iter().map(|s| f(Box::new(s))));
+            smaller
+        };
+        match self {
+            Int => vec![],
+            Str => vec![Int],
+            List(a) => wrap(a, &List),
+            Boxed(a) => wrap(a, &Boxed),
+            ConsList(a) => wrap(a, &ConsList),
+            Tree(a) => wrap(a, &Tree),
+            Rose(a) => wrap(a, &Rose),
+            Either(a, b) => {
+                let mut smaller = vec![Int, (**a).clone(), (**b).clone()];
+                smaller.extend(
+                    a.shrink()
+                        .into_iter()
+                        .map(|s| Either(Box::new(s), b.clone())),
+                );
+                smaller.extend(
+                    b.shrink()
+                        .into_iter()
+                        .map(|s| Either(a.clone(), Box::new(s))),
+                );
+                smaller
+            }
+            Struct(fields) => {
+                let mut smaller: Vec<Shape> = fields.clone();
+                for i in 0..fields.len() {
+                    if fields.len() > 1 {
+                        let mut fewer = fields.clone();
+                        fewer.remove(i);
+                        smaller.push(Struct(fewer));
+                    }
+                    for s in fields[i].shrink() {
+                        let mut simpler = fields.clone();
+                        simpler[i] = s;
+                        smaller.push(Struct(simpler));
+                    }
+                }
+                smaller
+            }
+        }
+    }
+
+    fn layout<'a>(&self, arena: &'a Bump, interner: &mut STLayoutInterner<'a>) -> InLayout<'a> {
+        let rec_ptr = interner.insert(Layout::RecursivePointer(Layout::VOID));
+        match self {
+            Shape::Int => Layout::I64,
+            Shape::Str => Layout::STR,
+            Shape::List(a) => {
+                let elem = a.layout(arena, interner);
+                interner.insert(Layout::Builtin(Builtin::List(elem)))
+            }
+            Shape::Struct(fields) => {
+                let fields = Vec::from_iter(fields.iter().map(|f| f.layout(arena, interner)));
+                interner.insert(Layout::struct_no_name_order(
+                    arena.alloc_slice_copy(&fields),
+                ))
+            }
+            Shape::Boxed(a) => {
+                let inner = a.layout(arena, interner);
+                interner.insert(Layout::Boxed(inner))
+            }
+            Shape::Either(a, b) => {
+                let left: &[InLayout] = arena.alloc([a.layout(arena, interner)]);
+                let right: &[InLayout] = arena.alloc([b.layout(arena, interner)]);
+                let tags = arena.alloc([left, right]);
+                interner.insert(Layout::Union(UnionLayout::NonRecursive(tags)))
+            }
+            Shape::ConsList(a) => {
+                let other_fields = arena.alloc([a.layout(arena, interner), rec_ptr]);
+                interner.insert_recursive(
+                    arena,
+                    Layout::Union(UnionLayout::NullableUnwrapped {
+                        nullable_id: false,
+                        other_fields,
+                    }),
+                )
+            }
+            Shape::Tree(a) => {
+                let leaf: &[InLayout] = arena.alloc([a.layout(arena, interner)]);
+                let node: &[InLayout] = arena.alloc([rec_ptr, rec_ptr]);
+                let tags = arena.alloc([leaf, node]);
+                interner.insert_recursive(arena, Layout::Union(UnionLayout::Recursive(tags)))
+            }
+            Shape::Rose(a) => {
+                let children = interner.insert(Layout::Builtin(Builtin::List(rec_ptr)));
+                let fields = arena.alloc([a.layout(arena, interner), children]);
+                interner.insert_recursive(
+                    arena,
+                    Layout::Union(UnionLayout::NonNullableUnwrapped(fields)),
+                )
+            }
+        }
+    }
+}
+
+/// A runtime value in the interpreter. Pointers are addresses into `Heap::memory`.
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Int(i128),
+    Struct(Vec<Value>),
+    Tag(TagIdIntType, Vec<Value>),
+}
+
+impl Value {
+    fn int(&self) -> Result<i128, String> {
+        match self {
+            Value::Int(i) => Ok(*i),
+            _ => Err(format!("expected an integer, found {:?}", self)),
+        }
+    }
+}
+
+/// Refcount of a static allocation, which is never modified or freed.
+/// Like `REFCOUNT_MAX` in the Zig builtins.
+const STATIC_REFCOUNT: usize = usize::MAX;
+
+#[derive(Clone, Default)]
+struct Heap {
+    /// Refcount of each allocation, keyed by its data address
+    refcounts: HashMap<i128, usize>,
+    /// Value stored at each address, with the address of the allocation that owns it
+    memory: HashMap<i128, (i128, Value)>,
+    next_addr: i128,
+}
+
+impl Heap {
+    fn alloc(&mut self, values: Vec<Value>, stride: u32, refcount: usize) -> i128 {
+        self.next_addr += 0x10000;
+        let addr = self.next_addr;
+        for (i, value) in values.into_iter().enumerate() {
+            let elem_addr = addr + i as i128 * stride as i128;
+            self.memory.insert(elem_addr, (addr, value));
+        }
+        self.refcounts.insert(addr, refcount);
+        addr
+    }
+
+    fn load(&self, addr: i128) -> Result<Value, String> {
+        match self.memory.get(&addr) {
+            Some((owner, _)) if self.refcounts[owner] == 0 => {
+                Err(format!("use after free at {:#x}", addr))
+            }
+            Some((_, value)) => Ok(value.clone()),
+            None => self.load_refcount(addr),
+        }
+    }
+
+    /// The refcount word sits just before the data, encoded with isize::MIN as 1
+    fn load_refcount(&self, addr: i128) -> Result<Value, String> {
+        let data_addr = addr + TARGET_INFO.ptr_width() as i128;
+        match self.refcounts.get(&data_addr) {
+            Some(0) => Err(format!("refcount read after free at {:#x}", data_addr)),
+            Some(&STATIC_REFCOUNT) => Ok(Value::Int(REFCOUNT_MAX as i128)),
+            Some(rc) => Ok(Value::Int(isize::MIN as i128 + *rc as i128 - 1)),
+            None => Err(format!("load from invalid address {:#x}", addr)),
+        }
+    }
+
+    fn live_refcount(&mut self, addr: i128) -> Result<&mut usize, String> {
+        match self.refcounts.get_mut(&addr) {
+            Some(0) => Err(format!("refcount modified after free at {:#x}", addr)),
+            Some(rc) => Ok(rc),
+            None => Err(format!("refcount of unknown allocation {:#x}", addr)),
+        }
+    }
+}
+
+fn tag_field_layouts<'a>(
+    union_layout: &UnionLayout<'a>,
+    tag_id: TagIdIntType,
+) -> &'a [InLayout<'a>] {
+    match *union_layout {
+        UnionLayout::NonRecursive(tags) | UnionLayout::Recursive(tags) => tags[tag_id as usize],
+        UnionLayout::NonNullableUnwrapped(fields) => fields,
+        UnionLayout::NullableWrapped {
+            nullable_id,
+            other_tags,
+        } => other_tags[(tag_id - (tag_id > nullable_id) as TagIdIntType) as usize],
+        UnionLayout::NullableUnwrapped { other_fields, .. } => other_fields,
+    }
+}
+
+struct Interpreter<'a, 'p> {
+    procs: HashMap<Symbol, &'p Proc<'a>>,
+    heap: Heap,
+    fuel: usize,
+}
+
+impl<'a, 'p> Interpreter<'a, 'p> {
+    fn run(
+        &mut self,
+        mut stmt: &'p Stmt<'a>,
+        env: &mut HashMap<Symbol, Value>,
+    ) -> Result<Value, String> {
+        let mut joins = HashMap::new();
+        loop {
+            self.fuel = self.fuel.checked_sub(1).ok_or("out of fuel")?;
+            match stmt {
+                Stmt::Let(symbol, expr, _, next) => {
+                    let value = self.eval(expr, env)?;
+                    env.insert(*symbol, value);
+                    stmt = next;
+                }
+                Stmt::Switch {
+                    cond_symbol,
+                    branches,
+                    default_branch,
+                    ..
+                } => {
+                    let cond = env[cond_symbol].int()? as u64;
+                    stmt = match branches.iter().find(|(id, _, _)| *id == cond) {
+                        Some((_, _, branch)) => branch,
+                        None => default_branch.1,
+                    };
+                }
+                Stmt::Join {
+                    id,
+                    parameters,
+                    body,
+                    remainder,
+                } => {
+                    joins.insert(*id, (*parameters, *body));
+                    stmt = remainder;
+                }
+                Stmt::Jump(id, args) => {
+                    let (params, body): (&[Param], _) = joins[id];
+                    let values = Vec::from_iter(args.iter().map(|a| env[a].clone()));
+                    for (param, value) in params.iter().zip(values) {
+                        env.insert(param.symbol, value);
+                    }
+                    stmt = body;
+                }
+                Stmt::Ret(symbol) => return Ok(env[symbol].clone()),
+                _ => return Err(format!("unexpected statement {:?}", stmt)),
+            }
+        }
+    }
+
+    fn eval(&mut self, expr: &Expr<'a>, env: &HashMap<Symbol, Value>) -> Result<Value, String> {
+        let arg = |symbols: &[Symbol], i: usize| env[&symbols[i]].int();
+        let value = match expr {
+            Expr::Literal(Literal::Int(bytes)) => Value::Int(i128::from_ne_bytes(*bytes)),
+            Expr::Literal(Literal::Bool(b)) => Value::Int(*b as i128),
+            Expr::Struct(fields) => Value::Struct(fields.iter().map(|f| env[f].clone()).collect()),
+            Expr::NullPointer => Value::Int(0),
+            Expr::StructAtIndex {
+                index, structure, ..
+            } => match &env[structure] {
+                Value::Struct(fields) => fields[*index as usize].clone(),
+                other => return Err(format!("StructAtIndex on {:?}", other)),
+            },
+            Expr::GetTagId {
+                structure,
+                union_layout,
+            } => Value::Int(self.tag_id(&env[structure], union_layout)? as i128),
+            Expr::UnionAtIndex {
+                structure,
+                union_layout,
+                tag_id,
+                index,
+            } => {
+                let tag = match &env[structure] {
+                    tag @ Value::Tag(..) => tag.clone(),
+                    ptr => self.heap.load(ptr.int()?)?,
+                };
+                // Tags with identical fields can share a body that names only one of them
+                let same_fields = |id| {
+                    id == *tag_id
+                        || tag_field_layouts(union_layout, id)
+                            == tag_field_layouts(union_layout, *tag_id)
+                };
+                match tag {
+                    Value::Tag(id, fields) if same_fields(id) => fields[*index as usize].clone(),
+                    other => return Err(format!("UnionAtIndex {} on {:?}", tag_id, other)),
+                }
+            }
+            Expr::ExprUnbox { symbol } => self.heap.load(env[symbol].int()?)?,
+            Expr::Call(Call {
+                call_type: CallType::ByName { name, .. },
+                arguments,
+            }) => {
+                let proc = self.procs[&name.name()];
+                let mut callee_env = HashMap::from_iter(
+                    proc.args
+                        .iter()
+                        .zip(arguments.iter())
+                        .map(|((_, param), a)| (*param, env[a].clone())),
+                );
+                self.run(&proc.body, &mut callee_env)?
+            }
+            Expr::Call(Call {
+                call_type: CallType::LowLevel { op, .. },
+                arguments: args,
+            }) => match op {
+                PtrCast => Value::Int(arg(args, 0)?),
+                And => Value::Int(arg(args, 0)? & arg(args, 1)?),
+                NumAdd => Value::Int(arg(args, 0)? + arg(args, 1)?),
+                NumSubSaturated => Value::Int(arg(args, 0)? - arg(args, 1)?),
+                NumMul => Value::Int(arg(args, 0)? * arg(args, 1)?),
+                NumGte => Value::Int((arg(args, 0)? >= arg(args, 1)?) as i128),
+                Eq => Value::Int((arg(args, 0)? == arg(args, 1)?) as i128),
+                NotEq => Value::Int((arg(args, 0)? != arg(args, 1)?) as i128),
+                ListLen => match &env[&args[0]] {
+                    Value::Struct(fields) => fields[1].clone(),
+                    other => return Err(format!("ListLen on {:?}", other)),
+                },
+                // List operations, as used for work lists of node addresses
+                ListWithCapacity
+                | ListReserve
+                | ListAppendUnsafe
+                | ListGetUnsafe
+                | ListDropAt
+                | ListReleaseExcessCapacity => self.eval_list_op(*op, args, env)?,
+                // Like the builtin, a null pointer counts as unique
+                RefCountIsUnique => {
+                    let addr = arg(args, 0)?;
+                    Value::Int((addr == 0 || *self.heap.live_refcount(addr)? == 1) as i128)
+                }
+                Prefetch => Value::Struct(vec![]),
+                RefCountIncDataPtr => {
+                    let addr = arg(args, 0)?;
+                    if addr != 0 {
+                        let rc = self.heap.live_refcount(addr)?;
+                        if *rc != STATIC_REFCOUNT {
+                            *rc += arg(args, 1)? as usize;
+                        }
+                    }
+                    Value::Struct(vec![])
+                }
+                RefCountDecDataPtr => {
+                    let addr = arg(args, 0)?;
+                    if addr != 0 {
+                        let rc = self.heap.live_refcount(addr)?;
+                        if *rc != STATIC_REFCOUNT {
+                            *rc -= 1;
+                        }
+                    }
+                    Value::Struct(vec![])
+                }
+                _ => return Err(format!("unexpected lowlevel {:?}", op)),
+            },
+            _ => return Err(format!("unexpected expression {:?}", expr)),
+        };
+        Ok(value)
+    }
+
+    /// Lists of pointer-sized elements, stored as `{ elements, len, capacity }`
+    fn eval_list_op(
+        &mut self,
+        op: LowLevel,
+        args: &[Symbol],
+        env: &HashMap<Symbol, Value>,
+    ) -> Result<Value, String> {
+        let stride = TARGET_INFO.ptr_width() as i128;
+        let list = |i: usize| match &env[&args[i]] {
+            Value::Struct(fields) => Ok((fields[0].int()?, fields[1].int()?, fields[2].int()?)),
+            other => Err(format!("{:?} on {:?}", op, other)),
+        };
+        let list_value = |addr, len, cap| {
+            Value::Struct(vec![Value::Int(addr), Value::Int(len), Value::Int(cap)])
+        };
+        let value = match op {
+            ListWithCapacity => match env[&args[0]].int()? {
+                0 => list_value(0, 0, 0),
+                cap => list_value(self.heap.alloc(vec![], stride as u32, 1), 0, cap),
+            },
+            ListReserve => {
+                let (addr, len, cap) = list(0)?;
+                let spare = env[&args[1]].int()?;
+                if len + spare <= cap {
+                    list_value(addr, len, cap)
+                } else {
+                    let mut elems = Vec::new();
+                    for i in 0..len {
+                        elems.push(self.heap.load(addr + i * stride)?);
+                    }
+                    if addr != 0 {
+                        *self.heap.live_refcount(addr)? -= 1;
+                    }
+                    let new_addr = self.heap.alloc(elems, stride as u32, 1);
+                    list_value(new_addr, len, len + spare)
+                }
+            }
+            ListAppendUnsafe => {
+                let (addr, len, cap) = list(0)?;
+                if len >= cap {
+                    return Err(format!("append to full list at {:#x}", addr));
+                }
+                let elem = env[&args[1]].clone();
+                self.heap.memory.insert(addr + len * stride, (addr, elem));
+                list_value(addr, len + 1, cap)
+            }
+            ListGetUnsafe => {
+                let (addr, len, _) = list(0)?;
+                let index = env[&args[1]].int()?;
+                if index >= len {
+                    return Err(format!("index {} out of bounds for length {}", index, len));
+                }
+                self.heap.load(addr + index * stride)?
+            }
+            ListDropAt => {
+                // Only dropping the last element of a unique list is supported
+                let (addr, len, cap) = list(0)?;
+                if env[&args[1]].int()? != len - 1 || *self.heap.live_refcount(addr)? != 1 {
+                    return Err(format!("unsupported ListDropAt on list at {:#x}", addr));
+                }
+                list_value(addr, len - 1, cap)
+            }
+            ListReleaseExcessCapacity => {
+                let (addr, len, cap) = list(0)?;
+                if len != 0 {
+                    return Err(format!(
+                        "unsupported ListReleaseExcessCapacity at {:#x}",
+                        addr
+                    ));
+                }
+                if cap != 0 {
+                    *self.heap.live_refcount(addr)? -= 1;
+                }
+                list_value(0, 0, 0)
+            }
+            _ => return Err(format!("unexpected list operation {:?}", op)),
+        };
+        Ok(value)
+    }
+
+    fn tag_id(&self, value: &Value, union_layout: &UnionLayout) -> Result<TagIdIntType, String> {
+        match (value, union_layout) {
+            (Value::Tag(id, _), UnionLayout::NonRecursive(_)) => Ok(*id),
+            (Value::Int(0), UnionLayout::NullableWrapped { nullable_id, .. }) => Ok(*nullable_id),
+            (Value::Int(0), UnionLayout::NullableUnwrapped { nullable_id, .. }) => {
+                Ok(*nullable_id as TagIdIntType)
+            }
+            (Value::Int(addr), _) => match self.heap.load(*addr)? {
+                Value::Tag(id, _) => Ok(id),
+                other => Err(format!("GetTagId on {:?}", other)),
+            },
+            _ => Err(format!("GetTagId on {:?}", value)),
+        }
+    }
+}
+
+/// Build a random value of the given shape. Allocations get a refcount of 1 or 2 if `shared`.
+fn random_value<'a>(
+    shape: &Shape,
+    arena: &'a Bump,
+    interner: &mut STLayoutInterner<'a>,
+    rng: &mut Rng,
+    heap: &mut Heap,
+    shared: bool,
+) -> Value {
+    let mut refcount = |rng: &mut Rng| if shared { 1 + rng.below(2) as usize } else { 1 };
+    let ptr_width = TARGET_INFO.ptr_width() as u32;
+    match shape {
+        Shape::Int => Value::Int(rng.below(100) as i128),
+        Shape::Str => {
+            if rng.below(2) == 0 {
+                // Small string: the sign bit of the capacity word is set
+                Value::Struct(vec![Value::Int(0), Value::Int(0), Value::Int(-1)])
+            } else {
+                let rc = refcount(rng);
+                let addr = heap.alloc(vec![], 1, rc);
+                Value::Struct(vec![Value::Int(addr), Value::Int(30), Value::Int(30)])
+            }
+        }
+        Shape::List(elem) => {
+            let len = rng.below(3) as usize;
+            if len == 0 {
+                return Value::Struct(vec![Value::Int(0), Value::Int(0), Value::Int(0)]);
+            }
+            let elems = Vec::from_iter(
+                (0..len).map(|_| random_value(elem, arena, interner, rng, heap, shared)),
+            );
+            let elem_layout = elem.layout(arena, interner);
+            let stride = interner.stack_size(elem_layout);
+            let rc = refcount(rng);
+            let addr = heap.alloc(elems, stride, rc);
+            Value::Struct(vec![
+                Value::Int(addr),
+                Value::Int(len as _),
+                Value::Int(len as _),
+            ])
+        }
+        Shape::Struct(fields) => Value::Struct(
+            fields
+                .iter()
+                .map(|f| random_value(f, arena, interner, rng, heap, shared))
+                .collect(),
+        ),
+        Shape::Boxed(inner) => {
+            let inner = random_value(inner, arena, interner, rng, heap, shared);
+            let rc = refcount(rng);
+            Value::Int(heap.alloc(vec![inner], 0, rc))
+        }
+        Shape::Either(a, b) => {
+            let id = rng.below(2) as TagIdIntType;
+            let field = random_value(
+                if id == 0 { a } else { b },
+                arena,
+                interner,
+                rng,
+                heap,
+                shared,
+            );
+            Value::Tag(id, vec![field])
+        }
+        Shape::ConsList(elem) => {
+            let mut list = Value::Int(0);
+            for _ in 0..rng.below(4) {
+                let head = random_value(elem, arena, interner, rng, heap, shared);
+                let rc = refcount(rng);
+                list = Value::Int(heap.alloc(vec![Value::Tag(1, vec![head, list])], 0, rc));
+            }
+            list
+        }
+        Shape::Tree(elem) => {
+            fn tree<'a>(
+                depth: usize,
+                elem: &Shape,
+                arena: &'a Bump,
+                interner: &mut STLayoutInterner<'a>,
+                rng: &mut Rng,
+                heap: &mut Heap,
+                refcount: &mut impl FnMut(&mut Rng) -> usize,
+                shared: bool,
+            ) -> Value {
+                let node = if depth == 0 || rng.below(2) == 0 {
+                    let leaf = random_value(elem, arena, interner, rng, heap, shared);
+                    Value::Tag(0, vec![leaf])
+                } else {
+                    let mut child = || {
+                        tree(
+                            depth - 1,
+                            elem,
+                            arena,
+                            interner,
+                            rng,
+                            heap,
+                            refcount,
+                            shared,
+                        )
+                    };
+                    let (left, right) = (child(), child());
+                    Value::Tag(1, vec![left, right])
+                };
+                let rc = refcount(rng);
+                Value::Int(heap.alloc(vec![node], 0, rc))
+            }
+            tree(2, elem, arena, interner, rng, heap, &mut refcount, shared)
+        }
+        Shape::Rose(elem) => {
+            fn rose<'a>(
+                depth: usize,
+                elem: &Shape,
+                arena: &'a Bump,
+                interner: &mut STLayoutInterner<'a>,
+                rng: &mut Rng,
+                heap: &mut Heap,
+                refcount: &mut impl FnMut(&mut Rng) -> usize,
+                shared: bool,
+                ptr_width: u32,
+            ) -> Value {
+                let label = random_value(elem, arena, interner, rng, heap, shared);
+                let len = if depth == 0 { 0 } else { rng.below(3) as usize };
+                let children = if len == 0 {
+                    Value::Struct(vec![Value::Int(0), Value::Int(0), Value::Int(0)])
+                } else {
+                    let nodes = Vec::from_iter((0..len).map(|_| {
+                        rose(
+                            depth - 1,
+                            elem,
+                            arena,
+                            interner,
+                            rng,
+                            heap,
+                            refcount,
+                            shared,
+                            ptr_width,
+                        )
+                    }));
+                    let rc = refcount(rng);
+                    let addr = heap.alloc(nodes, ptr_width, rc);
+                    Value::Struct(vec![
+                        Value::Int(addr),
+                        Value::Int(len as _),
+                        Value::Int(len as _),
+                    ])
+                };
+                let rc = refcount(rng);
+                Value::Int(heap.alloc(vec![Value::Tag(0, vec![label, children])], 0, rc))
+            }
+            rose(
+                2,
+                elem,
+                arena,
+                interner,
+                rng,
+                heap,
+                &mut refcount,
+                shared,
+                ptr_width,
+            )
+        }
+    }
+}
+
+/// Expand a refcounting statement for `layout` and run it on `value`
+fn simulate<'a>(
+    arena: &'a Bump,
+    interner: &mut STLayoutInterner<'a>,
+    layout: InLayout<'a>,
+    modify: impl FnOnce(Symbol) -> ModifyRc,
+    value: &Value,
+    heap: Heap,
+) -> Result<Heap, String> {
+    simulate_with(arena, interner, layout, modify, value, heap, |_| {})
+}
+
+fn simulate_with<'a>(
+    arena: &'a Bump,
+    interner: &mut STLayoutInterner<'a>,
+    layout: InLayout<'a>,
+    modify: impl FnOnce(Symbol) -> ModifyRc,
+    value: &Value,
+    heap: Heap,
+    configure: impl FnOnce(&mut CodeGenHelp<'a>),
+) -> Result<Heap, String> {
+    let mut structure = Symbol::ARG_1;
+    let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+    let (stmt, mut help) = expand_with(
+        arena,
+        interner,
+        layout,
+        |s| {
+            structure = s;
+            modify(s)
+        },
+        following,
+        configure,
+    );
+    let procs = help.take_procs();
+    let mut interpreter = Interpreter {
+        procs: HashMap::from_iter(procs.iter().map(|p| (p.name.name(), p))),
+        heap,
+        fuel: 100_000,
+    };
+    let mut env = HashMap::from([
+        (structure, value.clone()),
+        (Symbol::ARG_1, Value::Struct(vec![])),
+    ]);
+    interpreter.run(stmt, &mut env)?;
+    Ok(interpreter.heap)
+}
+
+/// Dec with the default helpers, checking that self-recursive helpers would do the same
+fn simulate_dec<'a>(
+    arena: &'a Bump,
+    interner: &mut STLayoutInterner<'a>,
+    layout: InLayout<'a>,
+    value: &Value,
+    heap: Heap,
+) -> Result<Heap, String> {
+    let prefer_recursion = |help: &mut CodeGenHelp| help.set_rc_prefer_recursion(true);
+    let recursive = simulate_with(
+        arena,
+        interner,
+        layout,
+        ModifyRc::Dec,
+        value,
+        heap.clone(),
+        prefer_recursion,
+    )?;
+    let use_work_list = |help: &mut CodeGenHelp| help.set_rc_work_list(true);
+    let work_list = simulate_with(
+        arena,
+        interner,
+        layout,
+        ModifyRc::Dec,
+        value,
+        heap.clone(),
+        use_work_list,
+    )?;
+    let heap = simulate(arena, interner, layout, ModifyRc::Dec, value, heap)?;
+    if recursive.refcounts != heap.refcounts {
+        return Err("Dec with self-recursive helpers disagrees with the default helpers".into());
+    }
+
+    // The work list helpers make extra allocations of their own, which must all be freed
+    for (addr, rc) in work_list.refcounts.iter() {
+        if *rc != heap.refcounts.get(addr).copied().unwrap_or(0) {
+            return Err("Dec with work list helpers disagrees with the default helpers".into());
+        }
+    }
+    Ok(heap)
+}
+
+fn check_shape(shape: &Shape, seed: u64) -> Result<(), String> {
+    let mut rng = Rng(seed | 1);
+    for _ in 0..4 {
+        let arena = &Bump::new();
+        let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+        let layout = shape.layout(arena, &mut interner);
+
+        // Inc followed by Dec must leave every refcount where it started
+        let mut heap = Heap::default();
+        let value = random_value(shape, arena, &mut interner, &mut rng, &mut heap, true);
+        let before = heap.refcounts.clone();
+        let inc = |s| ModifyRc::Inc(s, 1);
+        let heap = simulate(arena, &mut interner, layout, inc, &value, heap)?;
+        let heap = simulate_dec(arena, &mut interner, layout, &value, heap)?;
+        for (addr, rc) in before.iter() {
+            if heap.refcounts[addr] != *rc {
+                return Err(format!(
+                    "Inc then Dec changed the refcount at {:#x} from {} to {}",
+                    addr, rc, heap.refcounts[addr]
+                ));
+            }
+        }
+
+        // Dec on a uniquely-owned value must free everything it owns
+        let mut heap = Heap::default();
+        let value = random_value(shape, arena, &mut interner, &mut rng, &mut heap, false);
+        let heap = simulate_dec(arena, &mut interner, layout, &value, heap)?;
+        if let Some((addr, rc)) = heap.refcounts.iter().find(|(_, rc)| **rc != 0) {
+            return Err(format!("Dec leaked {:#x} with refcount {}", addr, rc));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn dec_cons_list_of_ints_frees_each_node_once() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // [Nil, Cons I64 next]. The payload has nothing to refcount, only the node itself.
+    let layout = cons_list(arena, &mut interner);
+    for prefer_recursion in [false, true] {
+        let configure = |help: &mut CodeGenHelp| help.set_rc_prefer_recursion(prefer_recursion);
+        let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+        let (_, mut help) = expand_with(
+            arena,
+            &mut interner,
+            layout,
+            ModifyRc::Dec,
+            following,
+            configure,
+        );
+        let procs = help.take_procs();
+        assert_eq!(procs.len(), 1);
+        assert_eq!(count_lowlevel(&procs[0].body, RefCountDecDataPtr), 1);
+
+        // Freeing a node twice would be an error from the heap
+        let mut heap = Heap::default();
+        let mut next = 0;
+        let mut nodes = Vec::new();
+        for i in 0..4 {
+            let node = Value::Tag(1, vec![Value::Int(i), Value::Int(next)]);
+            next = heap.alloc(vec![node], 0, 1);
+            nodes.push(next);
+        }
+        let heap = simulate_with(
+            arena,
+            &mut interner,
+            layout,
+            ModifyRc::Dec,
+            &Value::Int(next),
+            heap,
+            configure,
+        )
+        .unwrap();
+        assert!(nodes.iter().all(|addr| heap.refcounts[addr] == 0));
+    }
+}
+
+#[test]
+fn dec_node_with_optional_child() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // Node : { value : I64, child : [None, Some Node] }
+    // The recursion is through the nullable union, whose payload is the Node record.
+    let rec_ptr = interner.insert(Layout::RecursivePointer(Layout::VOID));
+    let node_fields = interner.insert(Layout::struct_no_name_order(
+        arena.alloc([Layout::I64, rec_ptr]),
+    ));
+    let child = interner.insert_recursive(
+        arena,
+        Layout::Union(UnionLayout::NullableUnwrapped {
+            nullable_id: false,
+            other_fields: arena.alloc([node_fields]),
+        }),
+    );
+    let node = interner.insert(Layout::struct_no_name_order(
+        arena.alloc([Layout::I64, child]),
+    ));
+
+    let some = |heap: &mut Heap, value, child, rc| {
+        let record = Value::Struct(vec![Value::Int(value), Value::Int(child)]);
+        heap.alloc(vec![Value::Tag(1, vec![record])], 0, rc)
+    };
+    let mut run = |middle_rc: usize| {
+        let mut heap = Heap::default();
+        let last = some(&mut heap, 3, 0, 1);
+        let middle = some(&mut heap, 2, last, middle_rc);
+        let first = some(&mut heap, 1, middle, 1);
+        let value = Value::Struct(vec![Value::Int(0), Value::Int(first)]);
+        let heap = simulate_dec(arena, &mut interner, node, &value, heap).unwrap();
+        [first, middle, last].map(|addr| heap.refcounts[&addr])
+    };
+
+    // Every child is freed, and the None at the end is skipped
+    assert_eq!(run(1), [0, 0, 0]);
+
+    // A shared child keeps its own children alive
+    assert_eq!(run(2), [0, 1, 1]);
+
+    // A node with no child has nothing to free
+    let heap = simulate_dec(
+        arena,
+        &mut interner,
+        node,
+        &Value::Struct(vec![Value::Int(0), Value::Int(0)]),
+        Heap::default(),
+    )
+    .unwrap();
+    assert!(heap.refcounts.is_empty());
+}
+
+#[test]
+fn dec_tailrec_stops_at_null_link() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // The tail of a cons list is nullable. The loop ends when it reaches the Nil link.
+    let cons_list = Shape::ConsList(Box::new(Shape::Int)).layout(arena, &mut interner);
+    let cons = |heap: &mut Heap, head, tail, rc| {
+        let node = Value::Tag(1, vec![Value::Int(head), Value::Int(tail)]);
+        heap.alloc(vec![node], 0, rc)
+    };
+
+    let mut heap = Heap::default();
+    let third = cons(&mut heap, 3, 0, 1);
+    let second = cons(&mut heap, 2, third, 1);
+    let first = cons(&mut heap, 1, second, 1);
+    let heap = simulate(
+        arena,
+        &mut interner,
+        cons_list,
+        ModifyRc::Dec,
+        &Value::Int(first),
+        heap,
+    )
+    .unwrap();
+    assert!(heap.refcounts.values().all(|rc| *rc == 0));
+
+    // A shared link also ends the loop, with a null pointer as the sentinel
+    let mut heap = Heap::default();
+    let third = cons(&mut heap, 3, 0, 1);
+    let second = cons(&mut heap, 2, third, 2);
+    let first = cons(&mut heap, 1, second, 1);
+    let heap = simulate(
+        arena,
+        &mut interner,
+        cons_list,
+        ModifyRc::Dec,
+        &Value::Int(first),
+        heap,
+    )
+    .unwrap();
+    assert_eq!(heap.refcounts[&first], 0);
+    assert_eq!(heap.refcounts[&second], 1);
+    assert_eq!(heap.refcounts[&third], 1);
+
+    // A non-nullable union never has a null field, but a tag without a tail-recursive
+    // field still exits the loop through the same null check.
+    let tree = Shape::Tree(Box::new(Shape::Int)).layout(arena, &mut interner);
+    let mut heap = Heap::default();
+    let left = heap.alloc(vec![Value::Tag(0, vec![Value::Int(1)])], 0, 1);
+    let right = heap.alloc(vec![Value::Tag(0, vec![Value::Int(2)])], 0, 1);
+    let node = Value::Tag(1, vec![Value::Int(left), Value::Int(right)]);
+    let root = heap.alloc(vec![node], 0, 1);
+    let heap = simulate(
+        arena,
+        &mut interner,
+        tree,
+        ModifyRc::Dec,
+        &Value::Int(root),
+        heap,
+    )
+    .unwrap();
+    assert!(heap.refcounts.values().all(|rc| *rc == 0));
+}
+
+#[test]
+fn dec_tree_loops_on_left_and_recurses_on_right() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // Tree : [Leaf I64, Node Tree Tree]
+    let tree = Shape::Tree(Box::new(Shape::Int)).layout(arena, &mut interner);
+    let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+    let (_, mut help) = expand(arena, &mut interner, tree, ModifyRc::Dec, following);
+    let procs = help.take_procs();
+    let dec = procs.iter().find(|p| p.args[0].0 == tree).unwrap();
+
+    // The first recursive field continues the loop. The second is a recursive call.
+    let mut field_indices = HashMap::new();
+    let mut recursive_call_args = Vec::new();
+    walk(&dec.body, &mut |s| match s {
+        Stmt::Let(sym, Expr::UnionAtIndex { index, .. }, _, _) => {
+            field_indices.insert(*sym, *index);
+        }
+        Stmt::Let(
+            _,
+            Expr::Call(Call {
+                call_type: CallType::ByName { name, .. },
+                arguments,
+            }),
+            _,
+            _,
+        ) if *name == dec.name => recursive_call_args.push(arguments[0]),
+        _ => {}
+    });
+    assert_eq!(recursive_call_args.len(), 1);
+    assert_eq!(field_indices[&recursive_call_args[0]], 1);
+    assert_eq!(field_indices.values().filter(|i| **i == 0).count(), 1);
+
+    // Both spines are freed, whichever way they are handled
+    let leaf = |heap: &mut Heap, x| heap.alloc(vec![Value::Tag(0, vec![Value::Int(x)])], 0, 1);
+    let node = |heap: &mut Heap, left, right| {
+        let tag = Value::Tag(1, vec![Value::Int(left), Value::Int(right)]);
+        heap.alloc(vec![tag], 0, 1)
+    };
+    let mut heap = Heap::default();
+    let mut left_spine = leaf(&mut heap, 0);
+    let mut right_spine = leaf(&mut heap, 0);
+    for i in 1..5 {
+        let right_leaf = leaf(&mut heap, i);
+        left_spine = node(&mut heap, left_spine, right_leaf);
+        let left_leaf = leaf(&mut heap, i);
+        right_spine = node(&mut heap, left_leaf, right_spine);
+    }
+    let root = node(&mut heap, left_spine, right_spine);
+    let heap = simulate(
+        arena,
+        &mut interner,
+        tree,
+        ModifyRc::Dec,
+        &Value::Int(root),
+        heap,
+    )
+    .unwrap();
+    assert!(heap.refcounts.values().all(|rc| *rc == 0));
+}
+
+#[test]
+fn dec_gets_tag_id_only_when_unique() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+    let tree = Shape::Tree(Box::new(Shape::Int)).layout(arena, &mut interner);
+    let list = cons_list(arena, &mut interner);
+
+    let configs: [fn(&mut CodeGenHelp); 3] = [
+        |help| help.set_rc_prefer_recursion(true),
+        |help| help.set_rc_work_list(true),
+        |_| {},
+    ];
+    for layout in [tree, list] {
+        for configure in configs {
+            let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+            let (_, mut help) = expand_with(
+                arena,
+                &mut interner,
+                layout,
+                ModifyRc::Dec,
+                following,
+                configure,
+            );
+            let procs = help.take_procs();
+            let is_tag_id = |expr: &Expr| matches!(expr, Expr::GetTagId { .. });
+
+            // Every GetTagId is in the `then` branch of the uniqueness check
+            let mut is_unique_syms = Vec::new();
+            walk(&procs[0].body, &mut |s| {
+                if let Stmt::Let(sym, expr, _, _) = s {
+                    if matches!(
+                        expr,
+                        Expr::Call(Call {
+                            call_type: CallType::LowLevel {
+                                op: RefCountIsUnique,
+                                ..
+                            },
+                            ..
+                        })
+                    ) {
+                        is_unique_syms.push(*sym);
+                    }
+                }
+            });
+            let mut when_unique = 0;
+            walk(&procs[0].body, &mut |s| {
+                if let Stmt::Switch {
+                    cond_symbol,
+                    branches,
+                    ..
+                } = s
+                {
+                    if is_unique_syms.contains(cond_symbol) {
+                        when_unique += count_exprs(&branches[0].2, is_tag_id);
+                    }
+                }
+            });
+            assert_eq!(is_unique_syms.len(), 1);
+            assert_eq!(when_unique, 1);
+            assert_eq!(count_exprs(&procs[0].body, is_tag_id), 1);
+        }
+    }
+}
+
+#[test]
+fn dec_tree_with_work_list() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // Tree : [Leaf I64, Node Tree Tree]
+    let tree = Shape::Tree(Box::new(Shape::Int)).layout(arena, &mut interner);
+    let configure = |help: &mut CodeGenHelp| help.set_rc_work_list(true);
+    let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+    let (_, mut help) = expand_with(
+        arena,
+        &mut interner,
+        tree,
+        ModifyRc::Dec,
+        following,
+        configure,
+    );
+    let procs = help.take_procs();
+    assert_eq!(procs.len(), 1);
+    let dec = &procs[0];
+
+    // Both children are pushed, and one node is popped per iteration. No recursive calls.
+    let mut loops = 0;
+    walk(&dec.body, &mut |s| {
+        if let Stmt::Join { id, body, .. } = s {
+            walk(body, &mut |inner| {
+                loops += matches!(inner, Stmt::Jump(target, _) if target == id) as usize;
+            });
+        }
+    });
+    assert_eq!(loops, 1);
+    assert_eq!(count_lowlevel(&dec.body, ListAppendUnsafe), 2);
+    assert_eq!(count_lowlevel(&dec.body, ListDropAt), 1);
+    assert_eq!(count_calls_by_name(&dec.body), 0);
+
+    // Both spines are freed, along with the work list
+    let leaf = |heap: &mut Heap, x| heap.alloc(vec![Value::Tag(0, vec![Value::Int(x)])], 0, 1);
+    let node = |heap: &mut Heap, left, right, rc| {
+        let tag = Value::Tag(1, vec![Value::Int(left), Value::Int(right)]);
+        heap.alloc(vec![tag], 0, rc)
+    };
+    let mut heap = Heap::default();
+    let mut left_spine = leaf(&mut heap, 0);
+    let mut right_spine = leaf(&mut heap, 0);
+    for i in 1..5 {
+        let right_leaf = leaf(&mut heap, i);
+        left_spine = node(&mut heap, left_spine, right_leaf, 1);
+        let left_leaf = leaf(&mut heap, i);
+        right_spine = node(&mut heap, left_leaf, right_spine, 1);
+    }
+
+    // A shared subtree is only decremented
+    let shared = node(&mut heap, left_spine, right_spine, 2);
+    let root = node(&mut heap, shared, shared, 1);
+    let heap = simulate_with(
+        arena,
+        &mut interner,
+        tree,
+        ModifyRc::Dec,
+        &Value::Int(root),
+        heap,
+        configure,
+    )
+    .unwrap();
+    assert_eq!(heap.refcounts[&root], 0);
+    assert_eq!(heap.refcounts[&shared], 0);
+    assert!(heap.refcounts.values().all(|rc| *rc == 0));
+}
+
+#[test]
+fn dec_tailrec_follows_lone_next_field() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // Chain : [Nil, Link Chain], where the non-null tag holds nothing but `next`
+    let rec_ptr = interner.insert(Layout::RecursivePointer(Layout::VOID));
+    let other_tags: &[&[InLayout]] = arena.alloc([arena.alloc([rec_ptr]) as &[_]]);
+    let chain = interner.insert_recursive(
+        arena,
+        Layout::Union(UnionLayout::NullableWrapped {
+            nullable_id: 0,
+            other_tags,
+        }),
+    );
+
+    // The helper loops over the `next` field instead of calling itself
+    let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+    let (_, mut help) = expand(arena, &mut interner, chain, ModifyRc::Dec, following);
+    let procs = help.take_procs();
+    let dec = procs.iter().find(|p| p.args[0].0 == chain).unwrap();
+    assert_eq!(count_calls_by_name(&dec.body), 0);
+    assert_eq!(
+        count_exprs(&dec.body, |e| matches!(
+            e,
+            Expr::UnionAtIndex { index: 0, .. }
+        )),
+        1
+    );
+
+    // Every link is freed, and the loop ends at the Nil tag
+    let mut heap = Heap::default();
+    let mut next = 0;
+    let mut links = Vec::new();
+    for _ in 0..5 {
+        next = heap.alloc(vec![Value::Tag(1, vec![Value::Int(next)])], 0, 1);
+        links.push(next);
+    }
+    let heap = simulate(
+        arena,
+        &mut interner,
+        chain,
+        ModifyRc::Dec,
+        &Value::Int(next),
+        heap,
+    )
+    .unwrap();
+    assert!(links.iter().all(|addr| heap.refcounts[addr] == 0));
+}
+
+#[test]
+fn str_helper_shared_between_recursive_tags() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // Doc : [Text Str, Line Str Doc, Indent I64 Str Doc]
+    let rec_ptr = interner.insert(Layout::RecursivePointer(Layout::VOID));
+    let tags: &[&[InLayout]] = arena.alloc([
+        arena.alloc([Layout::STR]) as &[_],
+        arena.alloc([Layout::STR, rec_ptr]),
+        arena.alloc([Layout::I64, Layout::STR, rec_ptr]),
+    ]);
+    let doc = interner.insert_recursive(arena, Layout::Union(UnionLayout::Recursive(tags)));
+
+    let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+    let (_, mut help) = expand(arena, &mut interner, doc, ModifyRc::Dec, following);
+    let procs = help.take_procs();
+    let str_helpers = procs.iter().filter(|p| p.args[0].0 == Layout::STR).count();
+    assert_eq!(str_helpers, 1);
+
+    // Every tag's Str field calls that one helper
+    let dec = procs.iter().find(|p| p.args[0].0 == doc).unwrap();
+    let str_helper = procs.iter().find(|p| p.args[0].0 == Layout::STR).unwrap();
+    let calls_to_str_helper = count_exprs(&dec.body, |e| {
+        matches!(
+            e,
+            Expr::Call(Call {
+                call_type: CallType::ByName { name, .. },
+                ..
+            }) if *name == str_helper.name
+        )
+    });
+    assert_eq!(calls_to_str_helper, 3);
+}
+
+#[test]
+fn dec_recursive_pointer_in_wrapper_struct() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // Node : [Leaf I64, Wrap { next : Node }]
+    let rec_ptr = interner.insert(Layout::RecursivePointer(Layout::VOID));
+    let wrapper = interner.insert(Layout::struct_no_name_order(arena.alloc([rec_ptr])));
+    let tags: &[&[InLayout]] =
+        arena.alloc([arena.alloc([Layout::I64]) as &[_], arena.alloc([wrapper])]);
+    let node = interner.insert_recursive(arena, Layout::Union(UnionLayout::Recursive(tags)));
+
+    // The wrapper's helper takes the pointer, and calls back into the Node helper
+    let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+    let (_, mut help) = expand(arena, &mut interner, node, ModifyRc::Dec, following);
+    let procs = help.take_procs();
+    assert_eq!(procs.len(), 2);
+    let node_helper = procs.iter().find(|p| p.args[0].0 == node).unwrap();
+    let wrapper_helper = procs.iter().find(|p| p.args[0].0 != node).unwrap();
+    assert!(matches!(
+        interner.get(wrapper_helper.args[0].0),
+        Layout::Struct { .. }
+    ));
+    let calls_to_node_helper = count_exprs(&wrapper_helper.body, |e| {
+        matches!(
+            e,
+            Expr::Call(Call {
+                call_type: CallType::ByName { name, .. },
+                ..
+            }) if *name == node_helper.name
+        )
+    });
+    assert_eq!(calls_to_node_helper, 1);
+
+    let mut heap = Heap::default();
+    let mut next = heap.alloc(vec![Value::Tag(0, vec![Value::Int(5)])], 0, 1);
+    let mut nodes = vec![next];
+    for _ in 0..3 {
+        let wrapped = Value::Struct(vec![Value::Int(next)]);
+        next = heap.alloc(vec![Value::Tag(1, vec![wrapped])], 0, 1);
+        nodes.push(next);
+    }
+    let heap = simulate(
+        arena,
+        &mut interner,
+        node,
+        ModifyRc::Dec,
+        &Value::Int(next),
+        heap,
+    )
+    .unwrap();
+    assert!(nodes.iter().all(|addr| heap.refcounts[addr] == 0));
+}
+
+#[test]
+fn dec_recursive_pointer_nested_in_structs() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // Node : [Nil, Node { meta : { next : Node } }]
+    let rec_ptr = interner.insert(Layout::RecursivePointer(Layout::VOID));
+    let inner = interner.insert(Layout::struct_no_name_order(arena.alloc([rec_ptr])));
+    let meta = interner.insert(Layout::struct_no_name_order(arena.alloc([inner])));
+    let other_tags: &[&[InLayout]] = arena.alloc([arena.alloc([meta]) as &[_]]);
+    let node = interner.insert_recursive(
+        arena,
+        Layout::Union(UnionLayout::NullableWrapped {
+            nullable_id: 0,
+            other_tags,
+        }),
+    );
+
+    // Both structs get helpers, and the pointer inside them resolves back to the Node union
+    let (_, dec) = summarise(arena, &mut interner, node, ModifyRc::Dec);
+    assert_eq!(dec.helper_args.len(), 3);
+    assert_eq!(dec.helper_args[0], node);
+    for arg in dec.helper_args[1..].iter() {
+        assert!(matches!(interner.get(*arg), Layout::Struct { .. }));
+    }
+
+    let mut heap = Heap::default();
+    let mut next = 0;
+    let mut links = Vec::new();
+    for _ in 0..3 {
+        let meta = Value::Struct(vec![Value::Struct(vec![Value::Int(next)])]);
+        next = heap.alloc(vec![Value::Tag(1, vec![meta])], 0, 1);
+        links.push(next);
+    }
+    let heap = simulate(
+        arena,
+        &mut interner,
+        node,
+        ModifyRc::Dec,
+        &Value::Int(next),
+        heap,
+    )
+    .unwrap();
+    assert!(links.iter().all(|addr| heap.refcounts[addr] == 0));
+}
+
+#[test]
+fn dec_nullable_wrapped_null_reads_no_fields() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // Expr : [Nil, Num I64, Add Expr Expr]
+    let rec_ptr = interner.insert(Layout::RecursivePointer(Layout::VOID));
+    let other_tags: &[&[InLayout]] = arena.alloc([
+        arena.alloc([Layout::I64]) as &[_],
+        arena.alloc([rec_ptr, rec_ptr]),
+    ]);
+    let expr = interner.insert_recursive(
+        arena,
+        Layout::Union(UnionLayout::NullableWrapped {
+            nullable_id: 0,
+            other_tags,
+        }),
+    );
+
+    // The null tag's branch returns without loading any fields
+    let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+    let (_, mut help) = expand(arena, &mut interner, expr, ModifyRc::Dec, following);
+    let procs = help.take_procs();
+    let dec = procs.iter().find(|p| p.args[0].0 == expr).unwrap();
+    let mut tag_id_syms = Vec::new();
+    walk(&dec.body, &mut |s| {
+        if let Stmt::Let(sym, Expr::GetTagId { .. }, _, _) = s {
+            tag_id_syms.push(*sym);
+        }
+    });
+    let mut null_branches = 0;
+    walk(&dec.body, &mut |s| {
+        if let Stmt::Switch {
+            cond_symbol,
+            branches,
+            ..
+        } = s
+        {
+            if tag_id_syms.contains(cond_symbol) {
+                for (_, _, branch) in branches.iter().filter(|(id, _, _)| *id == 0) {
+                    null_branches += 1;
+                    assert_eq!(
+                        count_exprs(branch, |e| matches!(e, Expr::UnionAtIndex { .. })),
+                        0
+                    );
+                }
+            }
+        }
+    });
+    assert_eq!(null_branches, 1);
+
+    // Dec of null itself touches no memory, in every kind of helper
+    let heap = simulate_dec(arena, &mut interner, expr, &Value::Int(0), Heap::default()).unwrap();
+    assert!(heap.refcounts.is_empty());
+
+    // Add (Num 1) Nil, where the null child is reached from a non-null node
+    let mut heap = Heap::default();
+    let num = heap.alloc(vec![Value::Tag(1, vec![Value::Int(1)])], 0, 1);
+    let add = heap.alloc(
+        vec![Value::Tag(2, vec![Value::Int(num), Value::Int(0)])],
+        0,
+        1,
+    );
+    let heap = simulate_dec(arena, &mut interner, expr, &Value::Int(add), heap).unwrap();
+    assert!(heap.refcounts.values().all(|rc| *rc == 0));
+}
+
+#[test]
+fn dec_nullable_wrapped_with_null_in_the_middle() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // [A Str, B Self, Null, C I64 Self], where the null tag has id 2
+    let rec_ptr = interner.insert(Layout::RecursivePointer(Layout::VOID));
+    let other_tags: &[&[InLayout]] = arena.alloc([
+        arena.alloc([Layout::STR]) as &[_],
+        arena.alloc([rec_ptr]),
+        arena.alloc([Layout::I64, rec_ptr]),
+    ]);
+    let layout = interner.insert_recursive(
+        arena,
+        Layout::Union(UnionLayout::NullableWrapped {
+            nullable_id: 2,
+            other_tags,
+        }),
+    );
+
+    let configs: [fn(&mut CodeGenHelp); 3] = [
+        |help| help.set_rc_prefer_recursion(true),
+        |help| help.set_rc_work_list(true),
+        |_| {},
+    ];
+    for configure in configs {
+        let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+        let (_, mut help) = expand_with(
+            arena,
+            &mut interner,
+            layout,
+            ModifyRc::Dec,
+            following,
+            configure,
+        );
+        let procs = help.take_procs();
+        let dec = procs.iter().find(|p| p.args[0].0 == layout).unwrap();
+
+        // The tag switch has a branch for each id, and only the null branch reads no fields
+        let mut tag_id_syms = Vec::new();
+        let mut field_tag_ids = Vec::new();
+        walk(&dec.body, &mut |s| match s {
+            Stmt::Let(sym, Expr::GetTagId { .. }, _, _) => tag_id_syms.push(*sym),
+            Stmt::Let(_, Expr::UnionAtIndex { tag_id, .. }, _, _) => field_tag_ids.push(*tag_id),
+            _ => {}
+        });
+        field_tag_ids.sort_unstable();
+        field_tag_ids.dedup();
+        assert_eq!(field_tag_ids, [0, 1, 3]);
+
+        let mut branch_ids = Vec::new();
+        walk(&dec.body, &mut |s| {
+            if let Stmt::Switch {
+                cond_symbol,
+                branches,
+                default_branch,
+                ..
+            } = s
+            {
+                if tag_id_syms.contains(cond_symbol) {
+                    for (id, _, branch) in branches.iter() {
+                        let reads = count_exprs(branch, |e| matches!(e, Expr::UnionAtIndex { .. }));
+                        assert_eq!(*id == 2, reads == 0, "tag {}", id);
+                        branch_ids.push(*id);
+                    }
+                    assert!(
+                        count_exprs(default_branch.1, |e| matches!(
+                            e,
+                            Expr::UnionAtIndex { tag_id: 3, .. }
+                        )) > 0
+                    );
+                }
+            }
+        });
+        branch_ids.sort_unstable();
+        assert_eq!(branch_ids, [0, 1, 2]);
+    }
+
+    // C 1 (B (A "name")) and C 2 (B Null), which reach both kinds of leaf
+    let mut heap = Heap::default();
+    let name = heap.alloc(vec![], 1, 1);
+    let name_value = Value::Struct(vec![Value::Int(name), Value::Int(30), Value::Int(30)]);
+    let a = heap.alloc(vec![Value::Tag(0, vec![name_value])], 0, 1);
+    let b1 = heap.alloc(vec![Value::Tag(1, vec![Value::Int(a)])], 0, 1);
+    let c1 = heap.alloc(
+        vec![Value::Tag(3, vec![Value::Int(1), Value::Int(b1)])],
+        0,
+        1,
+    );
+    let b2 = heap.alloc(vec![Value::Tag(1, vec![Value::Int(0)])], 0, 1);
+    let c2 = heap.alloc(
+        vec![Value::Tag(3, vec![Value::Int(2), Value::Int(b2)])],
+        0,
+        1,
+    );
+    let heap = simulate_dec(arena, &mut interner, layout, &Value::Int(c1), heap).unwrap();
+    let heap = simulate_dec(arena, &mut interner, layout, &Value::Int(c2), heap).unwrap();
+    assert!(heap.refcounts.values().all(|rc| *rc == 0));
+}
+
+#[test]
+fn dec_recursive_pointer_in_tuple() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // Node : [Leaf I64, Pair (I64, Node)]
+    let rec_ptr = interner.insert(Layout::RecursivePointer(Layout::VOID));
+    let tuple = interner.insert(Layout::struct_no_name_order(
+        arena.alloc([Layout::I64, rec_ptr]),
+    ));
+    let tags: &[&[InLayout]] =
+        arena.alloc([arena.alloc([Layout::I64]) as &[_], arena.alloc([tuple])]);
+    let node = interner.insert_recursive(arena, Layout::Union(UnionLayout::Recursive(tags)));
+
+    // The tuple gets its own helper, which calls back into the Node helper for the child
+    let (_, dec) = summarise(arena, &mut interner, node, ModifyRc::Dec);
+    assert_eq!(dec.helper_args.len(), 2);
+    assert_eq!(dec.helper_args[0], node);
+    assert!(matches!(
+        interner.get(dec.helper_args[1]),
+        Layout::Struct { .. }
+    ));
+
+    // Pair (1, Pair (2, Leaf 3)), with a shared innermost Pair
+    let mut heap = Heap::default();
+    let leaf = heap.alloc(vec![Value::Tag(0, vec![Value::Int(3)])], 0, 1);
+    let pair = |heap: &mut Heap, x, next, rc| {
+        let tuple = Value::Struct(vec![Value::Int(x), Value::Int(next)]);
+        heap.alloc(vec![Value::Tag(1, vec![tuple])], 0, rc)
+    };
+    let inner = pair(&mut heap, 2, leaf, 2);
+    let outer = pair(&mut heap, 1, inner, 1);
+    let heap = simulate_dec(arena, &mut interner, node, &Value::Int(outer), heap).unwrap();
+    assert_eq!(heap.refcounts[&outer], 0);
+    assert_eq!(heap.refcounts[&inner], 1);
+    assert_eq!(heap.refcounts[&leaf], 1);
+
+    // Once the last reference goes, the rest of the chain is freed too
+    let heap = simulate_dec(arena, &mut interner, node, &Value::Int(inner), heap).unwrap();
+    assert!(heap.refcounts.values().all(|rc| *rc == 0));
+}
+
+#[test]
+fn dec_nullable_unwrapped_like_optional_box() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // [None, Some Str], with no recursion
+    let layout = interner.insert(Layout::Union(UnionLayout::NullableUnwrapped {
+        nullable_id: false,
+        other_fields: arena.alloc([Layout::STR]),
+    }));
+
+    // One helper for the union and one for the string
+    let (_, dec) = summarise(arena, &mut interner, layout, ModifyRc::Dec);
+    assert_same_layouts(&dec.helper_args, &[layout, Layout::STR]);
+    assert_eq!(dec.unique_checks, 1);
+
+    // Some "name" frees both the string and the node
+    let mut heap = Heap::default();
+    let name = heap.alloc(vec![], 1, 1);
+    let name_value = Value::Struct(vec![Value::Int(name), Value::Int(30), Value::Int(30)]);
+    let some = heap.alloc(vec![Value::Tag(1, vec![name_value])], 0, 1);
+    let heap = simulate_dec(arena, &mut interner, layout, &Value::Int(some), heap).unwrap();
+    assert_eq!(heap.refcounts[&some], 0);
+    assert_eq!(heap.refcounts[&name], 0);
+
+    // None touches no memory
+    let heap = simulate_dec(
+        arena,
+        &mut interner,
+        layout,
+        &Value::Int(0),
+        Heap::default(),
+    )
+    .unwrap();
+    assert!(heap.refcounts.is_empty());
+    assert!(heap.memory.is_empty());
+}
+
+#[test]
+fn dec_tailrec_with_zero_one_and_two_recursive_fields() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // Expr : [Lit I64, Add Expr Expr, Neg Expr]
+    let rec_ptr = interner.insert(Layout::RecursivePointer(Layout::VOID));
+    let tags: &[&[InLayout]] = arena.alloc([
+        arena.alloc([Layout::I64]) as &[_],
+        arena.alloc([rec_ptr, rec_ptr]),
+        arena.alloc([rec_ptr]),
+    ]);
+    let expr = interner.insert_recursive(arena, Layout::Union(UnionLayout::Recursive(tags)));
+
+    let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+    let (_, mut help) = expand(arena, &mut interner, expr, ModifyRc::Dec, following);
+    let procs = help.take_procs();
+    assert_eq!(procs.len(), 1);
+    let dec = &procs[0];
+    let mut loops = 0;
+    walk(&dec.body, &mut |s| {
+        if let Stmt::Join { id, body, .. } = s {
+            walk(body, &mut |inner| {
+                loops += matches!(inner, Stmt::Jump(target, _) if target == id) as usize;
+            });
+        }
+    });
+    assert_eq!(loops, 1);
+
+    // Lit passes null as the next node, which ends the loop. Add calls the helper
+    // for one child and loops on the other. Neg loops on its only child.
+    let mut tag_id_sym = None;
+    walk(&dec.body, &mut |s| {
+        if let Stmt::Let(sym, Expr::GetTagId { .. }, _, _) = s {
+            tag_id_sym = Some(*sym);
+        }
+    });
+    let mut per_tag = Vec::new();
+    walk(&dec.body, &mut |s| {
+        if let Stmt::Switch {
+            cond_symbol,
+            branches,
+            default_branch,
+            ..
+        } = s
+        {
+            if Some(*cond_symbol) == tag_id_sym {
+                let all = branches
+                    .iter()
+                    .map(|(id, _, b)| (*id, b))
+                    .chain([(2, default_branch.1)]);
+                for (id, branch) in all {
+                    let nulls = count_exprs(branch, |e| matches!(e, Expr::NullPointer));
+                    let loads = count_exprs(branch, |e| matches!(e, Expr::UnionAtIndex { .. }));
+                    per_tag.push((id, nulls, loads, count_calls_by_name(branch)));
+                }
+            }
+        }
+    });
+    assert_eq!(per_tag, [(0, 1, 0, 0), (1, 0, 2, 1), (2, 0, 1, 0)]);
+
+    // Neg (Add (Neg (Lit 1)) (Add (Lit 2) (Lit 3)))
+    let mut heap = Heap::default();
+    let node = |heap: &mut Heap, id, fields: Vec<i128>| {
+        let fields = fields.into_iter().map(Value::Int).collect();
+        heap.alloc(vec![Value::Tag(id, fields)], 0, 1)
+    };
+    let lit1 = node(&mut heap, 0, vec![1]);
+    let neg1 = node(&mut heap, 2, vec![lit1]);
+    let lit2 = node(&mut heap, 0, vec![2]);
+    let lit3 = node(&mut heap, 0, vec![3]);
+    let add2 = node(&mut heap, 1, vec![lit2, lit3]);
+    let add1 = node(&mut heap, 1, vec![neg1, add2]);
+    let root = node(&mut heap, 2, vec![add1]);
+    let heap = simulate_dec(arena, &mut interner, expr, &Value::Int(root), heap).unwrap();
+    assert!(heap.refcounts.values().all(|rc| *rc == 0));
+}
+
+#[test]
+fn dec_record_with_str_and_recursive_pointer() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // Node : [Nil, Node { name : Str, next : Node }]
+    let rec_ptr = interner.insert(Layout::RecursivePointer(Layout::VOID));
+    let record = interner.insert(Layout::struct_no_name_order(
+        arena.alloc([Layout::STR, rec_ptr]),
+    ));
+    let node = interner.insert_recursive(
+        arena,
+        Layout::Union(UnionLayout::NullableUnwrapped {
+            nullable_id: false,
+            other_fields: arena.alloc([record]),
+        }),
+    );
+
+    // The record gets a helper, which refcounts the string and resolves `next` to Node
+    let (_, dec) = summarise(arena, &mut interner, node, ModifyRc::Dec);
+    assert_eq!(dec.helper_args.len(), 3);
+    assert_eq!(dec.helper_args[0], node);
+    assert!(dec.helper_args.contains(&Layout::STR));
+    assert!(dec
+        .helper_args
+        .iter()
+        .any(|arg| matches!(interner.get(*arg), Layout::Struct { .. })));
+
+    // The fields are loaded from each node before it is freed, or the heap reports a
+    // use-after-free. The last name is shared, so it survives with one less reference.
+    let mut heap = Heap::default();
+    let mut next = 0;
+    let mut names = Vec::new();
+    let mut links = Vec::new();
+    for i in 0..3 {
+        let name = heap.alloc(vec![], 1, if i == 2 { 2 } else { 1 });
+        let name_value = Value::Struct(vec![Value::Int(name), Value::Int(30), Value::Int(30)]);
+        let fields = Value::Struct(vec![name_value, Value::Int(next)]);
+        next = heap.alloc(vec![Value::Tag(1, vec![fields])], 0, 1);
+        names.push(name);
+        links.push(next);
+    }
+    let heap = simulate_dec(arena, &mut interner, node, &Value::Int(next), heap).unwrap();
+    assert!(links.iter().all(|addr| heap.refcounts[addr] == 0));
+    assert_eq!(heap.refcounts[&names[0]], 0);
+    assert_eq!(heap.refcounts[&names[1]], 0);
+    assert_eq!(heap.refcounts[&names[2]], 1);
+}
+
+#[test]
+fn all_null_union_is_a_no_op() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+    let layout = interner.insert(Layout::Union(UnionLayout::NullableWrapped {
+        nullable_id: 0,
+        other_tags: &[],
+    }));
+
+    let inc = |s| ModifyRc::Inc(s, 1);
+    let modifiers: [&dyn Fn(Symbol) -> ModifyRc; 2] = [&inc, &ModifyRc::Dec];
+    for modify in modifiers {
+        let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+        let (_, mut help) = expand(arena, &mut interner, layout, modify, following);
+        let procs = help.take_procs();
+        let helper = procs.iter().find(|p| p.args[0].0 == layout).unwrap();
+
+        assert!(matches!(helper.body, Stmt::Let(_, _, _, Stmt::Ret(_))));
+        assert_eq!(count_lowlevel(&helper.body, RefCountIsUnique), 0);
+        assert_eq!(count_lowlevel(&helper.body, RefCountIncDataPtr), 0);
+        assert_eq!(count_lowlevel(&helper.body, RefCountDecDataPtr), 0);
+        assert_eq!(count_calls_by_name(&helper.body), 0);
+    }
+}
+
+#[test]
+fn identical_tags_share_one_body() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // [A Str, B Str, C I64]
+    let str_tag: &[InLayout] = arena.alloc([Layout::STR]);
+    let tags = arena.alloc([str_tag, str_tag, arena.alloc([Layout::I64]) as &[_]]);
+    let layout = interner.insert(Layout::Union(UnionLayout::NonRecursive(tags)));
+
+    let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+    let (_, mut help) = expand(arena, &mut interner, layout, ModifyRc::Dec, following);
+    let procs = help.take_procs();
+    let helper = procs.iter().find(|p| p.args[0].0 == layout).unwrap();
+
+    let mut switch_branches = None;
+    walk(&helper.body, &mut |s| {
+        if let Stmt::Switch { branches, .. } = s {
+            switch_branches = Some(*branches);
+        }
+    });
+    match switch_branches.unwrap() {
+        [(0, _, Stmt::Jump(jp_a, _)), (1, _, Stmt::Jump(jp_b, _))] => assert_eq!(jp_a, jp_b),
+        _ => panic!("expected tags A and B to jump to a shared body"),
+    }
+
+    let field_loads = count_exprs(&helper.body, |e| matches!(e, Expr::UnionAtIndex { .. }));
+    assert_eq!(field_loads, 1);
+}
+
+#[test]
+#[should_panic(expected = "is not a power of two")]
+fn dec_alignment_must_be_power_of_two() {
+    let arena = &Bump::new();
+    let mut ident_ids = IdentIds::default();
+    let help = CodeGenHelp::new(arena, TARGET_INFO, ModuleId::ATTR);
+    let mut ctx = Context {
+        new_linker_data: bumpalo::vec![in arena],
+        recursive_union: None,
+        known_nonempty: false,
+        op: HelperOp::Dec,
+    };
+    let data_ptr = help.create_symbol(&mut ident_ids, "data_ptr");
+    let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+    modify_refcount(
+        &help,
+        &mut ident_ids,
+        &mut ctx,
+        Layout::STR,
+        data_ptr,
+        24,
+        following,
+    );
+}
+
+#[test]
+fn inc_by_list_len_is_one_call() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+    let box_int = interner.insert(Layout::Boxed(Layout::I64));
+
+    let mut ident_ids = IdentIds::default();
+    let mut help = CodeGenHelp::new(arena, TARGET_INFO, ModuleId::ATTR);
+    let element = help.create_symbol(&mut ident_ids, "element");
+    let list = help.create_symbol(&mut ident_ids, "list");
+    let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+    let (stmt, _) = help.expand_inc_by_list_len(
+        &mut ident_ids,
+        &mut interner,
+        box_int,
+        element,
+        list,
+        following,
+    );
+
+    // The length is the amount for a single call to the element's Inc helper
+    let len = match stmt {
+        Stmt::Let(len, _, _, next) => match next {
+            Stmt::Let(_, Expr::Call(Call { arguments, .. }), _, Stmt::Ret(_)) => {
+                assert_eq!(arguments, &[element, *len]);
+                *len
+            }
+            _ => panic!("expected a call to the Inc helper after the length"),
+        },
+        _ => panic!("expected the list length first"),
+    };
+    assert_eq!(count_lowlevel(stmt, ListLen), 1);
+    assert_eq!(count_calls_by_name(stmt), 1);
+
+    let mut heap = Heap::default();
+    let boxed = heap.alloc(vec![Value::Int(7)], 0, 1);
+    let procs = help.take_procs();
+    let mut interpreter = Interpreter {
+        procs: HashMap::from_iter(procs.iter().map(|p| (p.name.name(), p))),
+        heap,
+        fuel: 1000,
+    };
+    let mut env = HashMap::from([
+        (element, Value::Int(boxed)),
+        (
+            list,
+            Value::Struct(vec![Value::Int(0), Value::Int(3), Value::Int(3)]),
+        ),
+        (Symbol::ARG_1, Value::Struct(vec![])),
+    ]);
+    interpreter.run(stmt, &mut env).unwrap();
+    assert_eq!(env[&len], Value::Int(3));
+    assert_eq!(interpreter.heap.refcounts[&boxed], 4);
+}
+
+/// Run the uniqueness check for each value of `layout`, returning the `is_unique` results
+fn run_is_unique<'a>(
+    arena: &'a Bump,
+    interner: &mut STLayoutInterner<'a>,
+    layout: InLayout<'a>,
+    heap: Heap,
+    values: &[Value],
+) -> Vec<Value> {
+    let mut ident_ids = IdentIds::default();
+    let mut help = CodeGenHelp::new(arena, TARGET_INFO, ModuleId::ATTR);
+    let structure = help.create_symbol(&mut ident_ids, "structure");
+    let is_unique = help.create_symbol(&mut ident_ids, "is_unique");
+    let following = arena.alloc(Stmt::Ret(is_unique));
+    let stmt = arena.alloc(emit_is_unique(
+        &mut help,
+        &mut ident_ids,
+        interner,
+        layout,
+        structure,
+        is_unique,
+        following,
+    ));
+    assert_eq!(count_lowlevel(stmt, RefCountIsUnique), 1);
+
+    let mut interpreter = Interpreter {
+        procs: HashMap::new(),
+        heap,
+        fuel: 1000,
+    };
+    Vec::from_iter(values.iter().map(|value| {
+        let mut env = HashMap::from([(structure, value.clone())]);
+        interpreter.run(stmt, &mut env).unwrap()
+    }))
+}
+
+#[test]
+fn is_unique_list() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+    let list_layout = interner.insert(Layout::Builtin(Builtin::List(Layout::I64)));
+
+    let mut heap = Heap::default();
+    let unique = heap.alloc(vec![Value::Int(1), Value::Int(2)], 8, 1);
+    let shared = heap.alloc(vec![Value::Int(1), Value::Int(2)], 8, 2);
+    let list = |ptr, len| Value::Struct(vec![Value::Int(ptr), Value::Int(len), Value::Int(len)]);
+
+    let results = run_is_unique(
+        arena,
+        &mut interner,
+        list_layout,
+        heap,
+        &[list(unique, 2), list(shared, 2), list(0, 0)],
+    );
+
+    // An empty list has no allocation, so it is unique
+    assert_eq!(results, [Value::Int(1), Value::Int(0), Value::Int(1)]);
+}
+
+#[test]
+fn is_unique_heap_str() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    let mut heap = Heap::default();
+    let unique = heap.alloc(vec![Value::Int(0)], 1, 1);
+    let shared = heap.alloc(vec![Value::Int(0)], 1, 2);
+    let big_str = |ptr| Value::Struct(vec![Value::Int(ptr), Value::Int(30), Value::Int(30)]);
+
+    // The small string flag is the sign bit of the last word
+    let small_str = Value::Struct(vec![
+        Value::Int(0x6968),
+        Value::Int(0),
+        Value::Int(i64::MIN as i128),
+    ]);
+
+    let results = run_is_unique(
+        arena,
+        &mut interner,
+        Layout::STR,
+        heap,
+        &[big_str(unique), big_str(shared), small_str],
+    );
+    assert_eq!(results, [Value::Int(1), Value::Int(0), Value::Int(1)]);
+}
+
+#[test]
+fn is_unique_without_allocation() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // [A I64, B I64]
+    let int_tag: &[InLayout] = arena.alloc([Layout::I64]);
+    let tags = arena.alloc([int_tag, int_tag]);
+    let union = interner.insert(Layout::Union(UnionLayout::NonRecursive(tags)));
+
+    for (layout, value) in [
+        (Layout::I64, Value::Int(5)),
+        (union, Value::Tag(1, vec![Value::Int(5)])),
+    ] {
+        let mut ident_ids = IdentIds::default();
+        let mut help = CodeGenHelp::new(arena, TARGET_INFO, ModuleId::ATTR);
+        let structure = help.create_symbol(&mut ident_ids, "structure");
+        let is_unique = help.create_symbol(&mut ident_ids, "is_unique");
+        let following = arena.alloc(Stmt::Ret(is_unique));
+        let stmt = emit_is_unique(
+            &mut help,
+            &mut ident_ids,
+            &mut interner,
+            layout,
+            structure,
+            is_unique,
+            following,
+        );
+        assert_eq!(count_lowlevel(&stmt, RefCountIsUnique), 0);
+
+        let mut interpreter = Interpreter {
+            procs: HashMap::new(),
+            heap: Heap::default(),
+            fuel: 1000,
+        };
+        let mut env = HashMap::from([(structure, value)]);
+        assert_eq!(interpreter.run(&stmt, &mut env).unwrap(), Value::Int(1));
+    }
+}
+
+#[test]
+fn list_loop_prefetches_next_element() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+    let list_str = interner.insert(Layout::Builtin(Builtin::List(Layout::STR)));
+
+    let configure = |help: &mut CodeGenHelp| help.set_rc_prefetch(true);
+    let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+    let (_, mut help) = expand_with(
+        arena,
+        &mut interner,
+        list_str,
+        ModifyRc::Dec,
+        following,
+        configure,
+    );
+    let procs = help.take_procs();
+    let dec = procs.iter().find(|p| p.args[0].0 == list_str).unwrap();
+    assert_eq!(count_lowlevel(&dec.body, Prefetch), 1);
+
+    // Each iteration prefetches the next address, then loads and refcounts the current element
+    let mut iteration = None;
+    walk(&dec.body, &mut |s| {
+        if let Stmt::Join {
+            body: Stmt::Let(_, _, _, Stmt::Switch { default_branch, .. }),
+            ..
+        } = s
+        {
+            iteration = Some(default_branch.1);
+        }
+    });
+    let mut steps = Vec::new();
+    let mut stmt = iteration.unwrap();
+    while let Stmt::Let(_, expr, _, next) = stmt {
+        steps.push(match expr {
+            Expr::Call(Call {
+                call_type: CallType::LowLevel { op, .. },
+                ..
+            }) => format!("{:?}", op),
+            Expr::ExprUnbox { .. } => "Unbox".to_string(),
+            _ => "Other".to_string(),
+        });
+        stmt = next;
+    }
+    assert!(matches!(stmt, Stmt::Jump(..)));
+    assert_eq!(steps, ["NumAdd", "Prefetch", "PtrCast", "Unbox", "Other"]);
+
+    // It's only a hint, so every string is still freed
+    let mut heap = Heap::default();
+    let strs = Vec::from_iter((0..3).map(|_| heap.alloc(vec![], 1, 1)));
+    let elems = strs
+        .iter()
+        .map(|addr| Value::Struct(vec![Value::Int(*addr), Value::Int(30), Value::Int(30)]))
+        .collect();
+    let list = heap.alloc(elems, interner.stack_size(Layout::STR), 1);
+    let value = Value::Struct(vec![Value::Int(list), Value::Int(3), Value::Int(3)]);
+    let heap = simulate_with(
+        arena,
+        &mut interner,
+        list_str,
+        ModifyRc::Dec,
+        &value,
+        heap,
+        configure,
+    )
+    .unwrap();
+    assert!(heap.refcounts.values().all(|rc| *rc == 0));
+}
+
+#[test]
+fn nonempty_list_skips_empty_check() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+    let inner = interner.insert(Layout::Builtin(Builtin::List(Layout::STR)));
+    let outer = interner.insert(Layout::Builtin(Builtin::List(inner)));
+
+    let mut ident_ids = IdentIds::default();
+    let mut help = CodeGenHelp::new(arena, TARGET_INFO, ModuleId::ATTR);
+    let list = help.create_symbol(&mut ident_ids, "list");
+    let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+    let (stmt, _) = help.expand_nonempty_list_refcount_stmt(
+        &mut ident_ids,
+        &mut interner,
+        outer,
+        &ModifyRc::Dec(list),
+        following,
+    );
+    assert_eq!(count_calls_by_name(stmt), 1);
+
+    // A later Dec of a list that might be empty gets its own helper
+    help.expand_refcount_stmt(
+        &mut ident_ids,
+        &mut interner,
+        outer,
+        &ModifyRc::Dec(list),
+        following,
+    );
+
+    let procs = help.take_procs();
+    let helpers = Vec::from_iter(procs.iter().filter(|p| p.args[0].0 == outer));
+    assert_eq!(helpers.len(), 2);
+
+    // No `is_empty` comparison or branch for the known non-empty list
+    assert_eq!(count_lowlevel(&helpers[0].body, Eq), 0);
+    assert!(matches!(
+        helpers[0].body,
+        Stmt::Let(
+            _,
+            Expr::Call(_),
+            _,
+            Stmt::Let(_, Expr::StructAtIndex { .. }, _, _)
+        )
+    ));
+    assert_eq!(count_lowlevel(&helpers[1].body, Eq), 1);
+
+    // The hint does not apply to the lists inside it
+    let inner_helper = procs.iter().find(|p| p.args[0].0 == inner).unwrap();
+    assert_eq!(count_lowlevel(&inner_helper.body, Eq), 1);
+}
+
+#[test]
+fn helper_fingerprint_is_structural() {
+    let arena = &Bump::new();
+    let help = CodeGenHelp::new(arena, TARGET_INFO, ModuleId::ATTR);
+
+    // The same layouts, interned at different indices in two interners
+    let mut interner_a = STLayoutInterner::with_capacity(4, TARGET_INFO);
+    let mut interner_b = STLayoutInterner::with_capacity(4, TARGET_INFO);
+    interner_b.insert(Layout::Boxed(Layout::U8));
+    fn build<'a>(
+        arena: &'a Bump,
+        interner: &mut STLayoutInterner<'a>,
+    ) -> (InLayout<'a>, InLayout<'a>, InLayout<'a>) {
+        let list_str = interner.insert(Layout::Builtin(Builtin::List(Layout::STR)));
+        let record = interner.insert(Layout::struct_no_name_order(
+            arena.alloc([Layout::STR, list_str]),
+        ));
+        let tree = rose_tree(arena, interner);
+        (list_str, record, tree)
+    }
+    let (list_a, record_a, tree_a) = build(arena, &mut interner_a);
+    let (list_b, record_b, tree_b) = build(arena, &mut interner_b);
+    assert_ne!(list_a, list_b);
+
+    let fingerprint_a = |layout, op| help.refcount_helper_fingerprint(&interner_a, layout, op);
+    let fingerprint_b = |layout, op| help.refcount_helper_fingerprint(&interner_b, layout, op);
+    for op in [HelperOp::Inc, HelperOp::Dec] {
+        assert_eq!(fingerprint_a(list_a, op), fingerprint_b(list_b, op));
+        assert_eq!(fingerprint_a(record_a, op), fingerprint_b(record_b, op));
+        assert_eq!(fingerprint_a(tree_a, op), fingerprint_b(tree_b, op));
+    }
+
+    // Different layouts or operations give different fingerprints
+    let dec = HelperOp::Dec;
+    assert_ne!(fingerprint_a(list_a, dec), fingerprint_a(record_a, dec));
+    assert_ne!(fingerprint_a(list_a, dec), fingerprint_a(Layout::STR, dec));
+    assert_ne!(fingerprint_a(tree_a, dec), fingerprint_a(record_a, dec));
+    assert_ne!(
+        fingerprint_a(list_a, HelperOp::Inc),
+        fingerprint_a(list_a, dec)
+    );
+}
+
+#[test]
+fn helper_fingerprint_is_pinned() {
+    let arena = &Bump::new();
+    let help = CodeGenHelp::new(arena, TARGET_INFO, ModuleId::ATTR);
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+    let list_str = interner.insert(Layout::Builtin(Builtin::List(Layout::STR)));
+    let record = interner.insert(Layout::struct_no_name_order(arena.alloc([
+        Layout::I64,
+        Layout::F32,
+        list_str,
+    ])));
+
+    // Fingerprints are shared between runs and hosts, so any change to them
+    // invalidates cached helpers
+    let fingerprint = help.refcount_helper_fingerprint(&interner, record, HelperOp::Dec);
+    assert_eq!(fingerprint, 8295064054542263953);
+}
+
+#[test]
+fn estimate_helper_sizes() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    let list_u8 = interner.insert(Layout::Builtin(Builtin::List(Layout::U8)));
+    let list_str = interner.insert(Layout::Builtin(Builtin::List(Layout::STR)));
+    let box_str = interner.insert(Layout::Boxed(Layout::STR));
+    let record = interner.insert(Layout::struct_no_name_order(arena.alloc([
+        Layout::STR,
+        list_u8,
+        Layout::U64,
+    ])));
+    let list_of_records = interner.insert(Layout::Builtin(Builtin::List(record)));
+    let either = Shape::Either(
+        Box::new(Shape::Str),
+        Box::new(Shape::List(Box::new(Shape::Int))),
+    )
+    .layout(arena, &mut interner);
+    let cons_list = cons_list(arena, &mut interner);
+    let tree = Shape::Tree(Box::new(Shape::Str)).layout(arena, &mut interner);
+    let rose_tree = rose_tree(arena, &mut interner);
+
+    let layouts = [
+        Layout::STR,
+        list_u8,
+        list_str,
+        box_str,
+        record,
+        list_of_records,
+        either,
+        cons_list,
+        tree,
+        rose_tree,
+    ];
+    for layout in layouts {
+        for op in [HelperOp::Inc, HelperOp::Dec] {
+            let modify = |s| match op {
+                HelperOp::Inc => ModifyRc::Inc(s, 1),
+                _ => ModifyRc::Dec(s),
+            };
+            let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+            let (_, mut help) = expand(arena, &mut interner, layout, modify, following);
+            let estimate = help.estimate_refcount_helper_size(&interner, layout, op);
+
+            let mut actual = 0;
+            for proc in help.take_procs().iter() {
+                walk(&proc.body, &mut |_| actual += 1);
+            }
+
+            // Within 25%
+            let error = (estimate as isize - actual as isize).unsigned_abs();
+            assert!(
+                error * 4 <= actual,
+                "{:?} helper for {}: estimated {} statements but generated {}",
+                op,
+                interner.dbg(layout),
+                estimate,
+                actual
+            );
+        }
+    }
+}
+
+#[test]
+fn dec_box_of_nonrecursive_union() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // Box [A (List U8), B Str]
+    let list_u8 = interner.insert(Layout::Builtin(Builtin::List(Layout::U8)));
+    let tags: &[&[InLayout]] =
+        arena.alloc([arena.alloc([list_u8]) as &[_], arena.alloc([Layout::STR])]);
+    let union = interner.insert(Layout::Union(UnionLayout::NonRecursive(tags)));
+    let layout = interner.insert(Layout::Boxed(union));
+
+    let following = arena.alloc(Stmt::Ret(Symbol::ARG_1));
+    let (_, mut help) = expand(arena, &mut interner, layout, ModifyRc::Dec, following);
+    let procs = help.take_procs();
+    let boxed = procs.iter().find(|p| p.args[0].0 == layout).unwrap();
+
+    // The union is decremented only if the box is unique, and the box is freed afterwards
+    let (when_done, check_unique) = match &boxed.body {
+        Stmt::Let(
+            _,
+            _,
+            _,
+            Stmt::Join {
+                body, remainder, ..
+            },
+        ) => (*body, *remainder),
+        _ => panic!("expected a uniqueness check"),
+    };
+    assert_eq!(count_lowlevel(when_done, RefCountDecDataPtr), 1);
+    assert_eq!(count_calls_by_name(when_done), 0);
+    match check_unique {
+        Stmt::Switch { branches, .. } => {
+            let when_unique = &branches[0].2;
+            assert_eq!(count_calls_by_name(when_unique), 1);
+            assert_eq!(count_lowlevel(when_unique, RefCountDecDataPtr), 0);
+        }
+        _ => panic!("expected a branch on uniqueness"),
+    }
+
+    let mut run = |box_rc: usize, tag_id| {
+        let mut heap = Heap::default();
+        let child_addr = heap.alloc(vec![Value::Int(1)], 1, 1);
+        let child = Value::Struct(vec![Value::Int(child_addr), Value::Int(1), Value::Int(1)]);
+        let box_addr = heap.alloc(vec![Value::Tag(tag_id, vec![child])], 0, box_rc);
+        let value = Value::Int(box_addr);
+        let heap = simulate(arena, &mut interner, layout, ModifyRc::Dec, &value, heap).unwrap();
+        (heap.refcounts[&box_addr], heap.refcounts[&child_addr])
+    };
+
+    // Unique: the interpreter would report a use after free if the box went first
+    assert_eq!(run(1, 0), (0, 0));
+    assert_eq!(run(1, 1), (0, 0));
+
+    // Shared: the union's fields are left alone
+    assert_eq!(run(2, 0), (1, 1));
+    assert_eq!(run(2, 1), (1, 1));
+}
+
+#[test]
+fn dec_box_of_recursive_tree() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // Box Tree, where Tree : [Leaf I64, Node Tree Tree]
+    let tree = Shape::Tree(Box::new(Shape::Int)).layout(arena, &mut interner);
+    let layout = interner.insert(Layout::Boxed(tree));
+
+    let leaf = |heap: &mut Heap, x| heap.alloc(vec![Value::Tag(0, vec![Value::Int(x)])], 0, 1);
+    let node = |heap: &mut Heap, left, right| {
+        let tag = Value::Tag(1, vec![Value::Int(left), Value::Int(right)]);
+        heap.alloc(vec![tag], 0, 1)
+    };
+    let mut run = |box_rc: usize| {
+        let mut heap = Heap::default();
+        let mut nodes = Vec::new();
+        let mut subtree = leaf(&mut heap, 0);
+        nodes.push(subtree);
+        for i in 1..4 {
+            let other = leaf(&mut heap, i);
+            subtree = node(&mut heap, subtree, other);
+            nodes.extend([other, subtree]);
+        }
+        let box_addr = heap.alloc(vec![Value::Int(subtree)], 0, box_rc);
+        let value = Value::Int(box_addr);
+
+        // Freeing a node twice would be an error from the heap
+        let heap = simulate_dec(arena, &mut interner, layout, &value, heap).unwrap();
+        let node_rcs = Vec::from_iter(nodes.iter().map(|addr| heap.refcounts[addr]));
+        (heap.refcounts[&box_addr], node_rcs)
+    };
+
+    // Unique: the whole tree under the box is freed
+    let (box_rc, node_rcs) = run(1);
+    assert_eq!(box_rc, 0);
+    assert!(node_rcs.iter().all(|rc| *rc == 0));
+
+    // Shared: the tree is left alone
+    let (box_rc, node_rcs) = run(2);
+    assert_eq!(box_rc, 1);
+    assert!(node_rcs.iter().all(|rc| *rc == 1));
+}
+
+#[test]
+fn dec_static_str_is_not_freed() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // A big string literal, stored in read-only data
+    let mut heap = Heap::default();
+    let addr = heap.alloc(vec![], 1, STATIC_REFCOUNT);
+    let literal = Value::Struct(vec![Value::Int(addr), Value::Int(30), Value::Int(30)]);
+
+    let inc = |s| ModifyRc::Inc(s, 1);
+    let heap = simulate(arena, &mut interner, Layout::STR, inc, &literal, heap).unwrap();
+    let heap = simulate(
+        arena,
+        &mut interner,
+        Layout::STR,
+        ModifyRc::Dec,
+        &literal,
+        heap,
+    )
+    .unwrap();
+    let heap = simulate(
+        arena,
+        &mut interner,
+        Layout::STR,
+        ModifyRc::Dec,
+        &literal,
+        heap,
+    )
+    .unwrap();
+    assert_eq!(heap.refcounts[&addr], STATIC_REFCOUNT);
+}
+
+#[test]
+fn reset_list_of_str_reuses_unique_buffer() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+    let list_str = interner.insert(Layout::Builtin(Builtin::List(Layout::STR)));
+    let stride = interner.stack_size(Layout::STR);
+
+    let mut ident_ids = IdentIds::default();
+    let mut help = CodeGenHelp::new(arena, TARGET_INFO, ModuleId::ATTR);
+    let structure = help.create_symbol(&mut ident_ids, "structure");
+    let (reset_call, _) =
+        help.call_reset_refcount(&mut ident_ids, &mut interner, list_str, structure);
+    let procs = help.take_procs();
+
+    let run = |list_rc: usize| {
+        let mut interpreter = Interpreter {
+            procs: HashMap::from_iter(procs.iter().map(|p| (p.name.name(), p))),
+            heap: Heap::default(),
+            fuel: 100_000,
+        };
+        let strings = Vec::from_iter((0..2).map(|_| interpreter.heap.alloc(vec![], 1, 1)));
+        let elems =
+            Vec::from_iter(strings.iter().map(|addr| {
+                Value::Struct(vec![Value::Int(*addr), Value::Int(30), Value::Int(30)])
+            }));
+        let buffer = interpreter.heap.alloc(elems, stride, list_rc);
+        let list = Value::Struct(vec![Value::Int(buffer), Value::Int(2), Value::Int(2)]);
+        let env = HashMap::from([(structure, list)]);
+        let reuse = interpreter.eval(&reset_call, &env).unwrap().int().unwrap();
+        (reuse, buffer, strings, interpreter.heap)
+    };
+
+    // Unique: the elements are released and the buffer is handed back for reuse
+    let (reuse, buffer, strings, heap) = run(1);
+    assert_eq!(reuse, buffer);
+    assert_eq!(heap.refcounts[&buffer], 1);
+    assert!(strings.iter().all(|addr| heap.refcounts[addr] == 0));
+
+    // Shared: the List goes through its usual Dec helper, and there's nothing to reuse
+    let (reuse, buffer, _, heap) = run(2);
+    assert_eq!(reuse, 0);
+    assert_eq!(heap.refcounts[&buffer], 1);
+}
+
+#[test]
+fn dec_assoc_list_releases_keys_and_values() {
+    let arena = &Bump::new();
+    let mut interner = STLayoutInterner::with_capacity(4, TARGET_INFO);
+
+    // A Dict stored as an association list: List (Str, List U8)
+    let list_u8 = interner.insert(Layout::Builtin(Builtin::List(Layout::U8)));
+    let entry = interner.insert(Layout::struct_no_name_order(
+        arena.alloc([Layout::STR, list_u8]),
+    ));
+    let layout = interner.insert(Layout::Builtin(Builtin::List(entry)));
+
+    let helpers = [layout, entry, list_u8, Layout::STR];
+    check_inc_dec(arena, &mut interner, layout, &helpers, 1, 0);
+
+    let mut heap = Heap::default();
+    let mut owned = Vec::new();
+    let entries = Vec::from_iter((0..3).map(|i| {
+        let key = heap.alloc(vec![], 1, 1);
+        let bytes = heap.alloc(vec![Value::Int(i); 4], 1, 1);
+        owned.extend([key, bytes]);
+        Value::Struct(vec![
+            Value::Struct(vec![Value::Int(key), Value::Int(30), Value::Int(30)]),
+            Value::Struct(vec![Value::Int(bytes), Value::Int(4), Value::Int(4)]),
+        ])
+    }));
+    let stride = interner.stack_size(entry);
+    let buffer = heap.alloc(entries, stride, 1);
+    let dict = Value::Struct(vec![Value::Int(buffer), Value::Int(3), Value::Int(3)]);
+
+    let heap = simulate(arena, &mut interner, layout, ModifyRc::Dec, &dict, heap).unwrap();
+    assert_eq!(heap.refcounts[&buffer], 0);
+    for addr in owned {
+        assert_eq!(heap.refcounts[&addr], 0, "leaked {:#x}", addr);
+    }
+}
+
+#[test]
+fn fuzz_inc_dec_balance() {
+    let mut rng = Rng(FUZZ_SEED);
+    for _ in 0..200 {
+        let shape = Shape::generate(&mut rng, 3);
+        let seed = rng.next();
+        if let Err(mut msg) = check_shape(&shape, seed) {
+            // Shrink to a minimal failing shape before reporting
+            let mut shape = shape;
+            'shrink: loop {
+                for smaller in shape.shrink() {
+                    if let Err(m) = check_shape(&smaller, seed) {
+                        shape = smaller;
+                        msg = m;
+                        continue 'shrink;
+                    }
+                }
+                break;
+            }
+            panic!("refcount imbalance for {:?}: {}", shape, msg);
+        }
+    }
+}
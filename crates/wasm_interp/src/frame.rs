@@ -2,6 +2,7 @@ use roc_wasm_module::{parse::Parse, Value, ValueType};
 use std::iter::repeat;
 
 use crate::value_store::ValueStore;
+use crate::Error;
 
 #[derive(Debug)]
 pub struct Frame {
@@ -41,7 +42,7 @@ impl Frame {
         code_bytes: &[u8],
         value_store: &mut ValueStore<'_>,
         pc: &mut usize,
-    ) -> Self {
+    ) -> Result<Self, Error> {
         let locals_start = value_store.depth() - n_args;
 
         // Parse local variable declarations in the function header. They're grouped by type.
@@ -54,20 +55,23 @@ impl Frame {
                 ValueType::I64 => Value::I64(0),
                 ValueType::F32 => Value::F32(0.0),
                 ValueType::F64 => Value::F64(0.0),
+                ValueType::FuncRef | ValueType::ExternRef => {
+                    return Err(Error::Unsupported("a local variable with a reference type"));
+                }
             };
             value_store.extend(repeat(zero).take(n));
         }
 
         let locals_count = value_store.depth() - locals_start;
 
-        Frame {
+        Ok(Frame {
             fn_index,
             return_addr,
             body_block_index,
             locals_start,
             locals_count,
             return_type,
-        }
+        })
     }
 
     pub fn get_local(&self, values: &ValueStore<'_>, index: u32) -> Value {
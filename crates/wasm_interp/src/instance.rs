@@ -222,6 +222,12 @@ impl<'a, I: ImportDispatcher> Instance<'a, I> {
                 I64 => Value::I64(value_str.parse::<i64>().map_err(|e| e.to_string())?),
                 F32 => Value::F32(value_str.parse::<f32>().map_err(|e| e.to_string())?),
                 F64 => Value::F64(value_str.parse::<f64>().map_err(|e| e.to_string())?),
+                FuncRef | ExternRef => {
+                    return Err(format!(
+                        "Can't pass {:?} as a command-line argument",
+                        value_type
+                    ))
+                }
             };
             self.value_store.push(value);
         }
@@ -315,7 +321,11 @@ impl<'a, I: ImportDispatcher> Instance<'a, I> {
             &module.code.bytes,
             &mut self.value_store,
             &mut self.program_counter,
-        );
+        )
+        .map_err(|e| {
+            let file_offset = self.program_counter + module.code.section_offset as usize;
+            e.to_string_at(file_offset)
+        })?;
         self.blocks.push(Block {
             ty: BlockType::FunctionBody(fn_index),
             vstack: self.value_store.depth(),
@@ -560,7 +570,7 @@ impl<'a, I: ImportDispatcher> Instance<'a, I> {
                 &module.code.bytes,
                 &mut self.value_store,
                 &mut self.program_counter,
-            );
+            )?;
             std::mem::swap(&mut swap_frame, &mut self.current_frame);
             self.previous_frames.push(swap_frame);
 
@@ -728,6 +738,11 @@ impl<'a, I: ImportDispatcher> Instance<'a, I> {
                 let fn_index = self.fetch_immediate_u32(module) as usize;
                 self.do_call(None, fn_index, module)?;
             }
+            RETURNCALL | RETURNCALLINDIRECT => {
+                return Err(Error::Unsupported(
+                    "a tail call (return_call or return_call_indirect)",
+                ));
+            }
             CALLINDIRECT => {
                 let expected_signature = self.fetch_immediate_u32(module);
                 let table_index = self.fetch_immediate_u32(module);
@@ -1692,6 +1707,19 @@ impl<'a, I: ImportDispatcher> Instance<'a, I> {
                 self.value_store
                     .push(Value::F64(f64::from_ne_bytes(x.to_ne_bytes())));
             }
+            REFNULL | REFISNULL | REFFUNC => {
+                return Err(Error::Unsupported("a reference type instruction"));
+            }
+            MISC => {
+                return Err(Error::Unsupported(
+                    "an instruction with the 0xfc prefix, like a bulk memory operation",
+                ));
+            }
+            SIMD => {
+                return Err(Error::Unsupported(
+                    "an instruction with the 0xfd prefix (128-bit SIMD)",
+                ));
+            }
         }
 
         if let Some(debug_string) = &self.debug_string {
@@ -68,6 +68,8 @@ pub(crate) enum Error {
     Type(ValueType, ValueType),
     StackEmpty,
     UnreachableOp,
+    /// A valid instruction that the interpreter doesn't support yet
+    Unsupported(&'static str),
 }
 
 impl Error {
@@ -91,6 +93,12 @@ impl Error {
                     file_offset
                 )
             }
+            Error::Unsupported(what) => {
+                format!(
+                    "ERROR: I found {} at file offset {:#x}, but I don't support that yet.\n",
+                    what, file_offset
+                )
+            }
         }
     }
 }
@@ -5,12 +5,13 @@ use crate::tests::{
     const_value, create_exported_function_no_locals, create_exported_function_with_locals,
     default_state,
 };
-use crate::{DefaultImportDispatcher, ImportDispatcher, Instance};
+use crate::{DefaultImportDispatcher, Error, ImportDispatcher, Instance};
 use bumpalo::{collections::Vec, Bump};
 use roc_wasm_module::sections::{Import, ImportDesc};
 use roc_wasm_module::{
-    opcodes::OpCode, sections::ElementSegment, Export, ExportType, SerialBuffer, Serialize,
-    Signature, Value, ValueType, WasmModule,
+    opcodes::{MiscOp, OpCode, SimdOp},
+    sections::ElementSegment,
+    Export, ExportType, SerialBuffer, Serialize, Signature, Value, ValueType, WasmModule,
 };
 
 #[test]
@@ -724,6 +725,85 @@ fn test_call_indirect_unsupported_table() {
     test_call_indirect_help(1, 0);
 }
 
+#[test]
+fn test_tail_call_unsupported() {
+    let arena = Bump::new();
+    let mut inst = default_state(&arena);
+    let mut module = WasmModule::new(&arena);
+
+    module.code.bytes.push(OpCode::RETURNCALL as u8);
+    module.code.bytes.encode_u32(0);
+
+    assert!(matches!(
+        inst.execute_next_instruction(&module),
+        Err(Error::Unsupported(_))
+    ));
+}
+
+#[test]
+fn test_reference_types_unsupported() {
+    let arena = Bump::new();
+    let mut inst = default_state(&arena);
+    let mut module = WasmModule::new(&arena);
+
+    module.code.bytes.push(OpCode::REFNULL as u8);
+    module.code.bytes.push(ValueType::FuncRef as u8);
+
+    assert!(matches!(
+        inst.execute_next_instruction(&module),
+        Err(Error::Unsupported(_))
+    ));
+
+    // (local funcref)
+    let mut buffer = vec![];
+    let mut cursor = 0;
+    [(1u32, ValueType::FuncRef)].serialize(&mut buffer);
+    let frame = Frame::enter(
+        0,
+        0,
+        0,
+        0,
+        None,
+        &buffer,
+        &mut inst.value_store,
+        &mut cursor,
+    );
+    assert!(matches!(frame, Err(Error::Unsupported(_))));
+}
+
+#[test]
+fn test_misc_prefix_unsupported() {
+    let arena = Bump::new();
+    let mut inst = default_state(&arena);
+    let mut module = WasmModule::new(&arena);
+
+    // data.drop 0
+    module.code.bytes.push(OpCode::MISC as u8);
+    module.code.bytes.encode_u32(MiscOp::DATADROP as u32);
+    module.code.bytes.encode_u32(0);
+
+    assert!(matches!(
+        inst.execute_next_instruction(&module),
+        Err(Error::Unsupported(_))
+    ));
+}
+
+#[test]
+fn test_simd_prefix_unsupported() {
+    let arena = Bump::new();
+    let mut inst = default_state(&arena);
+    let mut module = WasmModule::new(&arena);
+
+    // i32x4.splat
+    module.code.bytes.push(OpCode::SIMD as u8);
+    module.code.bytes.encode_u32(SimdOp::I32X4SPLAT as u32);
+
+    assert!(matches!(
+        inst.execute_next_instruction(&module),
+        Err(Error::Unsupported(_))
+    ));
+}
+
 fn test_call_indirect_help(table_index: u32, elem_index: u32) -> Value {
     let arena = Bump::new();
     let mut module = WasmModule::new(&arena);
@@ -849,7 +929,8 @@ fn test_set_get_local() {
         &buffer,
         &mut inst.value_store,
         &mut cursor,
-    );
+    )
+    .unwrap();
 
     module.code.bytes.push(OpCode::I32CONST as u8);
     module.code.bytes.encode_i32(12345);
@@ -896,7 +977,8 @@ fn test_tee_get_local() {
         &buffer,
         &mut inst.value_store,
         &mut cursor,
-    );
+    )
+    .unwrap();
 
     module.code.bytes.push(OpCode::I32CONST as u8);
     module.code.bytes.encode_i32(12345);
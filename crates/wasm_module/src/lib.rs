@@ -635,6 +635,10 @@ pub enum ValueType {
     I64 = 0x7e,
     F32 = 0x7d,
     F64 = 0x7c,
+    /// Reference types proposal
+    FuncRef = 0x70,
+    /// Reference types proposal
+    ExternRef = 0x6f,
 }
 
 impl ValueType {
@@ -654,6 +658,8 @@ impl From<u8> for ValueType {
             0x7e => Self::I64,
             0x7d => Self::F32,
             0x7c => Self::F64,
+            0x70 => Self::FuncRef,
+            0x6f => Self::ExternRef,
             _ => internal_error!("Invalid ValueType 0x{:02x}", x),
         }
     }
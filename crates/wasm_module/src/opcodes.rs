@@ -18,6 +18,10 @@ pub enum OpCode {
     RETURN = 0x0f,
     CALL = 0x10,
     CALLINDIRECT = 0x11,
+    /// Tail call proposal
+    RETURNCALL = 0x12,
+    /// Tail call proposal
+    RETURNCALLINDIRECT = 0x13,
     DROP = 0x1a,
     SELECT = 0x1b,
     GETLOCAL = 0x20,
@@ -183,6 +187,61 @@ pub enum OpCode {
     I64REINTERPRETF64 = 0xbd,
     F32REINTERPRETI32 = 0xbe,
     F64REINTERPRETI64 = 0xbf,
+
+    /// Reference types proposal
+    REFNULL = 0xd0,
+    /// Reference types proposal
+    REFISNULL = 0xd1,
+    /// Reference types proposal
+    REFFUNC = 0xd2,
+
+    /// Prefix for the "miscellaneous" instructions, like bulk memory operations.
+    /// It is followed by a LEB-encoded `MiscOp`.
+    MISC = 0xfc,
+
+    /// Prefix for the 128-bit SIMD instructions.
+    /// It is followed by a LEB-encoded `SimdOp`.
+    SIMD = 0xfd,
+}
+
+/// Instructions with the `MISC` prefix
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MiscOp {
+    MEMORYINIT = 8,
+    DATADROP = 9,
+    MEMORYFILL = 11,
+}
+
+impl MiscOp {
+    fn from_u32(x: u32) -> Option<Self> {
+        match x {
+            8 => Some(Self::MEMORYINIT),
+            9 => Some(Self::DATADROP),
+            11 => Some(Self::MEMORYFILL),
+            _ => None,
+        }
+    }
+}
+
+/// Instructions with the `SIMD` prefix
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SimdOp {
+    I32X4SPLAT = 17,
+    I32X4EXTRACTLANE = 27,
+    I32X4REPLACELANE = 28,
+}
+
+impl SimdOp {
+    fn from_u32(x: u32) -> Option<Self> {
+        match x {
+            17 => Some(Self::I32X4SPLAT),
+            27 => Some(Self::I32X4EXTRACTLANE),
+            28 => Some(Self::I32X4REPLACELANE),
+            _ => None,
+        }
+    }
 }
 
 impl From<u8> for OpCode {
@@ -204,6 +263,8 @@ enum OpImmediates {
     Leb64x1,
     Leb32x2,
     BrTable,
+    Misc,
+    Simd,
 }
 
 fn immediates_for(op: OpCode) -> Result<OpImmediates, String> {
@@ -221,6 +282,8 @@ fn immediates_for(op: OpCode) -> Result<OpImmediates, String> {
         RETURN => NoImmediate,
         CALL => Leb32x1,
         CALLINDIRECT => Leb32x2,
+        RETURNCALL => Leb32x1,
+        RETURNCALLINDIRECT => Leb32x2,
         DROP => NoImmediate,
         SELECT => NoImmediate,
         GETLOCAL | SETLOCAL | TEELOCAL => Leb32x1,
@@ -233,6 +296,9 @@ fn immediates_for(op: OpCode) -> Result<OpImmediates, String> {
 
         CURRENTMEMORY | GROWMEMORY => Byte1,
 
+        MISC => Misc,
+        SIMD => Simd,
+
         I32CONST => Leb32x1,
         I64CONST => Leb64x1,
         F32CONST => Bytes4,
@@ -257,6 +323,10 @@ fn immediates_for(op: OpCode) -> Result<OpImmediates, String> {
             NoImmediate
         }
 
+        REFNULL => Byte1,
+        REFISNULL => NoImmediate,
+        REFFUNC => Leb32x1,
+
         // Catch-all in case of an invalid cast from u8 to OpCode while parsing binary
         // (rustc keeps this code, I verified in Compiler Explorer)
         #[allow(unreachable_patterns)]
@@ -312,6 +382,46 @@ impl SkipBytes for OpCode {
                     u32::skip_bytes(bytes, cursor)?;
                 }
             }
+            Misc => {
+                *cursor += 1;
+                let misc_op_offset = *cursor;
+                let misc_op = u32::parse((), bytes, cursor)?;
+                match MiscOp::from_u32(misc_op) {
+                    Some(MiscOp::MEMORYINIT) => {
+                        u32::skip_bytes(bytes, cursor)?;
+                        *cursor += 1; // memory index
+                    }
+                    Some(MiscOp::DATADROP) => {
+                        u32::skip_bytes(bytes, cursor)?;
+                    }
+                    Some(MiscOp::MEMORYFILL) => {
+                        *cursor += 1; // memory index
+                    }
+                    None => {
+                        return Err(ParseError {
+                            message: format!("Unknown Wasm instruction 0xfc {}", misc_op),
+                            offset: misc_op_offset,
+                        })
+                    }
+                }
+            }
+            Simd => {
+                *cursor += 1;
+                let simd_op_offset = *cursor;
+                let simd_op = u32::parse((), bytes, cursor)?;
+                match SimdOp::from_u32(simd_op) {
+                    Some(SimdOp::I32X4SPLAT) => {}
+                    Some(SimdOp::I32X4EXTRACTLANE | SimdOp::I32X4REPLACELANE) => {
+                        *cursor += 1; // lane index
+                    }
+                    None => {
+                        return Err(ParseError {
+                            message: format!("Unknown Wasm instruction 0xfd {}", simd_op),
+                            offset: simd_op_offset,
+                        })
+                    }
+                }
+            }
         }
         Ok(())
     }